@@ -14,12 +14,15 @@ use crate::Callbacks;
 use std::sync::mpsc;
 use std::fs::File;
 use crate::manager::FileManager;
+use crate::typesetter::Typesetter;
 use filecase::SingleArchiverImpl;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::thread;
 use std::rc::Rc;
 use std::cell::RefCell;
+use crate::diagnostic::Diagnostic;
+use crate::typst_tools::crossref::{self, BibIndex, Citation, IncludeCache};
 
 #[derive(Debug)]
 pub enum AnalyzerAction {
@@ -30,10 +33,20 @@ pub enum AnalyzerAction {
 
     ChangeBaseDir(Option<String>),
 
-    BibChanged(String),
+    // Sets the project root chosen by the user (None to find it from the file path).
+    ChangeRoot(Option<PathBuf>),
+
+    // Carries the font families known to the typesetter.
+    FontsChanged(Vec<String>),
+
+    // Carries the path and the content of the bibliography file.
+    BibChanged(PathBuf, String),
 
     BibError(String),
 
+    // Carries the revision of the analysis that was linted, the problems found and the citations.
+    Linted(u64, Vec<Diagnostic>, Vec<Citation>),
+
     // Item selected from the left sidebar. Calculate char position from byte offset at current
     // document model. Then calculate line from char offset. Propagate line to editor, so the
     // mark can be positioned there.
@@ -57,18 +70,43 @@ pub struct Analyzer {
 
     on_refs_validated : Callbacks<()>,
 
-    on_doc_error : Callbacks<TexError>,
+    on_doc_error : Callbacks<Vec<Diagnostic>>,
+
+    // Problems of a document that parses correctly, found before it is compiled: warnings
+    // about what typst would not typeset as the user likely intended, and errors such as
+    // references to undefined labels.
+    on_lint : Callbacks<Vec<Diagnostic>>,
 
     on_ref_file_changed : Callbacks<String>,
 
-    on_line_selection : Callbacks<usize>
+    on_line_selection : Callbacks<usize>,
+
+    // Places where the bibliography entries are cited, at all files of the project.
+    on_citations : Callbacks<Vec<Citation>>
+
+}
+
+// Work for the thread that lints the document, so that reading the included files and
+// checking fonts, labels and citations does not block typing. Only the latest text is linted.
+enum LintRequest {
+
+    // Carries the revision of the analysis, the file and the configured project root
+    // of the document, its text and its model.
+    Text(u64, Option<PathBuf>, Option<PathBuf>, String, Document),
+
+    Families(Vec<String>),
+
+    Bib(BibIndex)
 
 }
 
 #[derive(Debug, Clone)]
 pub struct BibFile {
     filename : Option<String>,
-    base_dir : Option<String>
+    base_dir : Option<String>,
+
+    // Project root, against which absolute bibliography paths are resolved.
+    root : Option<String>
 }
 
 impl Analyzer {
@@ -79,11 +117,13 @@ impl Analyzer {
         let on_section_changed : Callbacks<Difference> = Default::default();
         let on_doc_changed : Callbacks<Document> = Default::default();
         let on_line_selection : Callbacks<usize> = Default::default();
-        let on_doc_error : Callbacks<TexError> = Default::default();
+        let on_doc_error : Callbacks<Vec<Diagnostic>> = Default::default();
+        let on_lint : Callbacks<Vec<Diagnostic>> = Default::default();
         let on_doc_cleared : Callbacks<()> = Default::default();
         let on_refs_cleared : Callbacks<()> = Default::default();
         let on_refs_validated : Callbacks<()> = Default::default();
         let on_ref_file_changed : Callbacks<String> = Default::default();
+        let on_citations : Callbacks<Vec<Citation>> = Default::default();
         // TODO keep an thread watching an external bib file (if any). The user can simply use
         // the embedded bibliography instead.
 
@@ -91,13 +131,52 @@ impl Analyzer {
         recv.attach(None, {
             let mut tk_info = TokenInfo::default();
             let mut doc = Document::default();
-            let mut last_err : Option<TexError> = None;
+            let mut last_err : Option<Vec<Diagnostic>> = None;
+
+            // File of the document being analyzed (None for documents not saved yet).
+            let mut curr_file : Option<PathBuf> = None;
+            let mut configured_root : Option<PathBuf> = None;
             let on_reference_changed = on_reference_changed.clone();
             let on_section_changed = on_section_changed.clone();
             let on_doc_changed = on_doc_changed.clone();
             let on_line_selection = on_line_selection.clone();
             let on_doc_cleared = on_doc_cleared.clone();
             let on_doc_error = on_doc_error.clone();
+            let on_lint = on_lint.clone();
+            let on_citations = on_citations.clone();
+
+            // Revision of the last analysis (results of the lint thread for older ones are
+            // ignored), warnings shown for it and places where bibliography entries are cited.
+            let mut lint_rev : u64 = 0;
+            let mut lints : Vec<Diagnostic> = Vec::new();
+            let mut citations : Vec<Citation> = Vec::new();
+            let (lint_send, lint_recv) = mpsc::channel::<LintRequest>();
+            std::thread::spawn({
+                let send = send.clone();
+                move || {
+                    let mut families : Vec<String> = Vec::new();
+                    let mut bib : Option<BibIndex> = None;
+
+                    // Files included by the document, read again only when they change.
+                    let mut includes = IncludeCache::default();
+                    let mut last : Option<(u64, Option<PathBuf>, Option<PathBuf>, String, Document)> = None;
+                    while let Ok(req) = lint_recv.recv() {
+                        for req in std::iter::once(req).chain(lint_recv.try_iter()) {
+                            match req {
+                                LintRequest::Text(rev, file, root, txt, doc) => last = Some((rev, file, root, txt, doc)),
+                                LintRequest::Families(new_families) => families = new_families,
+                                LintRequest::Bib(new_bib) => bib = Some(new_bib)
+                            }
+                        }
+                        if let Some((rev, file, configured_root, txt, doc)) = &last {
+                            let root = file.as_deref()
+                                .and_then(|f| crate::typst_tools::project_root(f, configured_root.as_deref()) );
+                            let (new_lints, new_citations) = lint(file.as_deref(), root.as_deref(), txt, doc, &families, bib.as_ref(), &mut includes);
+                            send.send(AnalyzerAction::Linted(*rev, new_lints, new_citations));
+                        }
+                    }
+                }
+            });
             let on_refs_cleared = on_refs_cleared.clone();
             let on_refs_validated = on_refs_validated.clone();
             let on_ref_file_changed = on_ref_file_changed.clone();
@@ -110,13 +189,19 @@ impl Analyzer {
                     loop {
                         match bib_recv.recv() {
                             Ok(Some(bib)) => {
-                                if let (Some(fname), Some(base_path)) = (bib.filename, bib.base_dir) {
-                                    let path = format!("{}/{}", base_path, fname);
+                                let path = match (bib.filename, bib.base_dir, bib.root) {
+                                    (Some(fname), _, Some(root)) if fname.starts_with('/') => {
+                                        Some(format!("{}{}", root.trim_end_matches('/'), fname))
+                                    },
+                                    (Some(fname), Some(base_path), _) => Some(format!("{}/{}", base_path, fname)),
+                                    _ => None
+                                };
+                                if let Some(path) = path {
                                     if Path::new(&path).exists() {
                                         if let Ok(mut f) = File::open(&path) {
                                             let mut content = String::new();
                                             if let Ok(_) = f.read_to_string(&mut content) {
-                                                send.send(AnalyzerAction::BibChanged(content));
+                                                send.send(AnalyzerAction::BibChanged(PathBuf::from(&path), content));
                                             } else {
                                                 eprintln!("could not read file");
                                             }
@@ -141,13 +226,17 @@ impl Analyzer {
 
                 match action {
                     AnalyzerAction::ChangeBaseDir(opt_path) => {
+                        curr_file = opt_path.as_ref().map(PathBuf::from);
                         if let Some(path) = opt_path {
                             if let Some(parent) = Path::new(&path).parent() {
-                                let parent_path = parent.to_str().unwrap().to_string();
+                                let parent_path = parent.display().to_string();
+                                let root = crate::typst_tools::project_root(Path::new(&path), configured_root.as_deref())
+                                    .map(|r| r.display().to_string() );
                                 if let Some(bib_file) = bib_file.as_mut() {
                                     bib_file.base_dir = Some(parent_path);
+                                    bib_file.root = root;
                                 } else {
-                                    bib_file = Some(BibFile { filename : None, base_dir : Some(parent_path) });
+                                    bib_file = Some(BibFile { filename : None, base_dir : Some(parent_path), root });
                                 }
                             } else {
                                 log::warn!("File without valid parent path");
@@ -155,14 +244,23 @@ impl Analyzer {
                         }
                         bib_send.send(bib_file.clone());
                     },
+                    AnalyzerAction::ChangeRoot(root) => {
+                        configured_root = root;
+                        if let (Some(file), Some(bib_file)) = (&curr_file, bib_file.as_mut()) {
+                            bib_file.root = crate::typst_tools::project_root(file, configured_root.as_deref())
+                                .map(|r| r.display().to_string() );
+                            bib_send.send(Some(bib_file.clone()));
+                        }
+                    },
 
                     // TextChanged is not triggered when text is
                     // first added to sourceview because signal is blocked.
                     // Must know text changes exactly when text is loaded.
                     AnalyzerAction::TextInit(new_txt) | AnalyzerAction::TextChanged(new_txt) => {
-
-                        match crate::typst_tools::parse_doc(Path::new(""), new_txt) {
+                        lint_rev += 1;
+                        match crate::typst_tools::parse_doc(curr_file.as_deref(), new_txt.clone()) {
                             Ok(new_doc) => {
+                                lint_send.send(LintRequest::Text(lint_rev, curr_file.clone(), configured_root.clone(), new_txt, new_doc.clone()));
                                 if doc != new_doc || last_err.is_some() {
                                     on_doc_changed.call(new_doc.clone());
                                 }
@@ -184,7 +282,8 @@ impl Analyzer {
                                             } else {
                                                 bib_file = Some(BibFile {
                                                     filename : Some(new_fname.to_string()),
-                                                    base_dir : None
+                                                    base_dir : None,
+                                                    root : None
                                                 });
                                                 bib_send.send(bib_file.clone());
                                             }
@@ -195,31 +294,56 @@ impl Analyzer {
                                 }
                             },
                             Err(errs) => {
-                                let fst_err = errs.get(0).map(|e| e.1.clone() )
-                                    .unwrap_or(String::from("Unknown error"));
                                 doc = Document::default();
                                 on_doc_cleared.call(());
-                                on_doc_error.call(TexError { msg : fst_err, line : 0 });
+                                on_doc_error.call(errs.clone());
+                                last_err = Some(errs);
+                                if !lints.is_empty() {
+                                    lints.clear();
+                                    on_lint.call(Vec::new());
+                                }
                             }
                         }
 
                     },
-                    AnalyzerAction::BibChanged(txt) => {
+                    AnalyzerAction::FontsChanged(new_families) => {
+                        lint_send.send(LintRequest::Families(new_families));
+                    },
+                    AnalyzerAction::Linted(rev, new_lints, new_citations) => {
+                        if rev != lint_rev || last_err.is_some() {
+                            return Continue(true);
+                        }
+                        if new_lints != lints {
+                            lints = new_lints;
+                            on_lint.call(lints.clone());
+                        }
+                        if new_citations != citations {
+                            citations = new_citations;
+                            on_citations.call(citations.clone());
+                        }
+                    },
+                    AnalyzerAction::BibChanged(bib_path, txt) => {
                         match BibParser::parse(&txt[..]) {
                             Ok(refs) =>  {
+                                let keys = refs.as_ref().iter().map(|r| r.key().to_string() );
+                                lint_send.send(LintRequest::Bib(BibIndex::new(Some(bib_path), txt.clone(), keys)));
                                 on_refs_cleared.call(());
                                 let n = refs.as_ref().len();
                                 for (ix, r) in refs.as_ref().iter().enumerate() {
                                     on_reference_changed.call(Difference::Added(ix, r.to_string()));
                                 }
+
+                                // The rows of the references were rebuilt without their citations,
+                                // which are set again once the bibliography is linted.
+                                citations.clear();
                             },
                             Err(e) => {
-                                on_doc_error.call(TexError { msg : format!("Bibtex error: {}", e), line : 0 });
+                                on_doc_error.call(vec![Diagnostic::error(format!("Bibtex error: {}", e))]);
                             }
                         }
                     },
                     AnalyzerAction::BibError(e) => {
-                        on_doc_error.call(TexError { msg : e, line : 0 });
+                        on_doc_error.call(vec![Diagnostic::error(e)]);
                     },
                     AnalyzerAction::ItemSelected(sel_ixs) => {
 
@@ -239,9 +363,11 @@ impl Analyzer {
             on_line_selection,
             on_doc_cleared,
             on_doc_error,
+            on_lint,
             on_refs_cleared,
             on_ref_file_changed,
-            on_refs_validated
+            on_refs_validated,
+            on_citations
         }
     }
 
@@ -289,11 +415,28 @@ impl Analyzer {
 
     pub fn connect_doc_error<F>(&self, f : F)
     where
-        F : Fn(TexError) + 'static
+        F : Fn(Vec<Diagnostic>) + 'static
     {
         self.on_doc_error.bind(f);
     }
 
+    /// Called with all warnings about the document after it is analyzed, if they changed.
+    pub fn connect_lint<F>(&self, f : F)
+    where
+        F : Fn(Vec<Diagnostic>) + 'static
+    {
+        self.on_lint.bind(f);
+    }
+
+    /// Called with every place where an entry of the bibliography is cited, when they change
+    /// (and whenever the bibliography is read again, since its list is rebuilt).
+    pub fn connect_citations<F>(&self, f : F)
+    where
+        F : Fn(Vec<Citation>) + 'static
+    {
+        self.on_citations.bind(f);
+    }
+
     pub fn connect_line_selection<F>(&self, f : F)
     where
         F : Fn(usize) + 'static
@@ -399,6 +542,51 @@ impl React<PapersWindow> for Analyzer {
                 send.send(AnalyzerAction::TextChanged(get_text(&view)));
             }
         });
+
+        // Bibliography paths starting with "/" are resolved at the project root.
+        let main_menu = window.titlebar.main_menu.clone();
+        self.send.send(AnalyzerAction::ChangeRoot(main_menu.configured_root()));
+        main_menu.project_root_action.connect_notify_local(Some("state"), {
+            let send = self.send.clone();
+            let main_menu = main_menu.clone();
+            move |_, _| {
+                send.send(AnalyzerAction::ChangeRoot(main_menu.configured_root()));
+            }
+        });
+    }
+
+}
+
+// Finds problems in a document that parsed correctly. Font families are only
+// checked once the typesetter indexed them. Labels and references are checked
+// over the document and all files it includes (read again only when they
+// changed), where the citations of the bibliography entries are also collected.
+pub(crate) fn lint(
+    file : Option<&Path>,
+    root : Option<&Path>,
+    txt : &str,
+    doc : &Document,
+    families : &[String],
+    bib : Option<&BibIndex>,
+    includes : &mut IncludeCache
+) -> (Vec<Diagnostic>, Vec<Citation>) {
+    let mut lints = Vec::new();
+    if !families.is_empty() {
+        lints.extend(crate::typst_tools::fonts::font_lints(file, txt, families));
+    }
+    let files = crossref::project_files(file, txt, doc, root, includes);
+    lints.extend(crossref::crossref_lints(&files, bib));
+    let citations = bib.map(|bib| crossref::citations(&files, bib) ).unwrap_or_default();
+    (lints, citations)
+}
+
+impl React<Typesetter> for Analyzer {
+
+    fn react(&self, typesetter : &Typesetter) {
+        let send = self.send.clone();
+        typesetter.connect_fonts_changed(move |families| {
+            send.send(AnalyzerAction::FontsChanged(families.into_iter().map(|f| f.name ).collect()));
+        });
     }
 
 }
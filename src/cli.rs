@@ -0,0 +1,336 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+/* Headless subcommands of the drafts binary. They use the same embedded fonts
+and typesetting pipeline as the editor, but never initialize GTK, so they can run
+in scripts and build systems without a display. */
+
+use std::path::{Path, PathBuf};
+use crate::typst_tools::{self, Fonts, SystemWorld, WorldWatcher};
+use typst::World;
+use crate::typst_tools::export::{self, ExportFormat, ExportRequest};
+use crate::diagnostic::Diagnostic;
+use crate::tex::{self, BibParser, Item, Object};
+use serde_json::{json, Value};
+use crate::typst_tools::crossref::{BibIndex, IncludeCache};
+
+pub const USAGE : &str = "Usage:
+    drafts                                  Start the editor
+    drafts compile FILE [-o OUTPUT] [--pages RANGE] [--dpi DPI] [--root DIR] [--font-path DIR]...
+                                            Typeset FILE (to PDF, PNG, SVG or HTML, by the OUTPUT extension)
+    drafts watch FILE [-o OUTPUT] [--pages RANGE] [--dpi DPI] [--root DIR] [--font-path DIR]...
+                                            Typeset FILE again whenever a file it depends on changes
+    drafts outline FILE                     Print the document outline as JSON
+    drafts refs FILE                        List the entries of a BibTeX file
+    drafts check FILE [--root DIR] [--font-path DIR]...
+                                            Report problems, exiting with 1 when there are any
+    drafts help                             Show this message
+
+The project root (where paths starting with / are resolved, and outside of which files
+cannot be read) is DIR, or else the closest directory containing FILE with a .drafts-root
+file, or else the directory of FILE. Fonts are searched at the system font
+directories, at each --font-path and at the fonts folder of the project root.";
+
+// Exit code for invalid arguments.
+const USAGE_ERROR : i32 = 2;
+
+/// Runs the subcommand given by the command line arguments (excluding the program name),
+/// using the given embedded fonts plus the system fonts, returning its exit code. Returns
+/// None when the arguments do not start with a subcommand, in which case the editor
+/// should be started.
+pub fn run(args : &[String], fonts : &Fonts) -> Option<i32> {
+    let (cmd, rest) = args.split_first()?;
+    let res = match &cmd[..] {
+        "compile" => compile(rest, &system_fonts(fonts), false),
+        "watch" => compile(rest, &system_fonts(fonts), true),
+        "outline" => outline(rest),
+        "refs" => refs(rest),
+        "check" => check(rest, &system_fonts(fonts)),
+
+        // Not listed at the usage message, since it is only started by the editor.
+        crate::worker::WORKER_CMD => Ok(crate::worker::run(fonts)),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(0)
+        },
+        _ => return None
+    };
+    match res {
+        Ok(code) => Some(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(USAGE_ERROR)
+        }
+    }
+}
+
+// Adds the system fonts to the embedded fonts.
+fn system_fonts(fonts : &Fonts) -> Fonts {
+    fonts.discover(typst_tools::font_cache_path().as_deref())
+}
+
+// Splits the arguments into the input file and the values of the given options.
+fn parse_args<'a>(args : &'a [String], opts : &[&str]) -> Result<(PathBuf, Vec<(&'a str, &'a str)>), String> {
+    let mut file = None;
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if opts.contains(&&arg[..]) {
+            let value = args.next().ok_or_else(|| format!("Missing value for {}\n\n{}", arg, USAGE) )?;
+            values.push((&arg[..], &value[..]));
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option {}\n\n{}", arg, USAGE));
+        } else if file.is_none() {
+            file = Some(PathBuf::from(arg));
+        } else {
+            return Err(format!("Unexpected argument {}\n\n{}", arg, USAGE));
+        }
+    }
+    let file = file.ok_or_else(|| format!("Missing input file\n\n{}", USAGE) )?;
+    Ok((file, values))
+}
+
+fn option<'a>(values : &[(&str, &'a str)], name : &str) -> Option<&'a str> {
+    values.iter().rev().find(|(opt, _)| *opt == name ).map(|(_, v)| *v )
+}
+
+// Values of an option that might be given many times.
+fn option_values(values : &[(&str, &str)], name : &str) -> Vec<PathBuf> {
+    values.iter().filter(|(opt, _)| *opt == name ).map(|(_, v)| PathBuf::from(v) ).collect()
+}
+
+fn read_source(file : &Path) -> Result<String, String> {
+    std::fs::read_to_string(file).map_err(|e| format!("Unable to read {}: {}", file.display(), e) )
+}
+
+/// Builds a world with the given file as its main source, at the given project root (or
+/// else the root found from the file path). Returns the world with its fonts, which include
+/// the fonts at the given directories and at the fonts folder of the project.
+pub fn file_world(
+    file : &Path,
+    root : Option<&Path>,
+    font_dirs : &[PathBuf],
+    fonts : &Fonts
+) -> Result<(SystemWorld, Fonts), String> {
+    let file = file.canonicalize().map_err(|e| format!("Unable to open {}: {}", file.display(), e) )?;
+    let root = match root {
+        Some(root) => Some(root.canonicalize().map_err(|e| format!("Invalid project root {}: {}", root.display(), e) )?),
+        None => None
+    };
+    let root = typst_tools::project_root(&file, root.as_deref())
+        .ok_or_else(|| format!("File {} has no parent directory", file.display()) )?;
+    let dirs = typst_tools::font_dirs(font_dirs, Some(&root));
+    let fonts = if dirs.is_empty() { fonts.clone() } else { fonts.with_dirs(&dirs) };
+    let mut world = SystemWorld::new(root, fonts.clone());
+    world.set_main(&file).map_err(|e| e.to_string() )?;
+    Ok((world, fonts))
+}
+
+/// Prints diagnostics as "file:line:column: severity: message", followed by their hints.
+pub fn print_diagnostics(diagnostics : &[Diagnostic], default_file : &Path) {
+    for diag in diagnostics {
+        let path = diag.path.as_deref().unwrap_or(default_file);
+        if diag.range.is_some() {
+            eprintln!("{}:{}:{}: {}: {}", path.display(), diag.line + 1, diag.column + 1, diag.severity, diag.message);
+        } else {
+            eprintln!("{}: {}: {}", path.display(), diag.severity, diag.message);
+        }
+        for hint in diag.hints.iter() {
+            eprintln!("    = {}", hint);
+        }
+    }
+}
+
+fn compile(args : &[String], fonts : &Fonts, watch : bool) -> Result<i32, String> {
+    let (file, values) = parse_args(args, &["-o", "--output", "--pages", "--dpi", "--root", "--font-path"])?;
+    let output = option(&values, "-o").or(option(&values, "--output"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| file.with_extension("pdf") );
+    let format = match output.extension().and_then(|e| e.to_str() ) {
+        Some("pdf") => ExportFormat::Pdf,
+        Some("png") => {
+            let dpi = match option(&values, "--dpi") {
+                Some(dpi) => dpi.parse::<f32>().map_err(|_| format!("Invalid resolution: {}", dpi) )?,
+                None => 300.0
+            };
+            ExportFormat::Png { dpi }
+        },
+        Some("svg") => ExportFormat::Svg,
+        Some("html") => ExportFormat::Html,
+        _ => return Err(format!("Unknown output format for {} (use .pdf, .png, .svg or .html)", output.display()))
+    };
+    let req = ExportRequest {
+        format,
+        pages : option(&values, "--pages").unwrap_or("").to_string(),
+        path : output.with_extension(""),
+        name : file.file_stem().and_then(|s| s.to_str() ).unwrap_or("untitled").to_string()
+    };
+    let (mut world, fonts) = file_world(&file, option(&values, "--root").map(Path::new), &option_values(&values, "--font-path"), fonts)?;
+    let code = compile_and_export(&mut world, &file, &fonts, &req);
+    if !watch {
+        return Ok(code);
+    }
+
+    let (send, recv) = std::sync::mpsc::channel::<notify::Event>();
+    let watcher = WorldWatcher::new(world.root(), move |event| { send.send(event); })?;
+    eprintln!("Watching {} for changes", watcher.root().display());
+    while let Ok(event) = recv.recv() {
+        if !world.relevant(&event) {
+            continue;
+        }
+
+        // Wait for all files saved together before typesetting again.
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        while recv.try_recv().is_ok() { }
+        compile_and_export(&mut world, &file, &fonts, &req);
+    }
+    Ok(0)
+}
+
+fn compile_and_export(world : &mut SystemWorld, file : &Path, fonts : &Fonts, req : &ExportRequest) -> i32 {
+    let doc = match typst_tools::compile(world) {
+        Ok(doc) => doc,
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, file);
+            return 1;
+        }
+    };
+    match export::export(&doc, world, fonts, req) {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path.display());
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn object_json(obj : &Object, line : usize) -> Value {
+    let (kind, index, arg) = match obj {
+        Object::Table(ix, _, arg) => ("table", *ix, arg.clone()),
+        Object::Image(ix, _, arg) => ("image", *ix, arg.clone()),
+        Object::Equation(ix, _, arg) => ("equation", *ix, arg.clone()),
+        Object::Code(ix, _, arg) => ("code", *ix, arg.clone()),
+        Object::Figure(ix, _, info) => ("figure", *ix, info.caption.clone()),
+        Object::Bibliography(ix, arg) => ("bibliography", *ix, Some(arg.clone()))
+    };
+    let mut out = json!({ "kind" : kind, "index" : index, "argument" : arg, "line" : line + 1 });
+    if let Object::Figure(_, _, info) = obj {
+        out["label"] = json!(info.label);
+    }
+    out
+}
+
+fn item_json(item : &Item) -> Value {
+    match item {
+        Item::Heading(heading, line) => json!({
+            "kind" : "heading",
+            "name" : heading.name,
+            "level" : heading.level,
+            "number" : heading.number_label(),
+            "line" : line + 1,
+            "items" : heading.items.iter().map(item_json).collect::<Vec<_>>()
+        }),
+        Item::Object(obj, line) => object_json(obj, *line)
+    }
+}
+
+/// Represents the outline of a document as JSON, with one-based line numbers.
+pub fn outline_json(doc : &tex::Document) -> Value {
+    json!({ "items" : doc.items.iter().map(item_json).collect::<Vec<_>>() })
+}
+
+fn outline(args : &[String]) -> Result<i32, String> {
+    let (file, _) = parse_args(args, &[])?;
+    let txt = read_source(&file)?;
+    match typst_tools::parse_doc(Some(&file), txt) {
+        Ok(doc) => {
+            println!("{}", serde_json::to_string_pretty(&outline_json(&doc)).unwrap());
+            Ok(0)
+        },
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, &file);
+            Ok(1)
+        }
+    }
+}
+
+fn refs(args : &[String]) -> Result<i32, String> {
+    let (file, _) = parse_args(args, &[])?;
+    let txt = read_source(&file)?;
+    let refs = match BibParser::parse(&txt) {
+        Ok(refs) => refs,
+        Err(e) => {
+            eprintln!("{}: {}", file.display(), e);
+            return Ok(1);
+        }
+    };
+    for entry in refs.as_ref() {
+        let field = |name| entry.find_field(name)
+            .map(|f| f.trim().trim_start_matches('{').trim_end_matches('}').to_string() )
+            .unwrap_or_default();
+        println!("{}\t{}\t{}\t{}\t{}", entry.key(), entry.entry(), field("year"), field("author"), field("title"));
+    }
+    Ok(0)
+}
+
+fn check(args : &[String], fonts : &Fonts) -> Result<i32, String> {
+    let (file, values) = parse_args(args, &["--root", "--font-path"])?;
+    let txt = read_source(&file)?;
+    let (doc, mut diagnostics) = match typst_tools::parse_doc(Some(&file), txt.clone()) {
+        Ok(doc) => (doc, Vec::new()),
+        Err(diagnostics) => (tex::Document::default(), diagnostics)
+    };
+
+    // Syntax errors are also compile errors, so the document is only compiled (and checked
+    // for the same problems the editor reports as warnings) when it parses.
+    if diagnostics.is_empty() {
+        let (mut world, _) = file_world(&file, option(&values, "--root").map(Path::new), &option_values(&values, "--font-path"), fonts)?;
+        if let Err(errs) = typst_tools::compile(&mut world) {
+            diagnostics.extend(errs);
+        }
+        let families : Vec<String> = typst_tools::fonts::font_families(world.book())
+            .into_iter()
+            .map(|f| f.name )
+            .collect();
+        let bib = bib_index(&doc, &file, world.root());
+        let mut includes = IncludeCache::default();
+        let (lints, _) = crate::analyzer::lint(Some(&file), Some(world.root()), &txt, &doc, &families, bib.as_ref(), &mut includes);
+        diagnostics.extend(lints);
+    }
+    print_diagnostics(&diagnostics, &file);
+    Ok(if diagnostics.is_empty() { 0 } else { 1 })
+}
+
+// Reads the bibliography of the document, if it has one. A bibliography that cannot be
+// read is already reported by the compiler, so its references are just not checked.
+fn bib_index(doc : &tex::Document, file : &Path, root : &Path) -> Option<BibIndex> {
+    let dir = file.parent().unwrap_or(root);
+    doc.objects().into_iter().find_map(|obj| {
+        let Object::Bibliography(_, fname) = obj else { return None };
+        let path = typst_tools::resolve_path(root, dir, &fname);
+        let txt = std::fs::read_to_string(&path).ok()?;
+        let keys : Vec<String> = BibParser::parse(&txt).ok()?
+            .as_ref()
+            .iter()
+            .map(|r| r.key().to_string() )
+            .collect();
+        Some(BibIndex::new(Some(path), txt, keys.into_iter()))
+    })
+}
+
+#[test]
+fn arguments_are_parsed() {
+    let args : Vec<String> = ["doc.typ", "-o", "out.png", "--dpi", "150"].iter().map(|s| s.to_string() ).collect();
+    let (file, values) = parse_args(&args, &["-o", "--dpi"]).unwrap();
+    assert_eq!(file, PathBuf::from("doc.typ"));
+    assert_eq!(option(&values, "-o"), Some("out.png"));
+    assert_eq!(option(&values, "--dpi"), Some("150"));
+    assert!(parse_args(&args[..2], &["-o"]).is_err());
+}
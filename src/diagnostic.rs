@@ -0,0 +1,143 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use std::path::{Path, PathBuf};
+use std::ops::Range;
+use std::fmt;
+use typst::syntax::Source;
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+impl fmt::Display for Severity {
+
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning")
+        }
+    }
+
+}
+
+/// A problem found while parsing or compiling a document. Diagnostics are produced
+/// both by the typesetter (compile errors) and by the analyzer (syntax errors and
+/// checks over the document model).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+
+    pub severity : Severity,
+
+    pub message : String,
+
+    // Additional information, such as the chain of calls that led to the problem.
+    pub hints : Vec<String>,
+
+    // File where the problem is. None when the problem is at the document currently
+    // edited but it was not saved to a file yet, or when the problem is not
+    // associated with any file.
+    pub path : Option<PathBuf>,
+
+    // Byte range of the problem at the file. None when the problem has no
+    // position (e.g. a missing bibliography file).
+    pub range : Option<Range<usize>>,
+
+    // Zero-based line and column (in characters) of the start of the range.
+    pub line : usize,
+
+    pub column : usize
+
+}
+
+impl Diagnostic {
+
+    /// Builds a diagnostic without a position in any file.
+    pub fn new(severity : Severity, message : impl Into<String>) -> Self {
+        Self {
+            severity,
+            message : message.into(),
+            hints : Vec::new(),
+            path : None,
+            range : None,
+            line : 0,
+            column : 0
+        }
+    }
+
+    pub fn error(message : impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message : impl Into<String>) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    /// Builds a diagnostic for a byte range of a parsed source. The path is taken
+    /// as an argument because the source might not correspond to a file on disk.
+    pub fn at_source(
+        severity : Severity,
+        message : impl Into<String>,
+        source : &Source,
+        path : Option<PathBuf>,
+        range : Range<usize>
+    ) -> Self {
+        let line = source.byte_to_line(range.start).unwrap_or(0);
+        let column = source.byte_to_column(range.start).unwrap_or(0);
+        Self {
+            severity,
+            message : message.into(),
+            hints : Vec::new(),
+            path,
+            range : Some(range),
+            line,
+            column
+        }
+    }
+
+    pub fn with_hint(mut self, hint : impl Into<String>) -> Self {
+        self.hints.push(hint.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Whether this diagnostic refers to the given document (or to the document
+    /// being edited when it has no file and the diagnostic has no path).
+    pub fn is_at(&self, path : Option<&Path>) -> bool {
+        self.path.as_deref() == path
+    }
+
+}
+
+impl fmt::Display for Diagnostic {
+
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.path.as_ref().and_then(|p| p.file_name() ).and_then(|n| n.to_str() ) {
+            write!(f, "{} ", name)?;
+        }
+        if self.range.is_some() {
+            write!(f, "(Line {}) ", self.line + 1)?;
+        }
+        write!(f, "{}", self.message)
+    }
+
+}
+
+/// Summarizes a set of diagnostics in a single line (e.g. for a toast), showing the
+/// first error (or warning, when there are no errors) and how many others there are.
+pub fn summarize(diagnostics : &[Diagnostic]) -> Option<String> {
+    let fst = diagnostics.iter().find(|d| d.is_error() ).or(diagnostics.first())?;
+    if diagnostics.len() > 1 {
+        Some(format!("{} (and {} more)", fst, diagnostics.len() - 1))
+    } else {
+        Some(fst.to_string())
+    }
+}
@@ -15,6 +15,9 @@ pub const RESOURCE_PREFIX : &'static str = "io/github/limads/drafts";
 
 pub const SETTINGS_FILE : &'static str = "user.json";
 
+// Index of the system fonts, kept at the data directory.
+pub const FONT_CACHE_FILE : &'static str = "fonts.json";
+
 pub mod ui;
 
 pub mod manager;
@@ -29,16 +32,144 @@ pub mod state;
 
 pub mod typst_tools;
 
+pub mod diagnostic;
+
+pub mod cli;
+
+pub mod worker;
+
 use std::collections::HashMap;
 use gtk4::*;
 use gtk4::prelude::*;
 use gdk_pixbuf::Pixbuf;
+use crate::typst_tools::preview::{Location, PageImage, Preview};
+
+/// A typeset page, as drawn by the preview. It has no image until it is rasterized.
+#[derive(Debug, Clone)]
+pub struct PreviewPage {
+
+    // Page size, in points.
+    pub width : f64,
+
+    pub height : f64,
+
+    // Identifies the content of the page.
+    pub hash : u64,
+
+    // Whether the page differs from the page at the same position in the previous version of the document.
+    pub changed : bool,
+
+    // Image of the page, with the number of pixels per point it was rasterized with.
+    pub image : Option<(f32, cairo::ImageSurface)>
+
+}
+
+/// Pages of the last document typeset.
+#[derive(Debug, Clone, Default)]
+pub struct PreviewDoc {
+
+    // Compilation the pages come from.
+    pub revision : u64,
+
+    pub pages : Vec<PreviewPage>
+
+}
+
+fn page_surface(img : PageImage) -> Option<cairo::ImageSurface> {
+    let stride = cairo::Format::ARgb32.stride_for_width(img.pixel_width).ok()?;
+    cairo::ImageSurface::create_for_data(
+        img.data,
+        cairo::Format::ARgb32,
+        img.pixel_width as i32,
+        img.pixel_height as i32,
+        stride
+    ).ok()
+}
+
+// Whether each page differs from the page at the same position in the previous version
+// of the document. No page changed when there is no previous version.
+fn changed_pages(prev_hashes : &[u64], hashes : &[u64]) -> Vec<bool> {
+    hashes.iter()
+        .enumerate()
+        .map(|(ix, hash)| !prev_hashes.is_empty() && prev_hashes.get(ix) != Some(hash) )
+        .collect()
+}
+
+// Images of the pages of a new document, taken from pages of the previous version with the same content.
+fn kept_images<T : Clone>(prev : impl Iterator<Item=(u64, Option<T>)>, hashes : &[u64]) -> Vec<Option<T>> {
+    let images : HashMap<u64, T> = prev.filter_map(|(hash, img)| Some((hash, img?)) ).collect();
+    hashes.iter().map(|hash| images.get(hash).cloned() ).collect()
+}
+
+// Pages with the same content as the page at the given index (itself included). The worker
+// rasterizes identical pages only once, so their image is shared.
+fn same_content(hashes : &[u64], ix : usize) -> Vec<usize> {
+    match hashes.get(ix) {
+        Some(hash) => hashes.iter().enumerate().filter(|(_, h)| *h == hash ).map(|(ix, _)| ix ).collect(),
+        None => Vec::new()
+    }
+}
+
+impl PreviewDoc {
 
-pub fn adjust_dimension_for_page(da : &DrawingArea, zoom_action : gio::SimpleAction, page : &poppler::Page) {
+    /// Builds the pages of a newly typeset document. Images of pages whose content is the same
+    /// as in the previous version of the document are kept, since they are not rasterized again.
+    pub fn new(preview : Preview, prev : Option<PreviewDoc>) -> Self {
+        let prev_pages = prev.map(|d| d.pages ).unwrap_or_default();
+        let prev_hashes : Vec<u64> = prev_pages.iter().map(|p| p.hash ).collect();
+        let changed = changed_pages(&prev_hashes, &preview.hashes);
+        let images = kept_images(prev_pages.into_iter().map(|p| (p.hash, p.image) ), &preview.hashes);
+        let pages = preview.sizes.iter().zip(preview.hashes.iter())
+            .zip(changed.into_iter().zip(images.into_iter()))
+            .map(|(((width, height), hash), (changed, image))| PreviewPage {
+                width : *width,
+                height : *height,
+                hash : *hash,
+                changed,
+                image
+            })
+            .collect();
+        let mut doc = Self { revision : preview.revision, pages };
+        doc.set_images(preview);
+        doc
+    }
+
+    /// Indices of pages that changed since the previous version of the document.
+    pub fn changed_pages(&self) -> Vec<usize> {
+        self.pages.iter().enumerate().filter(|(_, p)| p.changed ).map(|(ix, _)| ix ).collect()
+    }
+
+    /// Sets the images of rasterized pages, unless they come from another compilation.
+    /// The image is also set to pages with the same content.
+    pub fn set_images(&mut self, preview : Preview) {
+        if preview.revision != self.revision {
+            return;
+        }
+        let hashes : Vec<u64> = self.pages.iter().map(|p| p.hash ).collect();
+        for (ix, img) in preview.images {
+            let scale = img.scale;
+            if let Some(surface) = page_surface(img) {
+                for same in same_content(&hashes, ix) {
+                    self.pages[same].image = Some((scale, surface.clone()));
+                }
+            }
+        }
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn page(&self, ix : usize) -> Option<&PreviewPage> {
+        self.pages.get(ix)
+    }
+
+}
+
+pub fn adjust_dimension_for_page(da : &DrawingArea, zoom_action : gio::SimpleAction, page : &PreviewPage) {
     let z = zoom_action.state().unwrap().get::<f64>().unwrap();
-    let (w, h) = page.size();
-    let page_w = (w * z) as i32;
-    let page_h = (h * z) as i32;
+    let page_w = (page.width * z) as i32;
+    let page_h = (page.height * z) as i32;
     da.set_width_request(page_w);
     da.set_height_request(page_h);
 }
@@ -47,7 +178,7 @@ pub fn draw_page_content(
     da : &DrawingArea,
     ctx : &cairo::Context,
     zoom_action : &gio::SimpleAction,
-    page : &poppler::Page,
+    page : &PreviewPage,
     draw_borders : bool
 ) {
     let z = zoom_action.state().unwrap().get::<f64>().unwrap();
@@ -60,6 +191,19 @@ pub fn draw_page_content(
     ctx.rectangle(1., 1., w, h);
     ctx.fill();
 
+    // Pages are rasterized with a number of pixels per point that might differ from the
+    // zoom (while a page rasterized for the new zoom is not ready yet, or beyond the largest
+    // scale pages are rasterized with), so the image is scaled to the page area.
+    if let Some((scale, surface)) = &page.image {
+        ctx.save();
+        ctx.scale(z / *scale as f64, z / *scale as f64);
+        if ctx.set_source_surface(surface, 0., 0.).is_ok() {
+            ctx.source().set_filter(cairo::Filter::Good);
+            ctx.paint();
+        }
+        ctx.restore();
+    }
+
     // Draw page borders
 
     // let color = 0.5843;
@@ -83,13 +227,27 @@ pub fn draw_page_content(
         ctx.stroke();
     }
 
-    // Poppler always render with the same dpi from the physical page resolution. We must
-    // apply a scale to the context if we want the content to be scaled.
-    ctx.scale(z, z);
+    ctx.restore();
+}
+
+// Extent of the band highlighting a located line, above and below its baseline (in points).
+const LOCATION_ASCENT : f64 = 10.0;
 
-    // TODO remove the transmute when GTK/cairo version match.
-    page.render(unsafe { std::mem::transmute::<_, _>(ctx) });
+const LOCATION_DESCENT : f64 = 3.0;
 
+/// Highlights the line where some text was laid out (as found by a forward search),
+/// with a caret at the text position.
+pub fn draw_location(da : &DrawingArea, ctx : &cairo::Context, zoom_action : &gio::SimpleAction, loc : &Location) {
+    let z = zoom_action.state().unwrap().get::<f64>().unwrap();
+    let w = da.allocation().width() as f64;
+    let (top, height) = ((loc.y - LOCATION_ASCENT) * z, (LOCATION_ASCENT + LOCATION_DESCENT) * z);
+    ctx.save();
+    ctx.set_source_rgba(0.97, 0.89, 0.36, 0.35);
+    ctx.rectangle(0., top, w, height);
+    ctx.fill();
+    ctx.set_source_rgba(0.21, 0.52, 0.89, 0.9);
+    ctx.rectangle(loc.x * z - 1., top, 2., height);
+    ctx.fill();
     ctx.restore();
 }
 
@@ -103,8 +261,8 @@ pub fn configure_da_for_doc(da : &DrawingArea) {
     da.set_margin_end(16);
 }
 
-pub fn draw_page_at_area(doc : &poppler::Document, page_ix : i32, da : &DrawingArea, zoom_action : &gio::SimpleAction) {
-    let page = doc.page(page_ix).unwrap();
+pub fn draw_page_at_area(doc : &PreviewDoc, page_ix : usize, da : &DrawingArea, zoom_action : &gio::SimpleAction) {
+    let page = doc.page(page_ix).unwrap().clone();
     configure_da_for_doc(&da);
     if page_ix == doc.n_pages()-1 {
         da.set_margin_bottom(16);
@@ -120,3 +278,16 @@ pub fn draw_page_at_area(doc : &poppler::Document, page_ix : i32, da : &DrawingA
 
     da.queue_draw();
 }
+
+#[test]
+fn unchanged_pages_keep_their_images() {
+    let prev = vec![(1, Some("a")), (2, Some("b")), (3, None)];
+    let hashes = [2, 1, 4, 2];
+    assert_eq!(kept_images(prev.into_iter(), &hashes), vec![Some("b"), Some("a"), None, Some("b")]);
+    assert_eq!(changed_pages(&[1, 2, 3], &hashes), vec![true, true, true, true]);
+    assert_eq!(changed_pages(&[1, 2, 3], &[1, 2, 5, 6]), vec![false, false, true, true]);
+    assert_eq!(changed_pages(&[], &hashes), vec![false; 4]);
+    assert_eq!(same_content(&hashes, 0), vec![0, 3]);
+    assert_eq!(same_content(&hashes, 2), vec![2]);
+    assert!(same_content(&hashes, 7).is_empty());
+}
@@ -24,18 +24,27 @@ fn register_resource() -> gio::Resource {
 }
 
 fn main() {
+    systemd_journal_logger::init();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let resource = register_resource();
+
+    // System fonts are added by the typesetter (or by subcommands that need them), so
+    // the window does not wait for them to be indexed.
+    let fonts = Fonts::embedded(&resource);
+
+    // Subcommands run without a display, so they must be handled before GTK is initialized.
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = drafts::cli::run(&args, &fonts) {
+        std::process::exit(code);
+    }
+
     gtk4::init().unwrap();
 
     let application = Application::builder()
         .application_id(drafts::APP_ID)
         .build();
 
-    systemd_journal_logger::init();
-    log::set_max_level(log::LevelFilter::Info);
-
-    let resource = register_resource();
-    let fonts = Fonts::new(&resource);
-
     // let resource = gio::Resource::load("resources/compiled.gresource");
 
     // For non-flatpak builds, store at XDG_CACHE_HOME/my.add.id (usually ~/.local/share/my.add.id)
@@ -79,6 +88,8 @@ fn main() {
 
     application.set_accels_for_action("win.save_as_file", &["<Ctrl><Shift>S"]);
     application.set_accels_for_action("win.typeset", &["F7"]);
+    application.set_accels_for_action("win.cancel_typesetting", &["<Shift>F7"]);
+    application.set_accels_for_action("win.show_in_preview", &["<Ctrl>J"]);
 
     application.connect_activate({
         let user_state = user_state.clone();
@@ -107,7 +118,7 @@ fn main() {
 
             papers_win.start_screen.recent_list.react(&manager);
 
-            let typesetter = Typesetter::new(fonts.clone());
+            let typesetter = Typesetter::new();
             typesetter.react(&papers_win);
             typesetter.react(&manager);
 
@@ -115,6 +126,10 @@ fn main() {
 
             papers_win.editor.react(&typesetter);
             papers_win.editor.pdf_viewer.react(&papers_win.titlebar);
+            papers_win.editor.pdf_viewer.react(&papers_win.doc_tree);
+            papers_win.doc_tree.react(&typesetter);
+            papers_win.editor.status_bar.react(&papers_win.editor);
+            papers_win.editor.status_bar.react(&typesetter);
             papers_win.editor.react(&manager);
             papers_win.react(&manager);
 
@@ -130,6 +145,14 @@ fn main() {
             papers_win.editor.react(&analyzer);
             papers_win.react(&typesetter);
 
+            papers_win.diagnostics_panel.react(&typesetter);
+            papers_win.diagnostics_panel.react(&analyzer);
+            papers_win.titlebar.fmt_popover.font_chooser.react(&typesetter);
+            analyzer.react(&typesetter);
+            papers_win.diagnostics_panel.react(&manager);
+            papers_win.editor.react(&papers_win.diagnostics_panel);
+            manager.react(&papers_win.diagnostics_panel);
+
             papers_win.window.show();
         }
     });
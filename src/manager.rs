@@ -70,6 +70,21 @@ impl React<PapersEditor> for FileManager {
     fn react(&self, editor : &PapersEditor) {
         let handler = filecase::connect_manager_with_editor(self.sender(), &editor.view, &editor.ignore_file_save_action);
         *(editor.buf_change_handler.borrow_mut()) = Some(handler);
+        let send = self.sender().clone();
+        editor.connect_open_request(move |path| {
+            send.send(SingleArchiverAction::OpenRequest(path.display().to_string())).unwrap();
+        });
+    }
+
+}
+
+impl React<DiagnosticsPanel> for FileManager {
+
+    fn react(&self, panel : &DiagnosticsPanel) {
+        let send = self.sender().clone();
+        panel.connect_open_request(move |path| {
+            send.send(SingleArchiverAction::OpenRequest(path.display().to_string())).unwrap();
+        });
     }
 
 }
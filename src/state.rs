@@ -5,6 +5,7 @@ For a copy, see http://www.gnu.org/licenses.*/
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use stateful::React;
 use std::ops::Deref;
@@ -18,7 +19,34 @@ use filecase::SingleArchiverImpl;
 pub struct InnerState {
     pub paned : filecase::PanedState,
     pub window : filecase::WindowState,
-    pub recent_files : Vec<String>
+    pub recent_files : Vec<String>,
+
+    #[serde(default)]
+    pub live_preview : bool,
+
+    // Idle time (in milliseconds) before the live preview is updated.
+    #[serde(default="default_preview_delay")]
+    pub live_preview_delay : i32,
+
+    // Whether the document is typeset again when files it depends on change.
+    #[serde(default)]
+    pub watch_files : bool,
+
+    // Whether the preview follows the cursor.
+    #[serde(default)]
+    pub follow_cursor : bool,
+
+    // Project roots chosen by the user, by document path.
+    #[serde(default)]
+    pub project_roots : HashMap<String, String>,
+
+    // Directories searched for fonts besides the system and project font directories.
+    #[serde(default)]
+    pub font_dirs : Vec<String>
+}
+
+fn default_preview_delay() -> i32 {
+    crate::ui::DEFAULT_PREVIEW_DELAY
 }
 
 impl InnerState {
@@ -52,7 +80,13 @@ impl Default for PapersState {
         PapersState(Rc::new(RefCell::new(InnerState {
             paned : filecase::PanedState { primary : 100, secondary : 400 },
             window : filecase::WindowState { width : 1024, height : 768 },
-            recent_files : Vec::new()
+            recent_files : Vec::new(),
+            live_preview : false,
+            live_preview_delay : default_preview_delay(),
+            watch_files : false,
+            follow_cursor : false,
+            project_roots : HashMap::new(),
+            font_dirs : Vec::new()
         })))
     }
 
@@ -63,9 +97,25 @@ impl React<crate::ui::PapersWindow> for PapersState {
     fn react(&self, win : &PapersWindow) {
         let state = self.clone();
         let sidebar_paned = win.editor.sub_paned.clone();
+        let main_menu = win.titlebar.main_menu.clone();
         win.window.connect_close_request(move |win| {
             let mut state = state.borrow_mut();
             filecase::set_win_dims_on_close(&win, &mut state.window);
+            if let Some(live) = main_menu.live_preview_action.state().and_then(|s| s.get::<bool>() ) {
+                state.live_preview = live;
+            }
+            if let Some(delay) = main_menu.preview_delay_action.state().and_then(|s| s.get::<i32>() ) {
+                state.live_preview_delay = delay;
+            }
+            if let Some(watch) = main_menu.watch_action.state().and_then(|s| s.get::<bool>() ) {
+                state.watch_files = watch;
+            }
+            if let Some(follow) = main_menu.follow_cursor_action.state().and_then(|s| s.get::<bool>() ) {
+                state.follow_cursor = follow;
+            }
+            if let Some(dirs) = main_menu.font_dirs_action.state().and_then(|s| s.get::<Vec<String>>() ) {
+                state.font_dirs = dirs;
+            }
             gtk4::Inhibit(false)
         });
     }
@@ -103,6 +153,12 @@ impl PersistentState<PapersWindow> for PapersState {
         for path in state.recent_files.iter() {
             papers_win.start_screen.recent_list.add_row(&path[..], false);
         }
+        let main_menu = &papers_win.titlebar.main_menu;
+        main_menu.live_preview_action.set_state(&state.live_preview.to_variant());
+        main_menu.preview_delay_action.set_state(&state.live_preview_delay.to_variant());
+        main_menu.watch_action.set_state(&state.watch_files.to_variant());
+        main_menu.follow_cursor_action.set_state(&state.follow_cursor.to_variant());
+        main_menu.font_dirs_action.set_state(&state.font_dirs.to_variant());
     }
 
 }
@@ -7,20 +7,13 @@ use std::cmp::{Eq, PartialEq};
 use super::*;
 use either::Either;
 use std::convert::AsRef;
+use crate::typst_tools::stats::{StatsCounter, TextStats};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ObjectIndex {
-
-    // Hols item index at document top-level
-    Root(usize),
-
-    // Holds section index and item index within the section
-    Section(usize, usize),
-
-    // Holds section index, subsection index and item index within subsection
-    Subsection(usize, usize, usize)
-
-}
+/// Position of an object at the document tree: the index of each heading it is under
+/// (counting from the document top-level), followed by the index of the object among
+/// the items of the innermost heading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectIndex(pub Vec<usize>);
 
 // First field carries the "order" of the object (how many objects of the same time
 // were already added before it).
@@ -35,6 +28,8 @@ pub enum Object {
 
     Code(usize, ObjectIndex, Option<String>),
 
+    Figure(usize, ObjectIndex, FigureInfo),
+
     Bibliography(usize, String),
 
     // Paragraph(usize, ObjectIndex)
@@ -45,56 +40,96 @@ impl Object {
 
     pub fn index(&self) -> ObjectIndex {
         match self {
-            Object::Table(_, ix, _) => *ix,
-            Object::Image(_, ix, _) => *ix,
-            Object::Equation(_, ix, _) => *ix,
-            Object::Code(_, ix, _) => *ix,
-            Object::Bibliography(_, _) => ObjectIndex::Root(0),
+            Object::Table(_, ix, _) => ix.clone(),
+            Object::Image(_, ix, _) => ix.clone(),
+            Object::Equation(_, ix, _) => ix.clone(),
+            Object::Code(_, ix, _) => ix.clone(),
+            Object::Figure(_, ix, _) => ix.clone(),
+            Object::Bibliography(_, _) => ObjectIndex::default(),
             // Object::Paragraph(_, ix) => *ix,
         }
     }
 
 }
 
+/// Caption and label of a figure (as in #figure(image("cat.png"), caption : [A cat]) <cat>).
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
-pub struct Document {
+pub struct FigureInfo {
 
-    pub items : Vec<Item>
+    pub caption : Option<String>,
+
+    pub label : Option<String>
 
 }
 
+/// A label (as in <intro>) or a reference to a label or citation (as in @intro),
+/// with its zero-based line and the byte range it spans at the source.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Section {
+pub struct Marker {
+
     pub name : String,
-    pub index : usize,
-    pub items : Vec<Item>
+
+    pub line : usize,
+
+    pub range : std::ops::Range<usize>
+
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Document {
+
+    pub items : Vec<Item>,
+
+    // Counts of the whole document.
+    pub stats : TextStats,
+
+    // Labels and references, in the order they appear (empty for TeX documents).
+    pub labels : Vec<Marker>,
+
+    pub refs : Vec<Marker>,
+
+    // Paths of the files included by the document (as in #include "intro.typ").
+    pub includes : Vec<Marker>,
+
+    // Hash of the text the document was parsed from (zero for TeX documents).
+    pub text_hash : u64
+
 }
 
+/// A heading, holding everything after it up to the next heading of the same or of a higher level.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Subsection {
+pub struct Heading {
 
     pub name : String,
 
-    // Index of the parent section (section.index)
-    pub parent_index : usize,
+    // Level of the heading, starting at 1 for top-level headings.
+    pub level : usize,
 
-    // Index of the subsection within the current section (number of previous subsections)
-    pub local_index : usize,
+    // Number of the heading and of the headings it is under (e.g. [1, 2] for the second
+    // heading under the first top-level heading).
+    pub number : Vec<usize>,
 
-    // How many subsections were added before it, irrespective of their section affiliation.
-    pub global_index : usize,
+    pub items : Vec<Item>,
 
-    pub items : Vec<Item>
+    // Counts of the heading, including the headings under it.
+    pub stats : TextStats
+
+}
+
+impl Heading {
+
+    /// Number of the heading, as shown by default (e.g. "1.2").
+    pub fn number_label(&self) -> String {
+        self.number.iter().map(|n| n.to_string() ).collect::<Vec<_>>().join(".")
+    }
 
 }
 
-/// Carries item and token index.
+/// Carries item and line (or token index, for TeX documents).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Item {
 
-    Section(Section, usize),
-
-    Subsection(Subsection, usize),
+    Heading(Heading, usize),
 
     Object(Object, usize)
 
@@ -104,46 +139,138 @@ impl Item {
 
     pub fn line(&self) -> usize {
         match self {
-            Item::Section(_, ix) => *ix,
-            Item::Subsection(_, ix) => *ix,
+            Item::Heading(_, ix) => *ix,
             Item::Object(_, ix) => *ix,
         }
     }
 
     pub fn token_index(&self) -> usize {
         match self {
-            Item::Section(_, ix) => *ix,
-            Item::Subsection(_, ix) => *ix,
+            Item::Heading(_, ix) => *ix,
             Item::Object(_, ix) => *ix,
         }
     }
-}
 
-pub fn push_to_innermost(
-    top : &mut Vec<Item>,
-    section : &mut Option<(Section, usize)>,
-    subsection : &mut Option<(Subsection, usize)>,
-    item : Item
-) {
-    if let Some((ref mut sub, _)) = subsection {
-        sub.items.push(item);
-    } else {
-        if let Some((ref mut sec, _)) = section {
-            sec.items.push(item);
-        } else {
-            top.push(item);
+    pub fn children(&self) -> &[Item] {
+        match self {
+            Item::Heading(heading, _) => &heading.items[..],
+            Item::Object(_, _) => &[]
         }
     }
+
 }
 
-#[derive(Default)]
-struct ItemCount {
+/// Builds the document tree from the headings and objects of a document, in the order
+/// they are found. A heading is placed under the closest heading before it with a lower
+/// level (or at the top-level), and other items under the last heading before them.
+#[derive(Debug, Default)]
+pub struct TreeBuilder {
+
+    items : Vec<Item>,
+
+    // Headings not closed yet, from the outermost, with their line, their index at the items
+    // of their parent and the counter of their text.
+    open : Vec<(Heading, usize, usize, StatsCounter)>,
+
+    // Number of the last heading at each level.
+    counts : Vec<usize>,
 
-    section : usize,
+    // Counter of the whole document.
+    doc_counter : StatsCounter,
 
-    subsection_global : usize,
+    labels : Vec<Marker>,
 
-    subsection_local : usize,
+    refs : Vec<Marker>,
+
+    includes : Vec<Marker>
+
+}
+
+impl TreeBuilder {
+
+    fn innermost_items(&mut self) -> &mut Vec<Item> {
+        match self.open.last_mut() {
+            Some((heading, _, _, _)) => &mut heading.items,
+            None => &mut self.items
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some((mut heading, line, _, counter)) = self.open.pop() {
+            heading.stats = counter.finish();
+            self.innermost_items().push(Item::Heading(heading, line));
+        }
+    }
+
+    pub fn open_heading(&mut self, name : String, level : usize, line : usize) {
+        let level = level.max(1);
+        while self.open.last().map(|(h, _, _, _)| h.level >= level ).unwrap_or(false) {
+            self.close();
+        }
+        crate::typst_tools::outline::step_counter(&mut self.counts, level);
+        let pos = self.innermost_items().len();
+        let heading = Heading { name, level, number : self.counts.clone(), items : Vec::new(), stats : TextStats::default() };
+        self.open.push((heading, line, pos, StatsCounter::default()));
+    }
+
+    /// Index the next item pushed will have at the document tree.
+    pub fn next_index(&self) -> ObjectIndex {
+        let mut ixs : Vec<usize> = self.open.iter().map(|(_, _, pos, _)| *pos ).collect();
+        ixs.push(self.open.last().map(|(h, _, _, _)| h.items.len() ).unwrap_or(self.items.len()));
+        ObjectIndex(ixs)
+    }
+
+    pub fn push(&mut self, item : Item) {
+        self.innermost_items().push(item);
+    }
+
+    /// Records a label. A label following a figure is also given to the figure, if the
+    /// figure was the last item pushed and has no label yet.
+    pub fn label(&mut self, label : Marker, after_figure : bool) {
+        if after_figure {
+            if let Some(Item::Object(Object::Figure(_, _, info), _)) = self.innermost_items().last_mut() {
+                if info.label.is_none() {
+                    info.label = Some(label.name.clone());
+                }
+            }
+        }
+        self.labels.push(label);
+    }
+
+    pub fn reference(&mut self, reference : Marker) {
+        self.refs.push(reference);
+    }
+
+    pub fn include(&mut self, include : Marker) {
+        self.includes.push(include);
+    }
+
+    /// Counts a top-level node of a typst source, at the document and at all open headings.
+    pub fn feed(&mut self, node : &typst::syntax::SyntaxNode) {
+        self.doc_counter.feed(node);
+        for (_, _, _, counter) in self.open.iter_mut() {
+            counter.feed(node);
+        }
+    }
+
+    pub fn finish(mut self) -> Document {
+        while !self.open.is_empty() {
+            self.close();
+        }
+        Document {
+            items : self.items,
+            stats : self.doc_counter.finish(),
+            labels : self.labels,
+            refs : self.refs,
+            includes : self.includes,
+            text_hash : 0
+        }
+    }
+
+}
+
+#[derive(Default)]
+struct ItemCount {
 
     table : usize,
 
@@ -157,41 +284,20 @@ struct ItemCount {
 
 }
 
-fn local_object_index(
-    items : &[Item],
-    parent_section : &Option<(Section, usize)>,
-    parent_subsection : &Option<(Subsection, usize)>,
-    count : &ItemCount
-) -> ObjectIndex {
-    match parent_subsection {
-        Some((Subsection { local_index, items, .. }, _)) => {
-            match parent_section {
-                Some((sec, _)) => {
-                    ObjectIndex::Subsection(sec.index, *local_index, items.len())
-                },
-                None => {
-                    panic!()
-                }
-            }
-        },
-        None => {
-            match parent_section {
-                Some((sec, _)) => {
-                    ObjectIndex::Section(sec.index, sec.items.len())
-                },
-                None => {
-                    ObjectIndex::Root(items.len())
-                }
-            }
-        }
+// Heading level of TeX sectioning commands.
+fn tex_heading_level(cmd : &str) -> Option<usize> {
+    match cmd {
+        "section" => Some(1),
+        "subsection" => Some(2),
+        "subsubsection" => Some(3),
+        "paragraph" => Some(4),
+        _ => None
     }
 }
 
 fn next_item<'a>(
-    items : &mut Vec<Item>,
+    tree : &mut TreeBuilder,
     tk_ix : &mut usize,
-    parent_section : &mut Option<(Section, usize)>,
-    parent_subsection : &mut Option<(Subsection, usize)>,
     count : &mut ItemCount,
     bl_token : Either<Token<'a>, Block<'a>>
 ) -> Result<(), String> {
@@ -207,46 +313,9 @@ fn next_item<'a>(
     }
 
     match bl_token {
-        Either::Left(Token::Command(Command { cmd : "section", arg, .. }, _)) => {
-            match parent_section.take() {
-                Some((mut section, sec_tk_ix)) => {
-                    if let Some((subsection, sub_tk_ix)) = parent_subsection.take() {
-                        section.items.push(Item::Subsection(subsection, sub_tk_ix));
-                        count.subsection_global += 1;
-                        count.subsection_local = 0;
-                    }
-                    items.push(Item::Section(section, sec_tk_ix));
-                    count.section += 1;
-                },
-                None => { }
-            }
+        Either::Left(Token::Command(Command { cmd, arg, .. }, _)) if tex_heading_level(cmd).is_some() => {
             let name = arg.ok_or(String::from("Unnamed section"))?.to_string();
-            *parent_section = Some((Section { name, index : count.section, items : Vec::new() }, curr_tk_ix));
-        },
-        Either::Left(Token::Command(Command { cmd : "subsection", arg, .. }, _)) => {
-            match parent_section {
-                Some((ref mut section, _)) => {
-                    match parent_subsection.take() {
-                        Some((subsection, sub_tk_ix)) => {
-                            section.items.push(Item::Subsection(subsection, sub_tk_ix));
-                            count.subsection_local += 1;
-                            count.subsection_global += 1;
-                        },
-                        None => { }
-                    }
-                    let name = arg.ok_or(String::from("Unnamed section"))?.to_string();
-                    *parent_subsection = Some((Subsection {
-                        name,
-                        parent_index : count.section,
-                        global_index : count.subsection_global,
-                        local_index : count.subsection_local,
-                        items : Vec::new()
-                    }, curr_tk_ix));
-                },
-                None => {
-                    return Err(String::from("Subsection without section parent"));
-                }
-            }
+            tree.open_heading(name, tex_heading_level(cmd).unwrap(), curr_tk_ix);
         },
         Either::Left(Token::Command(Command { cmd : "includegraphics", .. }, range)) => {
 
@@ -256,61 +325,30 @@ fn next_item<'a>(
             \label{fig:galaxy}
             \end{figure}
             */
-            push_to_innermost(
-                items,
-                parent_section,
-                parent_subsection, Item::Object(Object::Image(count.image, local_object_index(&items[..],
-                &parent_section,
-                &parent_subsection,
-                &count), None), curr_tk_ix)
-            );
+            let ix = tree.next_index();
+            tree.push(Item::Object(Object::Image(count.image, ix, None), curr_tk_ix));
             count.image += 1;
         },
         Either::Left(Token::Text(_, range)) => {
-            /*push_to_innermost(
-                items,
-                parent_section,
-                parent_subsection,
-                Item::Object(Object::Paragraph(count.paragraph, local_object_index(&items[..],
-                &parent_section,
-                &parent_subsection,
-                &count), ))
-            );
+            /*let ix = tree.next_index();
+            tree.push(Item::Object(Object::Paragraph(count.paragraph, ix), curr_tk_ix));
             count.paragraph += 1;*/
         },
         Either::Left(Token::Math(_, _, range)) => {
-            push_to_innermost(
-                items,
-                parent_section,
-                parent_subsection,
-                Item::Object(Object::Equation(count.math, local_object_index(&items[..], &parent_section, &parent_subsection, &count),
-                None), curr_tk_ix)
-            );
+            let ix = tree.next_index();
+            tree.push(Item::Object(Object::Equation(count.math, ix, None), curr_tk_ix));
             count.math += 1;
         },
         Either::Right(Block { start_cmd : Command { arg, .. }, .. }) => {
             match arg {
                 Some(CommandArg::Text("tabular")) => {
-                    push_to_innermost(
-                        items,
-                        parent_section,
-                        parent_subsection,
-                        Item::Object(Object::Table(count.table, local_object_index(&items[..],
-                        &parent_section,
-                        &parent_subsection,
-                        &count), None), curr_tk_ix)
-                    );
+                    let ix = tree.next_index();
+                    tree.push(Item::Object(Object::Table(count.table, ix, None), curr_tk_ix));
                     count.table += 1;
                 },
                 Some(CommandArg::Text("lstlisting")) => {
-                    push_to_innermost(
-                        items,
-                        parent_section,
-                        parent_subsection,
-                        Item::Object(Object::Code(count.code, local_object_index(&items[..],
-                        &parent_section,
-                        &parent_subsection, &count), None), curr_tk_ix)
-                    );
+                    let ix = tree.next_index();
+                    tree.push(Item::Object(Object::Code(count.code, ix, None), curr_tk_ix));
                     count.code += 1;
                 },
                 _ => { }
@@ -332,194 +370,68 @@ fn is_doc_block<'a>(bl_token : &Either<Token<'a>, Block<'a>>) -> bool {
     }
 }
 
-fn iter_next_item(objs : &mut Vec<Object>, tree : &[Item]) {
-    match tree.get(0) {
-        Some(item) => match item {
-            Item::Section(Section { items, .. }, _) => {
-                iter_next_item(objs, &items[..]);
-            },
-            Item::Subsection(Subsection { items, .. }, _) => {
-                iter_next_item(objs, &items[..]);
-            },
-            Item::Object(obj, _) => {
-                objs.push(obj.clone());
-                if tree.len() > 1 {
-                    iter_next_item(objs, &tree[1..]);
-                }
-            }
-        },
-        None => { }
+fn collect_objects(objs : &mut Vec<Object>, tree : &[Item]) {
+    for item in tree {
+        match item {
+            Item::Heading(heading, _) => collect_objects(objs, &heading.items[..]),
+            Item::Object(obj, _) => objs.push(obj.clone())
+        }
     }
 }
 
-impl Document {
-
-    pub fn get_line(&self, sel_ixs : &[usize]) -> Option<usize> {
-        match (sel_ixs.get(0), sel_ixs.get(1), sel_ixs.get(2)) {
-            (Some(sec_or_obj), None, None) => {
-                Some(self.items.get(*sec_or_obj)?.line())
-            },
-            (Some(sec), Some(subsec_or_obj), None) => {
-                match self.items.get(*sec)? {
-                    Item::Section(Section { items, .. }, _) => {
-                        Some(items.get(*subsec_or_obj)?.line())
-                    },
-                    _ => None
-                }
-            },
-            (Some(sec), Some(subsec), Some(obj)) => {
-                match self.items.get(*sec)? {
-                    Item::Section(Section { items, .. }, _) => {
-                        match items.get(*subsec)? {
-                            Item::Subsection(Subsection { items, .. }, _) => {
-                                Some(items.get(*obj)?.line())
-                            },
-                            _ => None
-                        }
-                    },
-                    _ => None
-                }
-            },
-            _ => {
-                None
-            }
-        }
+fn collect_items<'a>(out : &mut Vec<(Vec<usize>, &'a Item)>, path : &mut Vec<usize>, tree : &'a [Item]) {
+    for (ix, item) in tree.iter().enumerate() {
+        path.push(ix);
+        out.push((path.clone(), item));
+        collect_items(out, path, item.children());
+        path.pop();
     }
+}
 
-    pub fn token_index_at(&self, ixs : &[usize]) -> Option<usize> {
-
-        for ix in ixs.iter() {
-            if self.items.len() <= *ix {
-                return None;
-            }
-        }
+impl Document {
 
-        match ixs.len() {
-            0 => None,
-            1 => Some(self.items[ixs[0]].token_index()),
-            2 => {
-                match &self.items[ixs[0]] {
-                    Item::Section(sec, _) => {
-                        Some(sec.items[ixs[1]].token_index())
-                    },
-                    _ => None
-                }
-            },
-            3 => {
-                match &self.items[ixs[0]] {
-                    Item::Section(sec, _) => {
-                        match &sec.items[ixs[1]] {
-                            Item::Subsection(sub, _) => {
-                                Some(sub.items[ixs[2]].token_index())
-                            },
-                            _ => None
-                        }
-                    },
-                    _ => None
-                }
-            },
-            _ => None
+    /// Item at the given path of indices at the document tree (as the paths of a tree view).
+    pub fn item_at(&self, ixs : &[usize]) -> Option<&Item> {
+        let (last, parents) = ixs.split_last()?;
+        let mut items = &self.items[..];
+        for ix in parents {
+            items = items.get(*ix)?.children();
         }
+        items.get(*last)
     }
 
-    // For the root_items, level_one_items, and level_two_items, the first field
-    // indexes the element index at the current document tree. The second field indexes
-    // the linear token index, used to find the object in the actual text document.
-    pub fn root_items(&self) -> Vec<(usize, usize, Either<Section, Object>)> {
-        let mut items = Vec::new();
-        for (ix, item) in self.items.iter().enumerate() {
-            match item {
-                Item::Section(s, tk_ix) => {
-                    items.push((ix, *tk_ix, Either::Left(s.clone())));
-                },
-                Item::Object(obj, tk_ix) => {
-                    items.push((ix, *tk_ix, Either::Right(obj.clone())));
-                },
-                _ => { }
-            }
-        }
-        items
+    pub fn get_line(&self, sel_ixs : &[usize]) -> Option<usize> {
+        Some(self.item_at(sel_ixs)?.line())
     }
 
-    pub fn level_one_items(&self) -> Vec<([usize;2], usize, Either<Subsection, Object>)> {
-        let mut items = Vec::new();
-        for (root_ix, root_item) in self.items.iter().enumerate() {
-            match root_item {
-                Item::Section(sec, _) => {
-                    for (sec_ix, sec_item) in sec.items.iter().enumerate() {
-                        match sec_item {
-                            Item::Subsection(sub, tk_ix) => {
-                                items.push(([root_ix, sec_ix], *tk_ix, Either::Left(sub.clone())));
-                            },
-                            Item::Object(obj, tk_ix) => {
-                                items.push(([root_ix, sec_ix], *tk_ix, Either::Right(obj.clone())));
-                            },
-                            _ => { }
-                        }
-                    }
-                },
-                _ => { }
-            }
-        }
-        items
+    pub fn token_index_at(&self, ixs : &[usize]) -> Option<usize> {
+        Some(self.item_at(ixs)?.token_index())
     }
 
-    pub fn level_two_items(&self) -> Vec<([usize;3], usize, Object)> {
+    /// All items of the document with their paths at the document tree, with
+    /// each heading coming before the items under it.
+    pub fn all_items(&self) -> Vec<(Vec<usize>, &Item)> {
         let mut items = Vec::new();
-        for (root_ix, root_item) in self.items.iter().enumerate() {
-            match root_item {
-                Item::Section(sec, _) => {
-                    for (sec_ix, sec_item) in sec.items.iter().enumerate() {
-                        match sec_item {
-                            Item::Subsection(sub, _) => {
-                                for (sub_ix, sub_item) in sub.items.iter().enumerate() {
-                                    match sub_item {
-                                        Item::Object(obj, tk_ix) => {
-                                            items.push(([root_ix, sec_ix, sub_ix], *tk_ix, obj.clone()));
-                                        },
-                                        _ => { }
-                                    }
-                                }
-                            },
-                            _ => { }
-                        }
-                    }
-                },
-                _ => { }
-            }
-        }
+        collect_items(&mut items, &mut Vec::new(), &self.items[..]);
         items
     }
 
     pub fn objects(&self) -> Vec<Object> {
         let mut objs = Vec::new();
-        iter_next_item(&mut objs, &self.items[..]);
+        collect_objects(&mut objs, &self.items[..]);
         objs
     }
 
-    pub fn sections(&self) -> Vec<Section> {
-        self.items.iter().filter_map(|item| {
+    /// Headings at any level, in the order they appear.
+    pub fn headings(&self) -> Vec<&Heading> {
+        self.all_items().into_iter().filter_map(|(_, item)| {
             match item {
-                Item::Section(sec, _) => {
-                    Some(sec.clone())
-                },
+                Item::Heading(heading, _) => Some(heading),
                 _ => None
             }
         }).collect()
     }
 
-    pub fn subsections(&self) -> Vec<Subsection> {
-        let secs = self.sections();
-        secs.iter().map(|sec| sec.items.iter().filter_map(|item| {
-            match item {
-                Item::Subsection(sub, _) => {
-                    Some(sub.clone())
-                },
-                _ => None
-            }
-        })).flatten().collect()
-    }
-
 }
 
 pub struct Parser {
@@ -532,7 +444,7 @@ impl Parser {
         let mut all_tks = Vec::new();
         blocked_tokens(Vec::new(), &mut tks, &mut all_tks)
             .map_err(|e| TexError { msg : e, line : 0 })?;
-        let mut doc_items : Option<Vec<Item>> = None;
+        let mut doc : Option<Document> = None;
 
         let mut tk_ix : usize = 0;
         for tk in all_tks {
@@ -541,36 +453,20 @@ impl Parser {
                 // Count beginning of the block
                 tk_ix += 1;
 
-                if doc_items.is_some() {
+                if doc.is_some() {
                     return Err(TexError { msg : String::from("Multiple document blocks found"), line : 0 } );
                 }
 
                 match tk {
                     Either::Right(Block { inner, .. }) => {
-                        let mut curr_section : Option<(Section, usize)> = None;
-                        let mut curr_subsection : Option<(Subsection, usize)> = None;
+                        let mut tree = TreeBuilder::default();
                         let mut count = ItemCount::default();
-                        let mut items : Vec<Item> = Vec::new();
 
                         for in_tk in inner {
-                            next_item(&mut items, &mut tk_ix, &mut curr_section, &mut curr_subsection, &mut count, in_tk)
+                            next_item(&mut tree, &mut tk_ix, &mut count, in_tk)
                                 .map_err(|e| TexError { msg : e, line : 0 } )?;
                         }
-
-                        // Push any residual subsections into any residual sections.
-                        if let Some((subsection, sub_tk_ix)) = curr_subsection.take() {
-                            if let Some((ref mut section, _)) = curr_section {
-                                section.items.push(Item::Subsection(subsection, sub_tk_ix));
-                            } else {
-                                return Err(TexError { msg : String::from("Subsection without section parent"), line : 0});
-                            }
-                        }
-
-                        // Push any residual sections.
-                        if let Some((section, sec_tk_ix)) = curr_section {
-                            items.push(Item::Section(section, sec_tk_ix));
-                        }
-                        doc_items = Some(items);
+                        doc = Some(tree.finish());
                     },
                     _ => {
                         panic!()
@@ -588,8 +484,8 @@ impl Parser {
             }
         }
 
-        match doc_items {
-            Some(items) => Ok(Document { items }),
+        match doc {
+            Some(doc) => Ok(doc),
             None => Err(TexError { msg : String::from("Missing document block"), line : 0 })
         }
     }
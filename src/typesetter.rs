@@ -12,7 +12,7 @@ use std::io::{Read, Write};
 use std::thread;
 use std::boxed;
 use std::process::Command;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile;
 use std::sync::mpsc;
 use std::io::{Seek, SeekFrom};
@@ -26,26 +26,28 @@ use std::path::{Path, PathBuf};
 use crate::manager::FileManager;
 use filecase::SingleArchiverImpl;
 use itertools::Itertools;
-use crate::typst_tools::Fonts;
+use crate::diagnostic::Diagnostic;
+use crate::typst_tools::export::ExportRequest;
+use crate::typst_tools::fonts::FontFamily;
+use crate::worker::{TypesettingRequest, WorkerEvent, WorkerProcess, WorkerRequest, WorkerResponse};
+use crate::typst_tools::preview::{ClickTarget, Location, Preview};
+use crate::typst_tools::outline::Outline;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 #[derive(Debug, Clone)]
 pub enum TypesetterTarget {
 
-    /// Carries path to a recently typeset file (with .pdf or .html extension)
-    File(String),
-
-    /// Carries binary content of a recently typeset PDF file
-    PDFContent(Vec<u8>),
-
-    /// Carries UTF-8 encoded content of a recently typeset HTML file
-    HTMLContent(String)
+    /// Carries the pages of a recently typeset document, as shown by the preview. HTML is
+    /// not a target: it is written by the export path, and has no preview.
+    Preview(Preview)
 
 }
 
 impl Default for TypesetterTarget {
 
     fn default() -> Self {
-        Self::PDFContent(Vec::new())
+        Self::Preview(Preview::default())
     }
 
 }
@@ -61,126 +63,458 @@ pub enum TypesetterAction {
     // current file dir.
     ChangeBaseDir(Option<PathBuf>),
 
-    Error(String)
+    // Sent when a file of the project is opened from the current document (e.g. by following
+    // a citation or a diagnostic), which keeps being typeset while the opened file is edited.
+    KeepMain,
+
+    // Sets the project root chosen by the user (None to find it from the file path).
+    SetRoot(Option<PathBuf>),
+
+    // Sets the font directories configured by the user.
+    SetFontDirs(Vec<PathBuf>),
+
+    // Carries the font families available to the current document, after fonts were indexed.
+    FontsChanged(Vec<FontFamily>),
+
+    // Carries all problems found at the last typesetting attempt.
+    Error(Vec<Diagnostic>),
+
+    // Carries the pages to be exported and where to write them.
+    Export(ExportRequest),
+
+    // Enables or disables typesetting when files the document depends on change.
+    Watch(bool),
+
+    // Carries paths of all files written by the last export.
+    Exported(Vec<PathBuf>),
+
+    ExportError(String),
+
+    // Asks for the given pages of the last document, rasterized with the given pixels per point.
+    Render(Vec<usize>, f32),
+
+    // Carries pages rasterized after a render request.
+    Rendered(Preview),
+
+    // Carries the byte offset of the cursor, to find where the text at it was laid out.
+    Locate(usize),
+
+    // Carries where the text at the cursor was laid out.
+    Located(Location),
+
+    // Carries a point clicked at the preview, to find what is at it.
+    Click(Location),
+
+    // Carries what is at the point clicked at the preview.
+    Clicked(ClickTarget),
+
+    // Carries where the content of the document just typeset was laid out.
+    Outline(Outline),
+
+    // Carries how long the last successful typesetting took.
+    Elapsed(Duration),
+
+    // Stops the current typesetting (if any), discarding requests queued after it.
+    Cancel,
+
+    // Sent back once typesetting was cancelled.
+    Cancelled
+
+}
+
+pub struct Typesetter {
+
+    send : glib::Sender<TypesetterAction>,
+
+    on_done : Callbacks<TypesetterTarget>,
+
+    on_error : Callbacks<Vec<Diagnostic>>,
+
+    on_exported : Callbacks<Vec<PathBuf>>,
+
+    on_export_error : Callbacks<String>,
+
+    on_fonts_changed : Callbacks<Vec<FontFamily>>,
+
+    on_cancelled : Callbacks<()>,
+
+    on_rendered : Callbacks<Preview>,
+
+    on_located : Callbacks<Location>,
+
+    on_clicked : Callbacks<ClickTarget>,
+
+    on_outline : Callbacks<Outline>,
+
+    on_elapsed : Callbacks<Duration>
 
 }
 
-pub struct Workspace {
+// Longest time a compilation might take before the worker typesetting it is stopped.
+const TYPESET_TIMEOUT : Duration = Duration::from_secs(30);
+
+// Time given to a compilation before it is stopped in favour of a newer request, so that
+// typing in live preview mode does not keep restarting compilations about to finish (and
+// discarding the caches of the worker).
+const SUPERSEDE_DELAY : Duration = Duration::from_millis(1000);
+
+// Interval between checks on the duration of the current compilation.
+const SUPERVISOR_TICK : Duration = Duration::from_millis(100);
+
+/// The compilation running at the worker and the request waiting for it to finish.
+/// Only the latest request is kept, and it supersedes the running compilation once
+/// that ran for longer than SUPERSEDE_DELAY.
+#[derive(Debug, Default)]
+struct CompileQueue {
+
+    // Whether a request was sent to the worker, which did not answer it yet.
+    sent : bool,
 
-    outdir : tempfile::TempDir,
+    // When the worker started the current compilation. Durations are only counted from then,
+    // since a new worker might still be indexing fonts when the request is sent.
+    started : Option<Instant>,
 
-    // file : tempfile::NamedTempFile,
+    // Latest typesetting request received while a compilation was running.
+    queued : Option<TypesettingRequest>
 
-    // out_uri : String
 }
 
-impl Workspace {
+impl CompileQueue {
+
+    // Returns the request if it can be sent now, or else keeps it (replacing any
+    // request kept before) until the current compilation finishes.
+    fn push(&mut self, req : TypesettingRequest) -> Option<TypesettingRequest> {
+        if self.busy() {
+            self.queued = Some(req);
+            None
+        } else {
+            self.sent = true;
+            Some(req)
+        }
+    }
 
-    pub fn new() -> Self {
-        let outdir = tempfile::Builder::new().tempdir().unwrap();
-        // println!("Outdir = {}", outdir.path().display());
-        // let file = tempfile::Builder::new().suffix(".tex").tempfile().unwrap();
-        // println!("Tempfile path = {}", file.path().to_str().unwrap());
-        // let out_uri = format!("file://{}/{}.pdf", outdir.path().to_str().unwrap(), file.path().file_stem().unwrap().to_str().unwrap().trim_end_matches(".tex"));
-        Self { outdir, /*file, out_uri*/ }
+    // Notes that the worker started compiling (a request sent to it, or a compilation
+    // started by the worker itself when a dependency changed).
+    fn start(&mut self, now : Instant) {
+        if self.started.is_none() {
+            self.started = Some(now);
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.sent || self.started.is_some()
+    }
+
+    // Marks the current compilation as over, returning how long it ran.
+    fn finish(&mut self, now : Instant) -> Option<Duration> {
+        self.sent = false;
+        self.started.take().map(|started| now.saturating_duration_since(started) )
+    }
+
+    // The request kept while the last compilation ran, which is sent now.
+    fn next(&mut self) -> Option<TypesettingRequest> {
+        let req = self.queued.take()?;
+        self.sent = true;
+        Some(req)
+    }
+
+    // Drops the request kept, returning whether a compilation is running.
+    fn cancel(&mut self) -> bool {
+        self.queued = None;
+        self.busy()
+    }
+
+    fn timed_out(&self, now : Instant) -> bool {
+        self.started.map(|started| now.saturating_duration_since(started) > TYPESET_TIMEOUT ).unwrap_or(false)
+    }
+
+    // Whether the running compilation should be stopped, so that the kept request is typeset now.
+    fn superseded(&self, now : Instant) -> bool {
+        self.queued.is_some() && self.started.map(|started| now.saturating_duration_since(started) > SUPERSEDE_DELAY ).unwrap_or(false)
     }
 
 }
 
-pub struct Typesetter {
+enum SupervisorEvent {
+    Request(WorkerRequest),
+    Cancel,
+    Worker(WorkerEvent)
+}
+
+/// Keeps a worker process typesetting the document, replacing it when it crashes, when a
+/// compilation takes too long or when the user cancels it.
+struct Supervisor {
+
+    worker : Option<WorkerProcess>,
+
+    // Generation of the current worker. It changes whenever a worker is stopped or started.
+    generation : u64,
+
+    events : mpsc::Sender<SupervisorEvent>,
 
     send : glib::Sender<TypesetterAction>,
 
-    on_done : Callbacks<TypesetterTarget>,
+    compiles : CompileQueue,
+
+    // Settings sent again to new workers.
+    watching : bool,
 
-    on_error : Callbacks<String>
+    font_dirs : Vec<PathBuf>,
+
+    render : Option<(Vec<usize>, f32)>
 
 }
 
-fn typeset_document_with_typst(ws : &mut Workspace, file : &Path, send : &glib::Sender<TypesetterAction>, fonts : Fonts) {
-    match crate::typst_tools::compile(file, fonts) {
-        Ok(pdf_bytes) => {
-            use std::io::Write;
-            if let Some(fname) = file.file_stem().and_then(|f| f.to_str() ) {
-                let mut out_path = PathBuf::from(ws.outdir.path().display().to_string());
-                if !out_path.exists() || !out_path.is_dir() {
-                    eprintln!("Invalid output path to PDF: {:?}", out_path);
-                    return;
+impl Supervisor {
+
+    fn worker(&mut self) -> Result<&mut WorkerProcess, String> {
+        if self.worker.is_none() {
+            self.generation += 1;
+            let events = self.events.clone();
+            let mut worker = WorkerProcess::spawn(self.generation, move |ev| { events.send(SupervisorEvent::Worker(ev)); })?;
+            worker.send(&WorkerRequest::Watch(self.watching))?;
+            worker.send(&WorkerRequest::FontDirs(self.font_dirs.clone()))?;
+            if let Some((pages, scale)) = &self.render {
+                worker.send(&WorkerRequest::Render(pages.clone(), *scale))?;
+            }
+            self.worker = Some(worker);
+        }
+        Ok(self.worker.as_mut().unwrap())
+    }
+
+    fn forward(&mut self, req : WorkerRequest) {
+        let res = self.worker().and_then(|w| w.send(&req) );
+        if let Err(e) = res {
+            self.stop();
+            match req {
+                WorkerRequest::Typeset(_) => {
+                    self.send.send(TypesetterAction::Error(vec![Diagnostic::error(e)]));
+                },
+                WorkerRequest::Export(_) => {
+                    self.send.send(TypesetterAction::ExportError(e));
+                },
+                _ => log::warn!("{}", e)
+            }
+        }
+    }
+
+    fn typeset(&mut self, req : TypesettingRequest) {
+        self.forward(WorkerRequest::Typeset(req));
+    }
+
+    fn request(&mut self, req : WorkerRequest) {
+        match req {
+            WorkerRequest::Typeset(req) => {
+                if let Some(req) = self.compiles.push(req) {
+                    self.typeset(req);
                 }
-                out_path.push(format!("{}.pdf", fname));
-                match std::fs::File::create(&out_path) {
-                    Ok(mut f) => {
-                        if let Ok(_) = f.write_all(&pdf_bytes) {
-                            send.send(TypesetterAction::Done(TypesetterTarget::File(out_path.to_str().unwrap().to_string()))).unwrap();
-                        } else {
-                            eprintln!("Unable to write to temporary file");
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Unable to create temporary file: {}", e);
-                    }
+            },
+            WorkerRequest::Watch(watch) => {
+                self.watching = watch;
+                self.forward(WorkerRequest::Watch(watch));
+            },
+            WorkerRequest::FontDirs(dirs) => {
+                self.font_dirs = dirs.clone();
+                self.forward(WorkerRequest::FontDirs(dirs));
+            },
+            WorkerRequest::Render(pages, scale) => {
+                self.render = Some((pages.clone(), scale));
+                self.forward(WorkerRequest::Render(pages, scale));
+            },
+            req => self.forward(req)
+        }
+    }
+
+    // Stops the current worker. Its remaining events are ignored, since they carry its generation.
+    fn stop(&mut self) {
+        if let Some(mut worker) = self.worker.take() {
+            worker.kill();
+        }
+        self.generation += 1;
+        self.compiles.finish(Instant::now());
+    }
+
+    fn cancel(&mut self) {
+        if self.compiles.cancel() {
+            self.stop();
+        }
+        self.send.send(TypesetterAction::Cancelled);
+    }
+
+    fn respond(&mut self, resp : WorkerResponse) {
+        match resp {
+            WorkerResponse::FontsChanged(families) => {
+                self.send.send(TypesetterAction::FontsChanged(families));
+            },
+            WorkerResponse::Started => {
+                // Compilations started by the worker itself (when a dependency changed) are also supervised.
+                self.compiles.start(Instant::now());
+            },
+            WorkerResponse::Done(preview) => {
+                if let Some(elapsed) = self.compiles.finish(Instant::now()) {
+                    self.send.send(TypesetterAction::Elapsed(elapsed));
                 }
-            } else {
-                eprintln!("Missing file name");
-            }
-        },
-        Err(errs) => {
-            for (line, msg) in errs.iter() {
-                send.send(TypesetterAction::Error(format!("(Line {}) {}", (line + 1), msg)));
+                self.send.send(TypesetterAction::Done(TypesetterTarget::Preview(preview)));
+                self.typeset_queued();
+            },
+            WorkerResponse::Rendered(preview) => {
+                self.send.send(TypesetterAction::Rendered(preview));
+            },
+            WorkerResponse::Located(loc) => {
+                self.send.send(TypesetterAction::Located(loc));
+            },
+            WorkerResponse::Clicked(target) => {
+                self.send.send(TypesetterAction::Clicked(target));
+            },
+            WorkerResponse::Outline(outline) => {
+                self.send.send(TypesetterAction::Outline(outline));
+            },
+            WorkerResponse::Error(diagnostics) => {
+                self.compiles.finish(Instant::now());
+                self.send.send(TypesetterAction::Error(diagnostics));
+                self.typeset_queued();
+            },
+            WorkerResponse::Exported(paths) => {
+                self.send.send(TypesetterAction::Exported(paths));
+            },
+            WorkerResponse::ExportError(e) => {
+                self.send.send(TypesetterAction::ExportError(e));
             }
         }
     }
-}
 
-pub struct TypesettingRequest {
+    fn typeset_queued(&mut self) {
+        if let Some(req) = self.compiles.next() {
+            self.typeset(req);
+        }
+    }
 
-    content : String,
+    // The worker exited by itself, which only happens when it crashes.
+    fn exited(&mut self) {
+        let Some(mut worker) = self.worker.take() else { return };
+        let mut lines = worker.wait().into_iter();
+        let mut diag = Diagnostic::error(lines.next().unwrap_or_default());
+        diag.hints.extend(lines);
+        self.generation += 1;
+        if self.compiles.busy() {
+            self.compiles.finish(Instant::now());
+            self.send.send(TypesetterAction::Error(vec![diag]));
+        } else {
+            log::warn!("{}: {}", diag.message, diag.hints.join("\n"));
+        }
+        self.typeset_queued();
+    }
 
-    base_path : Option<PathBuf>,
+    fn check_duration(&mut self) {
+        if self.compiles.timed_out(Instant::now()) {
+            self.stop();
+            let diag = Diagnostic::error(format!("Typesetting was stopped after {} seconds", TYPESET_TIMEOUT.as_secs()))
+                .with_hint("The document might never finish compiling (e.g. due to a recursive function without a base case)");
+            self.send.send(TypesetterAction::Error(vec![diag]));
+            self.typeset_queued();
+        } else if self.compiles.superseded(Instant::now()) {
+            self.stop();
+            self.typeset_queued();
+        }
+    }
 
-    file :  Option<PathBuf>
+    fn run(&mut self, recv : mpsc::Receiver<SupervisorEvent>) {
+        // Workers are started ahead of the first request, so that fonts are indexed early.
+        if let Err(e) = self.worker() {
+            log::warn!("{}", e);
+            self.stop();
+        }
+        loop {
+            match recv.recv_timeout(SUPERVISOR_TICK) {
+                Ok(SupervisorEvent::Request(req)) => self.request(req),
+                Ok(SupervisorEvent::Cancel) => self.cancel(),
+                Ok(SupervisorEvent::Worker(WorkerEvent::Response(gen, resp))) if gen == self.generation => self.respond(resp),
+                Ok(SupervisorEvent::Worker(WorkerEvent::Exited(gen))) if gen == self.generation => self.exited(),
+
+                // Events of workers that were already stopped.
+                Ok(SupervisorEvent::Worker(_)) => { },
+                Err(mpsc::RecvTimeoutError::Timeout) => { },
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+            self.check_duration();
+        }
+    }
 
 }
 
 impl Typesetter {
 
-    pub fn new(fonts : Fonts) -> Self {
+    pub fn new() -> Self {
         let (send, recv) = glib::MainContext::channel::<TypesetterAction>(glib::PRIORITY_DEFAULT);
         let on_done : Callbacks<TypesetterTarget> = Default::default();
-        let on_error : Callbacks<String> = Default::default();
-        let (content_send, content_recv) = mpsc::channel::<TypesettingRequest>();
+        let on_error : Callbacks<Vec<Diagnostic>> = Default::default();
+        let on_exported : Callbacks<Vec<PathBuf>> = Default::default();
+        let on_export_error : Callbacks<String> = Default::default();
+        let on_fonts_changed : Callbacks<Vec<FontFamily>> = Default::default();
+        let on_cancelled : Callbacks<()> = Default::default();
+        let on_rendered : Callbacks<Preview> = Default::default();
+        let on_located : Callbacks<Location> = Default::default();
+        let on_clicked : Callbacks<ClickTarget> = Default::default();
+        let on_outline : Callbacks<Outline> = Default::default();
+        let on_elapsed : Callbacks<Duration> = Default::default();
+        let (content_send, content_recv) = mpsc::channel::<SupervisorEvent>();
 
         thread::spawn({
             let send = send.clone();
+            let events = content_send.clone();
             move || {
-                let mut ws = Workspace::new();
-                loop {
-                    match content_recv.recv() {
-                        Ok(TypesettingRequest { content, base_path, file }) => {
-                            // typeset_document_from_lib(&mut ws, &content, base_path.as_ref().map(|p| p.as_path() ), &send);
-                            // typeset_document_from_cli(&mut ws, &content, base_path.as_ref().map(|p| p.as_path() ), &send)
-                            if let Some(file) = file {
-                                typeset_document_with_typst(&mut ws, &file, &send, fonts.clone());
-                            } else {
-                                println!("Missing current file");
-                            }
-                        },
-                        _ => { }
-                    }
-                }
+                let mut supervisor = Supervisor {
+                    worker : None,
+                    generation : 0,
+                    events,
+                    send,
+                    compiles : CompileQueue::default(),
+                    watching : false,
+                    font_dirs : Vec::new(),
+                    render : None
+                };
+                supervisor.run(content_recv);
             }
         });
 
-        let mut base_path : Option<PathBuf> = None;
+        let mut configured_root : Option<PathBuf> = None;
         let mut file : Option<PathBuf> = None;
+
+        // Document typeset in place of the edited file, when it includes the file.
+        let mut main : Option<PathBuf> = None;
+        let mut keep_main = false;
         recv.attach(None, {
             let send = send.clone();
             let on_done = on_done.clone();
             let on_error = on_error.clone();
+            let on_exported = on_exported.clone();
+            let on_export_error = on_export_error.clone();
+            let on_fonts_changed = on_fonts_changed.clone();
+            let on_cancelled = on_cancelled.clone();
+            let on_rendered = on_rendered.clone();
+            let on_located = on_located.clone();
+            let on_clicked = on_clicked.clone();
+            let on_outline = on_outline.clone();
+            let on_elapsed = on_elapsed.clone();
             move |action| {
                 match action {
                     TypesetterAction::Request(txt) => {
-                        content_send.send(TypesettingRequest { content : txt, base_path : base_path.clone(), file : file.clone() });
+                        let root = main.as_deref().or(file.as_deref())
+                            .and_then(|f| crate::typst_tools::project_root(f, configured_root.as_deref()) );
+                        let req = TypesettingRequest { content : txt, root, file : file.clone(), main : main.clone() };
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Typeset(req)));
+                    },
+                    TypesetterAction::Export(req) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Export(req)));
+                    },
+                    TypesetterAction::Watch(watch) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Watch(watch)));
+                    },
+                    TypesetterAction::Exported(paths) => {
+                        on_exported.call(paths);
+                    },
+                    TypesetterAction::ExportError(e) => {
+                        on_export_error.call(e);
                     },
                     TypesetterAction::Done(target) => {
                         on_done.call(target.clone());
@@ -190,23 +524,150 @@ impl Typesetter {
                     },
                     TypesetterAction::ChangeBaseDir(opt_path) => {
                         if let Some(path) = opt_path {
-                            if let Some(parent) = Path::new(&path).parent() {
-                                base_path = Some(parent.to_owned());
+                            if Path::new(&path).parent().is_some() {
+
+                                // Saving the edited file again keeps its main.
+                                if file.as_deref() != Some(path.as_path()) {
+                                    main = if std::mem::take(&mut keep_main) {
+                                        main.take().or(file.take()).filter(|m| m != &path )
+                                    } else {
+                                        None
+                                    };
+                                    if let Some(main) = &main {
+                                        log::info!("Typesetting {} while {} is edited", main.display(), path.display());
+                                    }
+                                }
                                 file = Some(path.to_owned());
                             } else {
                                 log::warn!("File without valid parent path");
                             }
                         } else {
-                            base_path = None;
                             file = None;
+                            main = None;
+                            keep_main = false;
                         }
+                    },
+                    TypesetterAction::KeepMain => {
+                        keep_main = file.is_some();
+                    },
+                    TypesetterAction::SetRoot(root) => {
+                        configured_root = root;
+                    },
+                    TypesetterAction::SetFontDirs(dirs) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::FontDirs(dirs)));
+                    },
+                    TypesetterAction::FontsChanged(families) => {
+                        on_fonts_changed.call(families);
+                    },
+                    TypesetterAction::Render(pages, scale) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Render(pages, scale)));
+                    },
+                    TypesetterAction::Rendered(preview) => {
+                        on_rendered.call(preview);
+                    },
+                    TypesetterAction::Locate(offset) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Locate(offset)));
+                    },
+                    TypesetterAction::Located(loc) => {
+                        on_located.call(loc);
+                    },
+                    TypesetterAction::Click(loc) => {
+                        content_send.send(SupervisorEvent::Request(WorkerRequest::Click(loc)));
+                    },
+                    TypesetterAction::Clicked(target) => {
+                        on_clicked.call(target);
+                    },
+                    TypesetterAction::Outline(outline) => {
+                        on_outline.call(outline);
+                    },
+                    TypesetterAction::Elapsed(elapsed) => {
+                        on_elapsed.call(elapsed);
+                    },
+                    TypesetterAction::Cancel => {
+                        content_send.send(SupervisorEvent::Cancel);
+                    },
+                    TypesetterAction::Cancelled => {
+                        on_cancelled.call(());
                     }
                 }
                 Continue(true)
             }
         });
 
-        Self { send, on_done, on_error }
+        Self { send, on_done, on_error, on_exported, on_export_error, on_fonts_changed, on_cancelled, on_rendered, on_located, on_clicked, on_outline, on_elapsed }
+    }
+
+    /// Called with the font families available to the document once fonts are indexed,
+    /// and whenever the font folders of the document change them.
+    pub fn connect_fonts_changed<F>(&self, f : F)
+    where
+        F : Fn(Vec<FontFamily>) + 'static
+    {
+        self.on_fonts_changed.bind(f);
+    }
+
+    /// Called with pages rasterized after the preview asked for them (e.g. after a zoom change).
+    pub fn connect_rendered<F>(&self, f : F)
+    where
+        F : Fn(Preview) + 'static
+    {
+        self.on_rendered.bind(f);
+    }
+
+    /// Called with where the text at the cursor was laid out, after the preview was asked to show it.
+    pub fn connect_located<F>(&self, f : F)
+    where
+        F : Fn(Location) + 'static
+    {
+        self.on_located.bind(f);
+    }
+
+    /// Called with what is at a point clicked at the preview (e.g. the source text that produced it).
+    pub fn connect_clicked<F>(&self, f : F)
+    where
+        F : Fn(ClickTarget) + 'static
+    {
+        self.on_clicked.bind(f);
+    }
+
+    /// Called after the document is typeset with where its headings (and the content
+    /// of each line of the main source) were laid out.
+    pub fn connect_outline<F>(&self, f : F)
+    where
+        F : Fn(Outline) + 'static
+    {
+        self.on_outline.bind(f);
+    }
+
+    /// Called with how long the document took to be typeset, just before it is shown.
+    pub fn connect_elapsed<F>(&self, f : F)
+    where
+        F : Fn(Duration) + 'static
+    {
+        self.on_elapsed.bind(f);
+    }
+
+    /// Called when the user stopped typesetting before it finished.
+    pub fn connect_cancelled<F>(&self, f : F)
+    where
+        F : Fn(()) + 'static
+    {
+        self.on_cancelled.bind(f);
+    }
+
+    /// Called with the paths of all files written after an export request.
+    pub fn connect_exported<F>(&self, f : F)
+    where
+        F : Fn(Vec<PathBuf>) + 'static
+    {
+        self.on_exported.bind(f);
+    }
+
+    pub fn connect_export_error<F>(&self, f : F)
+    where
+        F : Fn(String) + 'static
+    {
+        self.on_export_error.bind(f);
     }
 
     pub fn connect_done<F>(&self, f : F)
@@ -218,22 +679,13 @@ impl Typesetter {
 
     pub fn connect_error<F>(&self, f : F)
     where
-        F : Fn(String) + 'static
+        F : Fn(Vec<Diagnostic>) + 'static
     {
         self.on_error.bind(f);
     }
 
 }
 
-fn request_typesetting_file(
-    pdf_btn : &Button,
-    send : &glib::Sender<TypesetterAction>
-) {
-    send.send(TypesetterAction::Request(String::new())).unwrap();
-    pdf_btn.set_icon_name("timer-symbolic");
-    pdf_btn.set_sensitive(false);
-}
-
 fn request_typesetting_buffer(
     pdf_btn : &Button,
     view : &sourceview5::View,
@@ -248,33 +700,227 @@ fn request_typesetting_buffer(
     ).to_string();
 
     if txt.is_empty() {
-        send.send(TypesetterAction::Error(String::from("Cannot typeset empty document")));
+        send.send(TypesetterAction::Error(vec![Diagnostic::error("Cannot typeset empty document")]));
         return;
     }
 
     send.send(TypesetterAction::Request(txt)).unwrap();
-    pdf_btn.set_icon_name("timer-symbolic");
-    pdf_btn.set_sensitive(false);
+
+    // While typesetting, the button stops it.
+    pdf_btn.set_icon_name(CANCEL_ICON);
+    pdf_btn.set_tooltip_text(Some("Stop typesetting"));
     //refresh_btn.set_sensitive(false);
 }
 
+const CANCEL_ICON : &str = "process-stop-symbolic";
+
+// Idle time (in milliseconds) after the cursor moves before the preview follows it.
+const FOLLOW_CURSOR_DELAY : u64 = 150;
+
+fn is_following_cursor(follow_cursor_action : &gio::SimpleAction) -> bool {
+    follow_cursor_action.state().and_then(|s| s.get::<bool>() ).unwrap_or(false)
+}
+
+// Byte offset of the cursor at the buffer (spans of typst sources count bytes).
+fn cursor_offset(view : &sourceview5::View) -> usize {
+    let buffer = view.buffer();
+    let cursor = buffer.iter_at_offset(buffer.cursor_position());
+    buffer.text(&buffer.start_iter(), &cursor, true).len()
+}
+
+fn is_typesetting(pdf_btn : &Button) -> bool {
+    pdf_btn.icon_name().map(|name| name == CANCEL_ICON ).unwrap_or(false)
+}
+
 impl React<PapersWindow> for Typesetter {
 
     fn react(&self, win : &PapersWindow) {
         let (titlebar, editor) = (&win.titlebar, &win.editor);
 
+        // Files opened from the document are files of its project (usually files it includes).
+        editor.connect_open_request({
+            let send = self.send.clone();
+            move |_| {
+                send.send(TypesetterAction::KeepMain).unwrap();
+            }
+        });
+        win.diagnostics_panel.connect_open_request({
+            let send = self.send.clone();
+            move |_| {
+                send.send(TypesetterAction::KeepMain).unwrap();
+            }
+        });
+
         titlebar.typeset_action.connect_activate({
             let view = editor.view.clone();
             let send = self.send.clone();
             let pdf_btn = titlebar.pdf_btn.clone();
             move |_, _| {
-                request_typesetting_file(&pdf_btn, &send);
+                request_typesetting_buffer(&pdf_btn, &view, &send);
+            }
+        });
+        titlebar.cancel_typesetting_action.connect_activate({
+            let send = self.send.clone();
+            move |_, _| {
+                send.send(TypesetterAction::Cancel);
             }
         });
         titlebar.pdf_btn.connect_clicked({
             let typeset_action = titlebar.typeset_action.clone();
+            let cancel_typesetting_action = titlebar.cancel_typesetting_action.clone();
             move |btn| {
-                typeset_action.activate(None);
+                if is_typesetting(btn) {
+                    cancel_typesetting_action.activate(None);
+                } else {
+                    typeset_action.activate(None);
+                }
+            }
+        });
+
+        // Typesets when files read by the last typesetting change on disk.
+        let watch_action = titlebar.main_menu.watch_action.clone();
+        if let Some(watch) = watch_action.state().and_then(|s| s.get::<bool>() ) {
+            self.send.send(TypesetterAction::Watch(watch));
+        }
+        watch_action.connect_notify_local(Some("state"), {
+            let send = self.send.clone();
+            move |action, _| {
+                if let Some(watch) = action.state().and_then(|s| s.get::<bool>() ) {
+                    send.send(TypesetterAction::Watch(watch));
+                }
+            }
+        });
+
+        // Typesets with the project root chosen by the user (if any).
+        let main_menu = titlebar.main_menu.clone();
+        self.send.send(TypesetterAction::SetRoot(main_menu.configured_root()));
+        main_menu.project_root_action.connect_notify_local(Some("state"), {
+            let send = self.send.clone();
+            let main_menu = main_menu.clone();
+            move |_, _| {
+                send.send(TypesetterAction::SetRoot(main_menu.configured_root()));
+            }
+        });
+
+        // Indexes the fonts at the directories configured by the user, typesetting
+        // the document again so that they take effect immediately.
+        self.send.send(TypesetterAction::SetFontDirs(main_menu.font_dirs()));
+        main_menu.font_dirs_action.connect_notify_local(Some("state"), {
+            let send = self.send.clone();
+            let main_menu = main_menu.clone();
+            let view = editor.view.clone();
+            let pdf_btn = titlebar.pdf_btn.clone();
+            move |_, _| {
+                send.send(TypesetterAction::SetFontDirs(main_menu.font_dirs()));
+                if view.buffer().char_count() > 0 {
+                    request_typesetting_buffer(&pdf_btn, &view, &send);
+                }
+            }
+        });
+
+        // Forward search: shows where the text at the cursor was laid out.
+        titlebar.show_in_preview_action.connect_activate({
+            let view = editor.view.clone();
+            let send = self.send.clone();
+            move |_, _| {
+                send.send(TypesetterAction::Locate(cursor_offset(&view)));
+            }
+        });
+
+        // While following the cursor, the preview shows it once it stops moving for a while,
+        // and after the document is typeset again.
+        let follow_cursor_action = titlebar.main_menu.follow_cursor_action.clone();
+        let follow_pending : Rc<RefCell<Option<glib::SourceId>>> = Rc::new(RefCell::new(None));
+        editor.view.buffer().connect_cursor_position_notify({
+            let follow_cursor_action = follow_cursor_action.clone();
+            let view = editor.view.clone();
+            let send = self.send.clone();
+            move |_| {
+                if !is_following_cursor(&follow_cursor_action) {
+                    return;
+                }
+                if let Some(id) = follow_pending.borrow_mut().take() {
+                    id.remove();
+                }
+                let id = glib::timeout_add_local_once(Duration::from_millis(FOLLOW_CURSOR_DELAY), {
+                    let follow_pending = follow_pending.clone();
+                    let view = view.clone();
+                    let send = send.clone();
+                    move || {
+                        follow_pending.borrow_mut().take();
+                        send.send(TypesetterAction::Locate(cursor_offset(&view)));
+                    }
+                });
+                *follow_pending.borrow_mut() = Some(id);
+            }
+        });
+        self.connect_done({
+            let view = editor.view.clone();
+            let send = self.send.clone();
+            move |_| {
+                if is_following_cursor(&follow_cursor_action) {
+                    send.send(TypesetterAction::Locate(cursor_offset(&view)));
+                }
+            }
+        });
+
+        // Pages are rasterized by the worker for the zoom and screen they are shown at.
+        editor.pdf_viewer.connect_render_request({
+            let send = self.send.clone();
+            move |(pages, scale)| {
+                send.send(TypesetterAction::Render(pages, scale));
+            }
+        });
+        editor.pdf_viewer.request_pages();
+
+        // Inverse search: finds the source text that produced the clicked point.
+        editor.pdf_viewer.connect_click({
+            let send = self.send.clone();
+            move |loc| {
+                send.send(TypesetterAction::Click(loc));
+            }
+        });
+
+        win.export_dialog.connect_export({
+            let send = self.send.clone();
+            move |req| {
+                send.send(TypesetterAction::Export(req));
+            }
+        });
+
+        // Live preview: Typeset the buffer once the user stops editing it for a while.
+        let pending : Rc<RefCell<Option<glib::SourceId>>> = Rc::new(RefCell::new(None));
+        editor.view.buffer().connect_changed({
+            let preview_delay_action = titlebar.main_menu.preview_delay_action.clone();
+            let view = editor.view.clone();
+            let send = self.send.clone();
+            let pdf_btn = titlebar.pdf_btn.clone();
+            let titlebar = titlebar.clone();
+            move |_| {
+                if !titlebar.is_live_preview() {
+                    return;
+                }
+                if let Some(id) = pending.borrow_mut().take() {
+                    id.remove();
+                }
+                let delay = preview_delay_action.state()
+                    .and_then(|s| s.get::<i32>() )
+                    .unwrap_or(DEFAULT_PREVIEW_DELAY)
+                    .max(0);
+                let id = glib::timeout_add_local_once(Duration::from_millis(delay as u64), {
+                    let pending = pending.clone();
+                    let view = view.clone();
+                    let send = send.clone();
+                    let pdf_btn = pdf_btn.clone();
+                    move || {
+                        // The source is removed after this returns, so it must not be removed again.
+                        pending.borrow_mut().take();
+                        if view.buffer().char_count() > 0 {
+                            request_typesetting_buffer(&pdf_btn, &view, &send);
+                        }
+                    }
+                });
+                *pending.borrow_mut() = Some(id);
             }
         });
         /*titlebar.pdf_btn.connect_clicked({
@@ -322,3 +968,35 @@ impl React<FileManager> for Typesetter {
 }
 
 
+
+#[test]
+fn compilations_are_queued_until_finished_or_timed_out() {
+    let req = |content : &str| TypesettingRequest { content : content.to_string(), root : None, file : None, main : None };
+    let t0 = Instant::now();
+    let t1 = t0 + Duration::from_secs(5);
+    let mut compiles = CompileQueue::default();
+    assert_eq!(compiles.push(req("a")).map(|r| r.content ), Some(String::from("a")));
+    assert!(compiles.push(req("b")).is_none());
+    assert!(compiles.push(req("c")).is_none());
+
+    // Time spent before the worker starts compiling (e.g. indexing fonts) is not counted.
+    assert!(!compiles.timed_out(t0 + TYPESET_TIMEOUT * 2));
+    assert!(!compiles.superseded(t1));
+    compiles.start(t0);
+
+    // Newer requests wait for the running compilation, and only the latest is typeset.
+    assert!(!compiles.superseded(t0 + SUPERSEDE_DELAY));
+    assert!(compiles.superseded(t1));
+    assert!(!compiles.timed_out(t1));
+    assert_eq!(compiles.finish(t1), Some(Duration::from_secs(5)));
+    assert_eq!(compiles.next().map(|r| r.content ), Some(String::from("c")));
+    assert!(compiles.next().is_none());
+    compiles.start(t1);
+
+    assert!(compiles.timed_out(t1 + TYPESET_TIMEOUT + Duration::from_millis(1)));
+    assert!(compiles.push(req("d")).is_none());
+    assert!(compiles.cancel());
+    compiles.finish(t1);
+    assert!(compiles.next().is_none());
+    assert!(!compiles.cancel());
+}
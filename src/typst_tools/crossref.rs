@@ -0,0 +1,316 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use typst::syntax::{Source, SourceId};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::tex::{Document, Marker, Object};
+
+// How deep includes are followed, so that a file including itself does not hang the analysis.
+const MAX_INCLUDE_DEPTH : usize = 16;
+
+/// A file of a project, with its parsed document model.
+#[derive(Clone)]
+pub struct ProjectFile {
+
+    // None for the document edited when it was not saved yet.
+    pub path : Option<PathBuf>,
+
+    pub source : Source,
+
+    pub doc : Document
+
+}
+
+/// Keys of the entries of a bibliography file, with the byte range of each key at the file.
+pub struct BibIndex {
+
+    pub path : Option<PathBuf>,
+
+    pub source : Source,
+
+    pub entries : Vec<(String, Range<usize>)>
+
+}
+
+impl BibIndex {
+
+    pub fn new(path : Option<PathBuf>, txt : String, keys : impl Iterator<Item=String>) -> Self {
+        let entries = keys.map(|key| {
+            let start = txt.find(&format!("{{{},", key))
+                .or_else(|| txt.find(&format!("{{{}", key)) )
+                .map(|pos| pos + 1)
+                .unwrap_or(0);
+            let range = start..(start + key.len()).min(txt.len());
+            (key, range)
+        }).collect();
+        let source = Source::new(SourceId::detached(), path.as_deref().unwrap_or(Path::new("")), txt);
+        Self { path, source, entries }
+    }
+
+    pub fn contains(&self, key : &str) -> bool {
+        self.entries.iter().any(|(k, _)| k == key )
+    }
+
+}
+
+/// A place where a bibliography entry is cited (as in @knuth1984), with its
+/// zero-based line and column (in characters).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+
+    pub key : String,
+
+    pub path : Option<PathBuf>,
+
+    pub line : usize,
+
+    pub column : usize
+
+}
+
+impl Citation {
+
+    /// Short description of where the citation is (e.g. "intro.typ:12").
+    pub fn place(&self) -> String {
+        match self.path.as_deref().and_then(Path::file_name) {
+            Some(name) => format!("{}:{}", name.to_string_lossy(), self.line + 1),
+            None => format!("Line {}", self.line + 1)
+        }
+    }
+
+}
+
+/// Files included by documents at previous analyses, so that they are only read and
+/// parsed again when they were modified since then.
+#[derive(Default)]
+pub struct IncludeCache {
+
+    // Modification time of each file when it was read, and the file (None if it could not be read or parsed).
+    files : HashMap<PathBuf, (Option<SystemTime>, Option<ProjectFile>)>
+
+}
+
+impl IncludeCache {
+
+    fn get(&mut self, path : &Path) -> Option<ProjectFile> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified() ).ok();
+        if let Some((cached, file)) = self.files.get(path) {
+            if modified.is_some() && *cached == modified {
+                return file.clone();
+            }
+        }
+        let file = read_include(path);
+        self.files.insert(path.to_owned(), (modified, file.clone()));
+        file
+    }
+
+}
+
+fn read_include(path : &Path) -> Option<ProjectFile> {
+    let txt = match std::fs::read_to_string(path) {
+        Ok(txt) => txt,
+        Err(e) => {
+            log::warn!("Unable to read included file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    // Included files with syntax errors are left out (the errors are shown when they are opened).
+    let doc = super::parse_doc(Some(path), txt.clone()).ok()?;
+    Some(ProjectFile { source : Source::new(SourceId::detached(), path, txt), path : Some(path.to_owned()), doc })
+}
+
+fn include_files(
+    file : &ProjectFile,
+    root : &Path,
+    depth : usize,
+    cache : &mut IncludeCache,
+    visited : &mut HashSet<PathBuf>,
+    out : &mut Vec<ProjectFile>
+) {
+    let dir = match file.path.as_deref().and_then(Path::parent) {
+        Some(dir) => dir.to_owned(),
+        None => return
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return;
+    }
+    for include in &file.doc.includes {
+        let path = super::resolve_path(root, &dir, &include.name);
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        if let Some(inc) = cache.get(&path) {
+            include_files(&inc, root, depth + 1, cache, visited, out);
+            out.push(inc);
+        }
+    }
+}
+
+/// The document being edited followed by all files it includes, directly or through
+/// other included files. Files are only included from documents saved to a file.
+/// Included files are taken from the cache unless they changed, and files not
+/// included anymore are dropped from it.
+pub fn project_files(
+    path : Option<&Path>,
+    txt : &str,
+    doc : &Document,
+    root : Option<&Path>,
+    cache : &mut IncludeCache
+) -> Vec<ProjectFile> {
+    let main = ProjectFile {
+        path : path.map(|p| p.to_owned() ),
+        source : Source::new(SourceId::detached(), path.unwrap_or(Path::new("")), txt.to_string()),
+        doc : doc.clone()
+    };
+    let mut files = Vec::new();
+    if let (Some(path), Some(root)) = (path, root) {
+        let mut visited = HashSet::new();
+        visited.insert(path.to_owned());
+        include_files(&main, root, 0, cache, &mut visited, &mut files);
+        cache.files.retain(|p, _| visited.contains(p) );
+    }
+    files.insert(0, main);
+    files
+}
+
+fn marker_diagnostic(severity : Severity, msg : String, file : &ProjectFile, marker : &Marker) -> Diagnostic {
+    Diagnostic::at_source(severity, msg, &file.source, file.path.clone(), marker.range.clone())
+}
+
+fn place(file : &ProjectFile, marker : &Marker) -> String {
+    match &file.path {
+        Some(path) => format!("{}:{}", path.display(), marker.line + 1),
+        None => format!("line {}", marker.line + 1)
+    }
+}
+
+fn is_label(files : &[ProjectFile], name : &str) -> bool {
+    files.iter().any(|f| f.doc.labels.iter().any(|l| l.name == name ) )
+}
+
+/// Every place where an entry of the bibliography is cited, in the order of the files.
+/// References to labels are not citations, even when an entry has the same key.
+pub fn citations(files : &[ProjectFile], bib : &BibIndex) -> Vec<Citation> {
+    let mut cites = Vec::new();
+    for file in files {
+        for reference in &file.doc.refs {
+            if bib.contains(&reference.name) && !is_label(files, &reference.name) {
+                cites.push(Citation {
+                    key : reference.name.clone(),
+                    path : file.path.clone(),
+                    line : reference.line,
+                    column : file.source.byte_to_column(reference.range.start).unwrap_or(0)
+                });
+            }
+        }
+    }
+    cites
+}
+
+/// Checks the labels and references of all files of a project: references to labels
+/// not defined anywhere, labels defined more than once and labels never referenced.
+/// References also cite bibliography entries, so references are only reported as
+/// undefined when the bibliography (if the project has one) was read. Entries of the
+/// bibliography never cited are reported at the bibliography file.
+pub fn crossref_lints(files : &[ProjectFile], bib : Option<&BibIndex>) -> Vec<Diagnostic> {
+    let mut labels : HashMap<&str, Vec<(&ProjectFile, &Marker)>> = HashMap::new();
+    for file in files {
+        for label in &file.doc.labels {
+            labels.entry(&label.name[..]).or_default().push((file, label));
+        }
+    }
+    let referenced : HashSet<&str> = files.iter()
+        .flat_map(|f| f.doc.refs.iter().map(|r| &r.name[..] ) )
+        .collect();
+    let has_bib = files.iter()
+        .any(|f| f.doc.objects().iter().any(|obj| matches!(obj, Object::Bibliography(..)) ) );
+
+    // Whether references can be checked (i.e. either there is no bibliography or it was read).
+    let check_refs = bib.is_some() || !has_bib;
+
+    let mut lints = Vec::new();
+    for file in files {
+        for label in &file.doc.labels {
+            let defs = &labels[&label.name[..]];
+            let (first_file, first) = defs[0];
+            if !std::ptr::eq(first, label) {
+                let msg = format!("Label <{}> is defined more than once", label.name);
+                lints.push(marker_diagnostic(Severity::Warning, msg, file, label)
+                    .with_hint(format!("First defined at {}", place(first_file, first))));
+            } else if !referenced.contains(&label.name[..]) {
+                let msg = format!("Label <{}> is never referenced", label.name);
+                lints.push(marker_diagnostic(Severity::Warning, msg, file, label));
+            }
+        }
+        if check_refs {
+            for reference in &file.doc.refs {
+                if labels.contains_key(&reference.name[..]) || bib.map(|b| b.contains(&reference.name) ).unwrap_or(false) {
+                    continue;
+                }
+                let diag = match bib {
+                    Some(bib) => {
+                        let bib_name = bib.path.as_deref()
+                            .and_then(Path::file_name)
+                            .map(|n| n.to_string_lossy().to_string() )
+                            .unwrap_or(String::from("the bibliography"));
+                        let msg = format!("Citation @{} has no entry at {}", reference.name, bib_name);
+                        marker_diagnostic(Severity::Error, msg, file, reference)
+                            .with_hint(format!("No label <{}> is defined either", reference.name))
+                    },
+                    None => {
+                        let msg = format!("Reference to undefined label <{}>", reference.name);
+                        marker_diagnostic(Severity::Error, msg, file, reference)
+                    }
+                };
+                lints.push(diag);
+            }
+        }
+    }
+    if let Some(bib) = bib {
+        for (key, range) in &bib.entries {
+            if !referenced.contains(&key[..]) {
+                let msg = format!("Bibliography entry {} is never cited", key);
+                lints.push(Diagnostic::at_source(Severity::Warning, msg, &bib.source, bib.path.clone(), range.clone()));
+            }
+        }
+    }
+    lints
+}
+
+#[test]
+fn labels_and_references_are_checked() {
+    let txt = "= Intro <intro>\nSee @intro, @missing and @knuth here.\n#figure([A]) <fig>\n#figure([B]) <fig>\n";
+    let doc = super::parse_doc(None, txt.to_string()).unwrap();
+    let files = project_files(None, txt, &doc, None, &mut IncludeCache::default());
+    let msgs : Vec<_> = crossref_lints(&files, None).into_iter().map(|d| (d.message, d.line) ).collect();
+    assert_eq!(msgs, vec![
+        (String::from("Label <fig> is never referenced"), 2),
+        (String::from("Label <fig> is defined more than once"), 3),
+        (String::from("Reference to undefined label <missing>"), 1),
+        (String::from("Reference to undefined label <knuth>"), 1)
+    ]);
+}
+
+#[test]
+fn citations_are_checked_against_bibliography() {
+    let txt = "As @knuth and @lamport show.\n#bibliography(\"refs.bib\")\n";
+    let doc = super::parse_doc(None, txt.to_string()).unwrap();
+    let files = project_files(None, txt, &doc, None, &mut IncludeCache::default());
+    let bib_txt = String::from("@book{knuth,\n  title = {TeX}\n}\n@book{dijkstra,\n  title = {GOTO}\n}\n");
+    let keys = vec![String::from("knuth"), String::from("dijkstra")];
+    let bib = BibIndex::new(Some(PathBuf::from("refs.bib")), bib_txt, keys.into_iter());
+    let msgs : Vec<_> = crossref_lints(&files, Some(&bib)).into_iter().map(|d| (d.message, d.line) ).collect();
+    assert_eq!(msgs, vec![
+        (String::from("Citation @lamport has no entry at refs.bib"), 0),
+        (String::from("Bibliography entry dijkstra is never cited"), 3)
+    ]);
+    let cites = citations(&files, &bib);
+    assert_eq!(cites, vec![Citation { key : String::from("knuth"), path : None, line : 0, column : 3 }]);
+}
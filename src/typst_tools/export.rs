@@ -0,0 +1,415 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use std::path::{Path, PathBuf};
+use std::fmt::Write;
+use typst::doc::{Document, Frame, FrameItem, TextItem};
+use typst::geom::{Color, Geometry, Paint, PathItem, Shape};
+use typst::image::{Image, ImageFormat, RasterFormat, VectorFormat};
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+use super::{Fonts, SystemWorld, html};
+
+/// Placeholder replaced by the file stem of the exported document at output file names.
+pub const NAME_PATTERN : &str = "{name}";
+
+/// Placeholder replaced by the (one-based) page number at output file names.
+pub const PAGE_PATTERN : &str = "{n}";
+
+/// Placeholder replaced by the number of pages of the document at output file names.
+pub const TOTAL_PATTERN : &str = "{total}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ExportFormat {
+
+    // A single PDF file with the selected pages.
+    Pdf,
+
+    // One PNG file per page, rasterized at the given resolution.
+    Png { dpi : f32 },
+
+    // One SVG file per page, with glyphs converted to paths.
+    Svg,
+
+    // A standalone HTML file for the whole document, with images and equations
+    // written to a folder next to it.
+    Html
+
+}
+
+impl ExportFormat {
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Pdf => "pdf",
+            ExportFormat::Png { .. } => "png",
+            ExportFormat::Svg => "svg",
+            ExportFormat::Html => "html"
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportRequest {
+
+    pub format : ExportFormat,
+
+    // Page range specification, such as "1-3,5,8-" (empty for all pages).
+    pub pages : String,
+
+    // Output path. Its file name might contain any of the placeholders {name}, {n} and {total}.
+    pub path : PathBuf,
+
+    // Stem of the exported document, replacing {name} at the output path.
+    pub name : String
+
+}
+
+/// Parses a comma-separated list of one-based pages or page ranges (e.g. "1-3,5,8-") into
+/// a sorted list of zero-based page indices. Ranges without start or end extend to the first
+/// or last page. An empty specification selects all pages.
+pub fn parse_page_range(spec : &str, n_pages : usize) -> Result<Vec<usize>, String> {
+    if spec.trim().is_empty() {
+        return Ok((0..n_pages).collect());
+    }
+    let parse_page = |s : &str| -> Result<usize, String> {
+        match s.trim().parse::<usize>() {
+            Ok(page) if page >= 1 && page <= n_pages => Ok(page),
+            Ok(page) => Err(format!("Page {} is out of the document (which has {} pages)", page, n_pages)),
+            Err(_) => Err(format!("Invalid page number: {}", s.trim()))
+        }
+    };
+    let mut pages = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        if let Some((start, end)) = part.split_once('-') {
+            let start = if start.trim().is_empty() { 1 } else { parse_page(start)? };
+            let end = if end.trim().is_empty() { n_pages } else { parse_page(end)? };
+            if start > end {
+                return Err(format!("Invalid page range: {}", part));
+            }
+            pages.extend((start - 1)..end);
+        } else {
+            pages.push(parse_page(part)? - 1);
+        }
+    }
+    pages.sort();
+    pages.dedup();
+    if pages.is_empty() {
+        return Err(String::from("No pages selected"));
+    }
+    Ok(pages)
+}
+
+/// Resolves the file name patterns of the output path for the given zero-based page. When many
+/// pages are written to separate files but the pattern has no page number, the page number is
+/// appended to the file stem, so that pages do not overwrite each other.
+pub fn output_path(req : &ExportRequest, page : usize, n_pages : usize, n_files : usize) -> PathBuf {
+    let fname = req.path.file_name()
+        .and_then(|f| f.to_str() )
+        .unwrap_or(NAME_PATTERN)
+        .to_string();
+    let ext = format!(".{}", req.format.extension());
+    let mut stem = fname.strip_suffix(&ext[..]).unwrap_or(&fname[..]).to_string();
+    if n_files > 1 && !stem.contains(PAGE_PATTERN) {
+        stem += "-";
+        stem += PAGE_PATTERN;
+    }
+    let stem = stem.replace(NAME_PATTERN, &req.name)
+        .replace(PAGE_PATTERN, &(page + 1).to_string())
+        .replace(TOTAL_PATTERN, &n_pages.to_string());
+    req.path.with_file_name(format!("{}{}", stem, ext))
+}
+
+/// Writes the pages of a compiled document selected by the request, returning the paths
+/// of all written files. The world is the one the document was compiled from (HTML is
+/// generated from its main source rather than from the laid-out pages).
+pub fn export(doc : &Document, world : &SystemWorld, fonts : &Fonts, req : &ExportRequest) -> Result<Vec<PathBuf>, String> {
+    let n_pages = doc.pages.len();
+    if req.format == ExportFormat::Html {
+        let path = output_path(req, 0, n_pages, 1);
+        let stem = path.file_stem().and_then(|s| s.to_str() ).unwrap_or("document").to_string();
+        let assets_url = format!("{}{}", stem, html::ASSETS_SUFFIX);
+        let assets_dir = path.with_file_name(&assets_url);
+        let html = html::to_html(world, doc, fonts, &req.name, &assets_dir, &assets_url)?;
+        write_file(&path, html.as_bytes())?;
+        return Ok(vec![path]);
+    }
+    let pages = parse_page_range(&req.pages, n_pages)?;
+    match req.format {
+        ExportFormat::Html => unreachable!(),
+        ExportFormat::Pdf => {
+            let mut selected = doc.clone();
+            selected.pages = pages.iter().map(|ix| doc.pages[*ix].clone() ).collect();
+            let path = output_path(req, pages[0], n_pages, 1);
+            write_file(&path, &typst::export::pdf(&selected))?;
+            Ok(vec![path])
+        },
+        ExportFormat::Png { dpi } => {
+            let mut paths = Vec::new();
+            for ix in pages.iter() {
+                let pixmap = typst::export::render(&doc.pages[*ix], dpi / 72.0, Color::WHITE);
+                let png = pixmap.encode_png().map_err(|e| format!("Unable to encode page {}: {}", ix + 1, e) )?;
+                let path = output_path(req, *ix, n_pages, pages.len());
+                write_file(&path, &png)?;
+                paths.push(path);
+            }
+            Ok(paths)
+        },
+        ExportFormat::Svg => {
+            let mut paths = Vec::new();
+            for ix in pages.iter() {
+                let path = output_path(req, *ix, n_pages, pages.len());
+                write_file(&path, svg_page(&doc.pages[*ix]).as_bytes())?;
+                paths.push(path);
+            }
+            Ok(paths)
+        }
+    }
+}
+
+fn write_file(path : &Path, data : &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("Unable to write {}: {}", path.display(), e) )
+}
+
+/// Writes a page as a standalone SVG document, in points.
+pub fn svg_page(frame : &Frame) -> String {
+    svg_frame(frame, true)
+}
+
+/// Writes a frame as a standalone SVG document, in points. Without background, only
+/// the frame content is painted (e.g. for equations placed over a web page).
+pub fn svg_frame(frame : &Frame, background : bool) -> String {
+    let (w, h) = (frame.width().to_pt(), frame.height().to_pt());
+    let mut svg = String::new();
+    write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}pt" height="{h}pt" viewBox="0 0 {w} {h}">"#
+    ).unwrap();
+    if background {
+        write!(svg, r#"<rect width="{w}" height="{h}" fill="white"/>"#).unwrap();
+    }
+    let mut n_clips = 0;
+    write_frame(&mut svg, frame, &mut n_clips);
+    svg += "</svg>\n";
+    svg
+}
+
+fn write_frame(svg : &mut String, frame : &Frame, n_clips : &mut usize) {
+    for (pos, item) in frame.items() {
+        let (x, y) = (pos.x.to_pt(), pos.y.to_pt());
+        match item {
+            FrameItem::Group(group) => {
+                let t = group.transform;
+                write!(
+                    svg,
+                    r#"<g transform="translate({x} {y}) matrix({} {} {} {} {} {})""#,
+                    t.sx.get(),
+                    t.ky.get(),
+                    t.kx.get(),
+                    t.sy.get(),
+                    t.tx.to_pt(),
+                    t.ty.to_pt()
+                ).unwrap();
+                if group.clips {
+                    *n_clips += 1;
+                    write!(
+                        svg,
+                        r#" clip-path="url(#clip{})"><clipPath id="clip{}"><rect width="{}" height="{}"/></clipPath>"#,
+                        n_clips,
+                        n_clips,
+                        group.frame.width().to_pt(),
+                        group.frame.height().to_pt()
+                    ).unwrap();
+                } else {
+                    *svg += ">";
+                }
+                write_frame(svg, &group.frame, n_clips);
+                *svg += "</g>";
+            },
+            FrameItem::Text(text) => write_text(svg, x, y, text),
+            FrameItem::Shape(shape, _) => write_shape(svg, x, y, shape),
+            FrameItem::Image(image, size, _) => write_image(svg, x, y, image, size.x.to_pt(), size.y.to_pt()),
+            FrameItem::Meta(..) => { }
+        }
+    }
+}
+
+// Returns the SVG color and opacity of a paint.
+fn svg_paint(paint : &Paint) -> (String, f64) {
+    let Paint::Solid(color) = paint;
+    let rgba = color.to_rgba();
+    (format!("rgb({},{},{})", rgba.r, rgba.g, rgba.b), rgba.a as f64 / 255.0)
+}
+
+// Builds the SVG path data of a glyph outline, in font units. Typst has no SVG backend
+// at the pinned revision, so pages are written here. The ttf-parser dependency must be
+// the version typst uses (0.18), since the builder is passed to the faces of typst fonts
+// (another version would not type check).
+struct OutlineBuilder(String);
+
+impl ttf_parser::OutlineBuilder for OutlineBuilder {
+
+    fn move_to(&mut self, x : f32, y : f32) {
+        write!(self.0, "M{} {}", x, y).unwrap();
+    }
+
+    fn line_to(&mut self, x : f32, y : f32) {
+        write!(self.0, "L{} {}", x, y).unwrap();
+    }
+
+    fn quad_to(&mut self, x1 : f32, y1 : f32, x : f32, y : f32) {
+        write!(self.0, "Q{} {} {} {}", x1, y1, x, y).unwrap();
+    }
+
+    fn curve_to(&mut self, x1 : f32, y1 : f32, x2 : f32, y2 : f32, x : f32, y : f32) {
+        write!(self.0, "C{} {} {} {} {} {}", x1, y1, x2, y2, x, y).unwrap();
+    }
+
+    fn close(&mut self) {
+        self.0 += "Z";
+    }
+
+}
+
+fn write_text(svg : &mut String, x : f64, y : f64, text : &TextItem) {
+    let (fill, opacity) = svg_paint(&text.fill);
+    let scale = text.size.to_pt() / text.font.units_per_em();
+    write!(svg, r#"<g fill="{fill}" fill-opacity="{opacity}">"#).unwrap();
+    let mut cursor = x;
+    for glyph in text.glyphs.iter() {
+        let offset = cursor + glyph.x_offset.at(text.size).to_pt();
+        let mut builder = OutlineBuilder(String::new());
+
+        // Glyphs without outlines (e.g. spaces or bitmap emoji) are skipped.
+        if text.font.ttf().outline_glyph(ttf_parser::GlyphId(glyph.id), &mut builder).is_some() {
+            write!(
+                svg,
+                r#"<path transform="translate({offset} {y}) scale({scale} {})" d="{}"/>"#,
+                -scale,
+                builder.0
+            ).unwrap();
+        }
+        cursor += glyph.x_advance.at(text.size).to_pt();
+    }
+    *svg += "</g>";
+}
+
+fn write_shape(svg : &mut String, x : f64, y : f64, shape : &Shape) {
+    let d = match &shape.geometry {
+        Geometry::Line(target) => format!("M0 0L{} {}", target.x.to_pt(), target.y.to_pt()),
+        Geometry::Rect(size) => {
+            let (w, h) = (size.x.to_pt(), size.y.to_pt());
+            format!("M0 0H{w}V{h}H0Z")
+        },
+        Geometry::Path(path) => {
+            let mut d = String::new();
+            for item in path.0.iter() {
+                match item {
+                    PathItem::MoveTo(p) => write!(d, "M{} {}", p.x.to_pt(), p.y.to_pt()).unwrap(),
+                    PathItem::LineTo(p) => write!(d, "L{} {}", p.x.to_pt(), p.y.to_pt()).unwrap(),
+                    PathItem::CubicTo(p1, p2, p) => write!(
+                        d,
+                        "C{} {} {} {} {} {}",
+                        p1.x.to_pt(),
+                        p1.y.to_pt(),
+                        p2.x.to_pt(),
+                        p2.y.to_pt(),
+                        p.x.to_pt(),
+                        p.y.to_pt()
+                    ).unwrap(),
+                    PathItem::ClosePath => d += "Z"
+                }
+            }
+            d
+        }
+    };
+    write!(svg, r#"<path transform="translate({x} {y})" d="{d}""#).unwrap();
+    match &shape.fill {
+        Some(paint) => {
+            let (fill, opacity) = svg_paint(paint);
+            write!(svg, r#" fill="{fill}" fill-opacity="{opacity}""#).unwrap();
+        },
+        None => *svg += r#" fill="none""#
+    }
+    if let Some(stroke) = &shape.stroke {
+        let (color, opacity) = svg_paint(&stroke.paint);
+        write!(
+            svg,
+            r#" stroke="{color}" stroke-opacity="{opacity}" stroke-width="{}""#,
+            stroke.thickness.to_pt()
+        ).unwrap();
+    }
+    *svg += "/>";
+}
+
+fn write_image(svg : &mut String, x : f64, y : f64, image : &Image, w : f64, h : f64) {
+    let mime = match image.format() {
+        ImageFormat::Raster(RasterFormat::Png) => "image/png",
+        ImageFormat::Raster(RasterFormat::Jpg) => "image/jpeg",
+        ImageFormat::Raster(RasterFormat::Gif) => "image/gif",
+        ImageFormat::Vector(VectorFormat::Svg) => "image/svg+xml"
+    };
+    let data = base64::engine::general_purpose::STANDARD.encode(&image.data()[..]);
+    write!(
+        svg,
+        r#"<image x="{x}" y="{y}" width="{w}" height="{h}" preserveAspectRatio="none" href="data:{mime};base64,{data}"/>"#
+    ).unwrap();
+}
+
+#[test]
+fn page_ranges_are_parsed() {
+    assert_eq!(parse_page_range("", 3).unwrap(), vec![0, 1, 2]);
+    assert_eq!(parse_page_range("1-2, 5, 4-", 6).unwrap(), vec![0, 1, 3, 4, 5]);
+    assert_eq!(parse_page_range("-2,2", 6).unwrap(), vec![0, 1]);
+    assert!(parse_page_range("3-1", 6).is_err());
+    assert!(parse_page_range("7", 6).is_err());
+}
+
+#[test]
+fn output_paths_follow_pattern() {
+    let req = ExportRequest {
+        format : ExportFormat::Png { dpi : 300.0 },
+        pages : String::new(),
+        path : PathBuf::from("/tmp/{name}-slide.png"),
+        name : String::from("talk")
+    };
+    assert_eq!(output_path(&req, 1, 10, 3), PathBuf::from("/tmp/talk-slide-2.png"));
+    assert_eq!(output_path(&req, 1, 10, 1), PathBuf::from("/tmp/talk-slide.png"));
+    let req = ExportRequest { path : PathBuf::from("/tmp/{n}-of-{total}"), ..req };
+    assert_eq!(output_path(&req, 0, 10, 3), PathBuf::from("/tmp/1-of-10.png"));
+}
+
+#[test]
+fn text_and_images_are_written_as_svg() {
+    let dir = std::env::temp_dir().join(format!("drafts-svg-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let png = base64::engine::general_purpose::STANDARD
+        .decode("iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==")
+        .unwrap();
+    std::fs::write(dir.join("dot.png"), &png).unwrap();
+    let main = dir.join("main.typ");
+    std::fs::write(&main, "#set page(width : 100pt, height : 100pt)\nHello\n#image(\"dot.png\", width : 20pt)\n").unwrap();
+
+    let fonts = Fonts::for_tests();
+    let mut world = SystemWorld::new(dir.clone(), fonts);
+    world.set_main(&main).unwrap();
+    let doc = super::compile(&mut world).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    let svg = svg_page(&doc.pages[0]);
+    assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="100pt" height="100pt" viewBox="0 0 100 100">"#));
+    assert!(svg.contains(r#"<rect width="100" height="100" fill="white"/>"#));
+
+    // One outline for each glyph of "Hello".
+    assert!(svg.contains(r#"<g fill="rgb(0,0,0)" fill-opacity="1">"#));
+    assert_eq!(svg.matches("<path transform=\"translate(").count(), 5);
+    assert!(svg.contains(r#"width="20" height="20" preserveAspectRatio="none" href="data:image/png;base64,iVBORw0KGgo"#));
+    assert!(svg.ends_with("</svg>\n"));
+}
@@ -0,0 +1,122 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use serde::{Serialize, Deserialize};
+use typst::font::FontInfo;
+
+/// Fonts found at a font file the last time it was indexed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedFile {
+
+    size : u64,
+
+    // Modification time, in nanoseconds since the Unix epoch.
+    modified : u128,
+
+    infos : Vec<FontInfo>
+
+}
+
+/// Information on the fonts of all font files found at the system font directories, so
+/// that files are only parsed again when their size or modification time change.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FontCache {
+
+    files : HashMap<PathBuf, IndexedFile>,
+
+    // Files looked up or inserted since the cache was loaded. The others are
+    // not saved, since they were removed from the font directories.
+    #[serde(skip)]
+    visited : HashSet<PathBuf>,
+
+    #[serde(skip)]
+    changed : bool
+
+}
+
+// Size and modification time of a file.
+fn file_stamp(path : &Path) -> Option<(u64, u128)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some((meta.len(), modified))
+}
+
+impl FontCache {
+
+    /// Loads the cache from the given path, or starts an empty cache when it
+    /// does not exist or cannot be read.
+    pub fn load(path : &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::warn!("Invalid font cache {}: {}", path.display(), e);
+                FontCache::default()
+            }),
+            Err(_) => FontCache::default()
+        }
+    }
+
+    /// Writes the cache when files were indexed or removed since it was loaded.
+    pub fn save(&mut self, path : &Path) {
+        let n_before = self.files.len();
+        let visited = &self.visited;
+        self.files.retain(|p, _| visited.contains(p) );
+        if !self.changed && self.files.len() == n_before {
+            return;
+        }
+        match serde_json::to_vec(&self) {
+            Ok(data) => {
+                if let Err(e) = std::fs::write(path, data) {
+                    log::warn!("Unable to write font cache {}: {}", path.display(), e);
+                }
+            },
+            Err(e) => log::warn!("Unable to serialize font cache: {}", e)
+        }
+    }
+
+    /// Returns the fonts of the file at the given path, unless it changed since it was indexed.
+    pub fn lookup(&mut self, path : &Path) -> Option<&[FontInfo]> {
+        let (size, modified) = file_stamp(path)?;
+        let file = self.files.get(path).filter(|f| f.size == size && f.modified == modified )?;
+        self.visited.insert(path.to_owned());
+        Some(&file.infos[..])
+    }
+
+    pub fn insert(&mut self, path : &Path, infos : Vec<FontInfo>) {
+        if let Some((size, modified)) = file_stamp(path) {
+            self.files.insert(path.to_owned(), IndexedFile { size, modified, infos });
+            self.visited.insert(path.to_owned());
+            self.changed = true;
+        }
+    }
+
+}
+
+#[test]
+fn changed_files_miss_and_unvisited_files_are_pruned() {
+    let dir = std::env::temp_dir().join(format!("drafts-font-cache-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, b, cache_path) = (dir.join("a.ttf"), dir.join("b.ttf"), dir.join("cache.json"));
+    std::fs::write(&a, b"font a").unwrap();
+    std::fs::write(&b, b"font b").unwrap();
+
+    let mut cache = FontCache::load(&cache_path);
+    cache.insert(&a, Vec::new());
+    cache.insert(&b, Vec::new());
+    cache.save(&cache_path);
+
+    // A file with another size is indexed again, and files not looked up are dropped on save.
+    std::fs::write(&a, b"font a, version 2").unwrap();
+    let mut cache = FontCache::load(&cache_path);
+    assert!(cache.lookup(&a).is_none());
+    assert!(cache.lookup(&b).is_some());
+    cache.save(&cache_path);
+
+    let cache = FontCache::load(&cache_path);
+    std::fs::remove_dir_all(&dir).ok();
+    assert_eq!(cache.files.keys().collect::<Vec<_>>(), vec![&b]);
+}
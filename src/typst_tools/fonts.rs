@@ -0,0 +1,182 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use typst::font::{FontBook, FontStyle, FontVariant};
+use typst::syntax::{ast, Source, SourceId, SyntaxNode};
+use typst::syntax::ast::{AstNode, Expr};
+use crate::diagnostic::{Diagnostic, Severity};
+use serde::{Serialize, Deserialize};
+
+/// A font family known to the typesetter, with all its variants.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FontFamily {
+    pub name : String,
+    pub variants : Vec<FontVariant>
+}
+
+/// Lists the families of the fonts at the book, sorted by name. Typst matches family
+/// names ignoring case, so families that differ only by case are merged.
+pub fn font_families(book : &FontBook) -> Vec<FontFamily> {
+    let mut families : BTreeMap<String, FontFamily> = BTreeMap::new();
+    let mut ix = 0;
+    while let Some(info) = book.info(ix) {
+        let family = families.entry(info.family.to_lowercase())
+            .or_insert_with(|| FontFamily { name : info.family.clone(), variants : Vec::new() });
+        if !family.variants.contains(&info.variant) {
+            family.variants.push(info.variant);
+        }
+        ix += 1;
+    }
+    let mut families : Vec<_> = families.into_values().collect();
+    for family in families.iter_mut() {
+        family.variants.sort_by_key(|v| (v.style as u8, v.weight.to_number(), v.stretch.to_ratio().get() as i64) );
+    }
+    families
+}
+
+/// The name typst accepts for a font weight at the "weight" argument of text.
+pub fn weight_name(weight : u16) -> &'static str {
+    match weight {
+        0..=149 => "thin",
+        150..=249 => "extralight",
+        250..=349 => "light",
+        350..=449 => "regular",
+        450..=549 => "medium",
+        550..=649 => "semibold",
+        650..=749 => "bold",
+        750..=849 => "extrabold",
+        _ => "black"
+    }
+}
+
+pub fn style_name(style : FontStyle) -> &'static str {
+    match style {
+        FontStyle::Normal => "normal",
+        FontStyle::Italic => "italic",
+        FontStyle::Oblique => "oblique"
+    }
+}
+
+/// Human-readable name of a variant (e.g. "Bold Italic").
+pub fn variant_label(variant : &FontVariant) -> String {
+    let weight = weight_name(variant.weight.to_number());
+    let mut label = String::new();
+    if weight != "regular" || variant.style == FontStyle::Normal {
+        let mut chars = weight.chars();
+        if let Some(first) = chars.next() {
+            label.extend(first.to_uppercase());
+            label.push_str(chars.as_str());
+        }
+    }
+    match variant.style {
+        FontStyle::Italic => label += " Italic",
+        FontStyle::Oblique => label += " Oblique",
+        FontStyle::Normal => { }
+    }
+    label.trim().to_string()
+}
+
+// Number of single-character edits turning a into b.
+fn edit_distance(a : &str, b : &str) -> usize {
+    let b : Vec<char> = b.chars().collect();
+    let mut prev : Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = subst.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+/// The known family whose name is closest to the given name, if any is reasonably close.
+pub fn closest_family<'a>(name : &str, families : &'a [String]) -> Option<&'a str> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return None;
+    }
+    families.iter()
+        .map(|f| {
+            let lower = f.to_lowercase();
+
+            // Families containing the name (e.g. "Linux Libertine" for "Libertine"), or
+            // contained in it (e.g. "Libertine" for "Linux Libertine Bold"), are preferred.
+            let dist = if lower.contains(&name) || name.contains(&lower) {
+                0
+            } else {
+                edit_distance(&name, &lower)
+            };
+            (dist, f)
+        })
+        .filter(|(dist, f)| *dist <= (f.chars().count() / 3).max(2) )
+        .min_by_key(|(dist, f)| (*dist, f.len()) )
+        .map(|(_, f)| &f[..])
+}
+
+// Font family names given to named "font" arguments below the node, with their spans.
+fn font_args(node : &SyntaxNode, out : &mut Vec<ast::Str>) {
+    if let Some(named) = node.cast::<ast::Named>() {
+        if named.name().get() == "font" {
+            match named.expr() {
+                Expr::Str(s) => out.push(s),
+                Expr::Array(arr) => {
+                    for item in arr.items() {
+                        if let ast::ArrayItem::Pos(Expr::Str(s)) = item {
+                            out.push(s);
+                        }
+                    }
+                },
+                _ => { }
+            }
+        }
+    }
+    for child in node.children() {
+        font_args(child, out);
+    }
+}
+
+/// Warns about fonts requested by the document (as in #set text(font : "Name")) that are not
+/// among the known families, which typst silently replaces by a fallback font.
+pub fn font_lints(path : Option<&Path>, txt : &str, families : &[String]) -> Vec<Diagnostic> {
+    let source = Source::new(SourceId::detached(), path.unwrap_or(Path::new("")), txt.to_string());
+    let mut args = Vec::new();
+    font_args(source.root(), &mut args);
+    let mut lints = Vec::new();
+    for arg in args {
+        let name = arg.get();
+        if families.iter().any(|f| f.eq_ignore_ascii_case(&name) ) {
+            continue;
+        }
+        let range = source.range(arg.span());
+        let mut diag = Diagnostic::at_source(
+            Severity::Warning,
+            format!("Unknown font family \"{}\" (a fallback font is used instead)", name),
+            &source,
+            path.map(|p| p.to_owned() ),
+            range
+        );
+        if let Some(closest) = closest_family(&name, families) {
+            diag = diag.with_hint(format!("Did you mean \"{}\"?", closest));
+        }
+        lints.push(diag);
+    }
+    lints
+}
+
+#[test]
+fn unknown_fonts_are_reported() {
+    let families = vec![String::from("Linux Libertine"), String::from("DejaVu Sans Mono")];
+    let txt = "#set text(font : \"Linux Libertin\")\n#text(font : (\"linux libertine\", \"Liberation Serif\"))[A]";
+    let lints = font_lints(None, txt, &families);
+    assert_eq!(lints.len(), 2);
+    assert_eq!(lints[0].hints, vec![String::from("Did you mean \"Linux Libertine\"?")]);
+    assert_eq!(lints[1].line, 1);
+    assert!(lints[1].hints.is_empty());
+    assert_eq!(closest_family(" ", &families), None);
+}
@@ -0,0 +1,812 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+/* Conversion of typst documents to HTML. The document structure (paragraphs, headings,
+lists, figures, tables, citations) is taken from the syntax tree of the main source and
+its includes, and the numbering of headings from the compiled document. Pieces that only
+make sense after evaluation (variables, function calls such as lorem(..), equations,
+citations and the bibliography) are compiled by typst in isolation, with the top-level
+bindings, imports, set rules and show rules of the document in effect: equations are
+written as SVG files and other pieces are replaced by their text. */
+
+use std::path::{Path, PathBuf};
+use std::fmt::Write;
+use typst::syntax::{Source, SourceId, Span};
+use typst::syntax::ast::{self, AstNode, Arg, Expr, Markup};
+use typst::doc::{Document, Frame, FrameItem};
+use typst::World;
+use super::{Fonts, SystemWorld};
+use super::export::svg_frame;
+use super::outline::Outline;
+use crate::tex::BibParser;
+
+/// Suffix of the folder created next to the HTML file to hold images and equations.
+pub const ASSETS_SUFFIX : &str = "_assets";
+
+// Functions whose content arguments are laid out as blocks.
+const BLOCK_FUNCS : [&str; 9] = ["block", "columns", "align", "pad", "stack", "grid", "rect", "place", "page"];
+
+// Upper limit to the nesting of included sources, which guards against cyclic includes.
+const MAX_INCLUDE_DEPTH : usize = 16;
+
+const STYLE : &str = r#"
+body { max-width: 48em; margin: 2em auto; padding: 0 1em; font-family: serif; line-height: 1.5; }
+figure { text-align: center; margin: 1.5em 0; }
+figure img { max-width: 100%; }
+figcaption { font-size: 0.9em; }
+table { border-collapse: collapse; margin: 0 auto; }
+td { border: 1px solid #999; padding: 0.25em 0.5em; }
+pre { background: #f4f4f4; padding: 0.5em; overflow-x: auto; }
+img.math { vertical-align: middle; }
+div.equation { text-align: center; margin: 1em 0; }
+section#bibliography p { padding-left: 2em; text-indent: -2em; }
+"#;
+
+fn load_bib_keys(path : &Path) -> Result<Vec<String>, String> {
+    let txt = std::fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e) )?;
+    let refs = BibParser::parse(&txt)?;
+    Ok(refs.as_ref().iter().map(|entry| entry.key().to_string() ).collect())
+}
+
+pub fn escape(s : &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '&' => out += "&amp;",
+            '"' => out += "&quot;",
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
+// Concatenates the text of a laid-out frame, separating runs that are not adjacent by spaces.
+fn frame_text(frame : &Frame, origin : (f64, f64), out : &mut String, last : &mut Option<(f64, f64)>) {
+    for (pos, item) in frame.items() {
+        let (x, y) = (origin.0 + pos.x.to_pt(), origin.1 + pos.y.to_pt());
+        match item {
+            FrameItem::Group(group) => frame_text(&group.frame, (x, y), out, last),
+            FrameItem::Text(text) => {
+                let width : f64 = text.glyphs.iter().map(|g| g.x_advance.at(text.size).to_pt() ).sum();
+                if let Some((last_y, last_x)) = *last {
+                    let apart = (last_y - y).abs() > 0.5 || x > last_x + 0.5;
+                    if apart && !out.ends_with(' ') && !text.text.starts_with(' ') {
+                        out.push(' ');
+                    }
+                }
+                out.push_str(&text.text);
+                *last = Some((y, x + width));
+            },
+            _ => { }
+        }
+    }
+}
+
+// Text of each line of a laid-out frame, from top to bottom. Runs of text at the same
+// baseline that are not adjacent are separated by spaces.
+fn frame_lines(frame : &Frame) -> Vec<String> {
+    fn runs(frame : &Frame, origin : (f64, f64), out : &mut Vec<(f64, f64, f64, String)>) {
+        for (pos, item) in frame.items() {
+            let (x, y) = (origin.0 + pos.x.to_pt(), origin.1 + pos.y.to_pt());
+            match item {
+                FrameItem::Group(group) => runs(&group.frame, (x, y), out),
+                FrameItem::Text(text) => {
+                    let width : f64 = text.glyphs.iter().map(|g| g.x_advance.at(text.size).to_pt() ).sum();
+                    out.push((y, x, x + width, text.text.to_string()));
+                },
+                _ => { }
+            }
+        }
+    }
+    let mut all = Vec::new();
+    runs(frame, (0.0, 0.0), &mut all);
+    all.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)) );
+    let mut lines : Vec<String> = Vec::new();
+    let mut last : Option<(f64, f64)> = None;
+    for (y, x, end, text) in all {
+        match last {
+            Some((last_y, last_end)) if (y - last_y).abs() <= 0.5 => {
+                let line = lines.last_mut().unwrap();
+                if x > last_end + 0.5 && !line.ends_with(' ') && !text.starts_with(' ') {
+                    line.push(' ');
+                }
+                line.push_str(&text);
+            },
+            _ => lines.push(text)
+        }
+        last = Some((y, end));
+    }
+    lines.into_iter().map(|l| l.trim().to_string() ).filter(|l| !l.is_empty() ).collect()
+}
+
+// Source of an expression, as written by the user.
+fn expr_text<'a>(source : &'a Source, expr : &Expr) -> &'a str {
+    span_text(source, expr.span())
+}
+
+fn span_text(source : &Source, span : Span) -> &str {
+    &source.text()[source.range(span)]
+}
+
+fn str_arg(expr : &Expr) -> Option<String> {
+    match expr {
+        Expr::Str(s) => Some(s.get().to_string()),
+        _ => None
+    }
+}
+
+fn pos_args(call : &ast::FuncCall) -> Vec<Expr> {
+    call.args().items().filter_map(|arg| match arg {
+        Arg::Pos(expr) => Some(expr),
+        _ => None
+    }).collect()
+}
+
+fn named_arg(call : &ast::FuncCall, name : &str) -> Option<Expr> {
+    call.args().items().find_map(|arg| match arg {
+        Arg::Named(named) if named.name().get() == name => Some(named.expr()),
+        _ => None
+    })
+}
+
+fn content_args(call : &ast::FuncCall) -> Vec<Markup> {
+    pos_args(call).iter().filter_map(|expr| match expr {
+        Expr::Content(block) => Some(block.body()),
+        _ => None
+    }).collect()
+}
+
+struct HtmlWriter<'a> {
+
+    fonts : &'a Fonts,
+
+    root : PathBuf,
+
+    // Directory of the main source, where relative imports of the preamble are resolved.
+    dir : PathBuf,
+
+    // Folder where images and equations are written, and how the HTML file refers to it.
+    assets_dir : PathBuf,
+
+    assets_url : String,
+
+    n_assets : usize,
+
+    // Top-level bindings, imports, set rules and show rules of the main source, in effect
+    // for evaluated pieces.
+    preamble : String,
+
+    // World used to evaluate expressions and equations, created on first use.
+    eval_world : Option<SystemWorld>,
+
+    // Where headings of the main source were laid out, with their numbering.
+    outline : Outline,
+
+    main : SourceId,
+
+    // Keys of the bibliography entries, to tell citations from references to labels.
+    bib_keys : Vec<String>,
+
+    // Call to the bibliography function (with paths relative to the project root), and
+    // whether the bibliography has a title.
+    bib_call : Option<(String, bool)>,
+
+    // Keys of each citation, in the order they appear (which might give their numbers).
+    cites : Vec<Vec<String>>,
+
+    n_figures : usize,
+
+    n_tables : usize,
+
+    n_listings : usize,
+
+    include_depth : usize
+
+}
+
+// Marks where the bibliography is placed, since it can only be written once all
+// citations are known.
+const BIBLIOGRAPHY_MARK : &str = "<!-- bibliography -->";
+
+impl<'a> HtmlWriter<'a> {
+
+    fn new_asset(&mut self, name : &str, data : &[u8]) -> Result<String, String> {
+        if !self.assets_dir.exists() {
+            std::fs::create_dir_all(&self.assets_dir)
+                .map_err(|e| format!("Unable to create {}: {}", self.assets_dir.display(), e) )?;
+        }
+        self.n_assets += 1;
+        let fname = format!("{}-{}", self.n_assets, name);
+        let path = self.assets_dir.join(&fname);
+        std::fs::write(&path, data).map_err(|e| format!("Unable to write {}: {}", path.display(), e) )?;
+        Ok(format!("{}/{}", self.assets_url, fname))
+    }
+
+    // Compiles a piece of typst markup in isolation, on a page that fits its content. Memoized
+    // results are evicted once for the whole export, not after each piece.
+    fn compile_piece(&mut self, markup : &str) -> Option<Frame> {
+        let text = format!(
+            "{}\n#set page(width: auto, height: auto, margin: 0pt)\n#set text(hyphenate: false)\n{}",
+            self.preamble,
+            markup
+        );
+        let (root, dir, fonts) = (&self.root, &self.dir, self.fonts);
+        let world = self.eval_world.get_or_insert_with(|| {
+            let mut world = SystemWorld::new(root.clone(), fonts.clone());
+            world.set_virtual_main(dir);
+            world
+        });
+        world.overlay_main(text);
+        match typst::compile(&*world) {
+            Ok(doc) => doc.pages.into_iter().next(),
+            Err(errs) => {
+                let diagnostics = super::world_diagnostics(world, &errs);
+                log::warn!("Unable to evaluate {} for HTML: {:?}", markup, crate::diagnostic::summarize(&diagnostics[..]));
+                None
+            }
+        }
+    }
+
+    // Replaces a code expression by the text it evaluates to.
+    fn eval(&mut self, source : &Source, expr : &Expr) -> String {
+        let code = expr_text(source, expr).trim_start_matches('#');
+        match self.compile_piece(&format!("#{}", code)) {
+            Some(frame) => {
+                let mut out = String::new();
+                frame_text(&frame, (0.0, 0.0), &mut out, &mut None);
+                escape(&out)
+            },
+            None => String::new()
+        }
+    }
+
+    fn equation(&mut self, source : &Source, eq : &ast::Equation) -> String {
+        let text = expr_text(source, &Expr::Equation(eq.clone()));
+        let img = self.compile_piece(text)
+            .and_then(|frame| self.new_asset("equation.svg", svg_frame(&frame, false).as_bytes()).ok() );
+        match img {
+            Some(url) => {
+                let img = format!(r#"<img class="math" src="{}" alt="{}">"#, url, escape(text));
+                if eq.block() {
+                    format!("<div class=\"equation\">{}</div>\n", img)
+                } else {
+                    img
+                }
+            },
+            None => format!("<code>{}</code>", escape(text))
+        }
+    }
+
+    fn image(&mut self, dir : &Path, call : &ast::FuncCall) -> String {
+        let Some(rel) = pos_args(call).first().and_then(str_arg) else {
+            return String::new();
+        };
+        let path = super::resolve_path(&self.root, dir, &rel);
+        let name = path.file_name().and_then(|n| n.to_str() ).unwrap_or("image").to_string();
+        let url = std::fs::read(&path).map_err(|e| e.to_string() )
+            .and_then(|data| self.new_asset(&name, &data) );
+        match url {
+            Ok(url) => format!(r#"<img src="{}" alt="{}">"#, url, escape(&rel)),
+            Err(e) => {
+                log::warn!("Unable to copy image {}: {}", path.display(), e);
+                String::new()
+            }
+        }
+    }
+
+    fn table(&mut self, source : &Source, dir : &Path, call : &ast::FuncCall) -> String {
+        let n_cols = match named_arg(call, "columns") {
+            Some(Expr::Int(n)) => n.get().max(1) as usize,
+            Some(Expr::Array(arr)) => arr.items().count().max(1),
+            _ => 1
+        };
+        let cells : Vec<String> = pos_args(call).iter().map(|cell| self.inline(source, dir, cell) ).collect();
+        let mut out = String::from("<table>\n");
+        for row in cells.chunks(n_cols) {
+            out += "<tr>";
+            for cell in row {
+                write!(out, "<td>{}</td>", cell.trim()).unwrap();
+            }
+            out += "</tr>\n";
+        }
+        out += "</table>\n";
+        out
+    }
+
+    fn figure(&mut self, source : &Source, dir : &Path, call : &ast::FuncCall) -> String {
+        let (body, kind) = match pos_args(call).first() {
+            Some(Expr::FuncCall(inner)) if callee_name(inner).as_deref() == Some("table") => {
+                self.n_tables += 1;
+                (self.table(source, dir, inner), format!("Table {}", self.n_tables))
+            },
+            Some(Expr::Raw(raw)) => {
+                self.n_listings += 1;
+                (raw_block(raw), format!("Listing {}", self.n_listings))
+            },
+            Some(Expr::Content(block)) => {
+                self.n_figures += 1;
+                (self.block_markup(source, dir, block.body()), format!("Figure {}", self.n_figures))
+            },
+            Some(expr) => {
+                self.n_figures += 1;
+                (self.inline(source, dir, expr), format!("Figure {}", self.n_figures))
+            },
+            None => return String::new()
+        };
+        let caption = match named_arg(call, "caption") {
+            Some(Expr::Content(block)) => format!("<figcaption>{}: {}</figcaption>", kind, self.inline_markup(source, dir, block.body()).trim()),
+            _ => String::new()
+        };
+        format!("<figure>\n{}{}</figure>\n", body, caption)
+    }
+
+    // Citations are written once all of them are known, since their text depends on the
+    // citations before them (e.g. for numbered styles).
+    fn citation(&mut self, keys : &[String]) -> String {
+        if let Some(missing) = keys.iter().find(|k| !self.bib_keys.contains(k) ) {
+            return format!("[{}?]", escape(missing));
+        }
+        if keys.is_empty() {
+            return String::new();
+        }
+        self.cites.push(keys.to_vec());
+        format!(r##"<a class="citation" href="#bibliography">{}</a>"##, cite_mark(self.cites.len() - 1))
+    }
+
+    // Keeps the bibliography call, with its paths made relative to the project root so
+    // that they are found from the directory of the main source.
+    fn bibliography_call(&mut self, source : &Source, dir : &Path, call : &ast::FuncCall) {
+        let mut args = Vec::new();
+        for arg in call.args().items() {
+            match &arg {
+                Arg::Pos(Expr::Str(s)) => {
+                    let path = super::resolve_path(&self.root, dir, &s.get());
+                    let rel = match path.strip_prefix(&self.root) {
+                        Ok(rel) => format!("/{}", rel.display()),
+                        Err(_) => path.display().to_string()
+                    };
+                    args.push(format!("{:?}", rel));
+                },
+                other => args.push(span_text(source, other.as_untyped().span()).to_string())
+            }
+        }
+        let titled = !matches!(named_arg(call, "title"), Some(Expr::None(_)));
+        self.bib_call = Some((format!("#bibliography({})", args.join(", ")), titled));
+    }
+
+    // Text of each citation and of each line of the bibliography (starting with its title,
+    // if any), as typst lays them out. Each citation is placed at its own line, followed
+    // by the bibliography, at a page wide enough for no line to break.
+    fn citations(&mut self) -> Option<(Vec<String>, Vec<String>)> {
+        let (call, _) = self.bib_call.clone()?;
+        let mut markup = String::new();
+        for keys in self.cites.iter() {
+            let args : Vec<String> = keys.iter().map(|k| format!("{:?}", k) ).collect();
+            writeln!(markup, "#cite({})\n", args.join(", ")).unwrap();
+        }
+        markup += &call;
+        let frame = self.compile_piece(&markup)?;
+        let mut lines = frame_lines(&frame);
+        if lines.len() < self.cites.len() {
+            log::warn!("Unable to find the text of citations for HTML");
+            return None;
+        }
+        let bib = lines.split_off(self.cites.len());
+        Some((lines, bib))
+    }
+
+    fn heading_numbering(&self, source : &Source, span : Span) -> Option<String> {
+        if source.id() != self.main {
+            return None;
+        }
+        let line = source.byte_to_line(source.range(span).start)?;
+        self.outline.heading_at(line)?.numbering.clone().filter(|n| !n.is_empty() )
+    }
+
+    fn include(&mut self, dir : &Path, expr : &Expr) -> String {
+        let Some(rel) = str_arg(expr) else {
+            return String::new();
+        };
+        if self.include_depth >= MAX_INCLUDE_DEPTH {
+            return String::new();
+        }
+        let path = super::resolve_path(&self.root, dir, &rel);
+        let txt = match std::fs::read_to_string(&path) {
+            Ok(txt) => txt,
+            Err(e) => {
+                log::warn!("Unable to read included file {}: {}", path.display(), e);
+                return String::new();
+            }
+        };
+        let source = Source::new(SourceId::detached(), &path, txt);
+        let inc_dir = path.parent().map(|p| p.to_owned() ).unwrap_or(dir.to_owned());
+        let Ok(markup) = source.ast() else {
+            return String::new();
+        };
+        self.include_depth += 1;
+        let out = self.block_markup(&source, &inc_dir, markup);
+        self.include_depth -= 1;
+        out
+    }
+
+    // Writes a function call, returning whether its output is a block (which ends the
+    // current paragraph).
+    fn call(&mut self, source : &Source, dir : &Path, call : &ast::FuncCall) -> (String, bool) {
+        let name = match callee_name(call) {
+            Some(name) => name,
+            None => return (self.eval(source, &Expr::FuncCall(call.clone())), false)
+        };
+        let inline_tag = match &name[..] {
+            "strong" => Some("strong"),
+            "emph" => Some("em"),
+            "underline" => Some("u"),
+            "strike" => Some("s"),
+            "sub" => Some("sub"),
+            "super" => Some("sup"),
+            _ => None
+        };
+        if let Some(tag) = inline_tag {
+            let body : String = content_args(call).into_iter().map(|m| self.inline_markup(source, dir, m) ).collect();
+            return (format!("<{tag}>{body}</{tag}>"), false);
+        }
+        match &name[..] {
+            "image" => (self.image(dir, call), false),
+            "figure" => (self.figure(source, dir, call), true),
+            "table" => (self.table(source, dir, call), true),
+            "link" => {
+                let url = pos_args(call).first().and_then(str_arg).unwrap_or_default();
+                let body : String = content_args(call).into_iter().map(|m| self.inline_markup(source, dir, m) ).collect();
+                let body = if body.is_empty() { escape(&url) } else { body };
+                (format!(r#"<a href="{}">{}</a>"#, escape(&url), body), false)
+            },
+            "cite" => {
+                let keys : Vec<String> = pos_args(call).iter().filter_map(str_arg).collect();
+                (self.citation(&keys), false)
+            },
+            "bibliography" => {
+                self.bibliography_call(source, dir, call);
+                (String::from(BIBLIOGRAPHY_MARK), true)
+            },
+            "heading" => {
+                let level = match named_arg(call, "level") {
+                    Some(Expr::Int(n)) => n.get().clamp(1, 6),
+                    _ => 1
+                };
+                let body : String = content_args(call).into_iter().map(|m| self.inline_markup(source, dir, m) ).collect();
+                (format!("<h{level}>{}</h{level}>\n", body.trim()), true)
+            },
+            "raw" => {
+                let text = pos_args(call).first().and_then(str_arg).unwrap_or_default();
+                (format!("<code>{}</code>", escape(&text)), false)
+            },
+            "linebreak" => (String::from("<br>"), false),
+            "parbreak" | "pagebreak" | "colbreak" | "h" | "v" => (String::new(), false),
+            _ => {
+                let bodies = content_args(call);
+                if bodies.is_empty() {
+                    (self.eval(source, &Expr::FuncCall(call.clone())), false)
+                } else if BLOCK_FUNCS.contains(&&name[..]) {
+                    let inner : String = bodies.into_iter().map(|m| self.block_markup(source, dir, m) ).collect();
+                    (format!("<div>\n{}</div>\n", inner), true)
+                } else {
+                    let inner : String = bodies.into_iter().map(|m| self.inline_markup(source, dir, m) ).collect();
+                    (format!("<span>{}</span>", inner), false)
+                }
+            }
+        }
+    }
+
+    fn reference(&mut self, target : &str) -> String {
+        if self.bib_keys.iter().any(|key| key == target ) {
+            self.citation(&[target.to_string()])
+        } else {
+            format!(r##"<a href="#{}">{}</a>"##, escape(target), escape(target))
+        }
+    }
+
+    fn inline_markup(&mut self, source : &Source, dir : &Path, markup : Markup) -> String {
+        let mut out = String::new();
+        for expr in markup.exprs() {
+            out += &self.inline(source, dir, &expr);
+        }
+        out
+    }
+
+    fn inline(&mut self, source : &Source, dir : &Path, expr : &Expr) -> String {
+        match expr {
+            Expr::Text(txt) => escape(txt.get()),
+            Expr::Space(_) | Expr::Parbreak(_) => String::from(" "),
+            Expr::Linebreak(_) => String::from("<br>"),
+            Expr::Escape(c) => escape(&c.get().to_string()),
+            Expr::Shorthand(c) => escape(&c.get().to_string()),
+            Expr::SmartQuote(q) => if q.double() { String::from("&quot;") } else { String::from("'") },
+            Expr::Strong(s) => format!("<strong>{}</strong>", self.inline_markup(source, dir, s.body())),
+            Expr::Emph(e) => format!("<em>{}</em>", self.inline_markup(source, dir, e.body())),
+            Expr::Raw(raw) => if raw.block() {
+                raw_block(raw)
+            } else {
+                format!("<code>{}</code>", escape(&raw.text()))
+            },
+            Expr::Link(link) => format!(r#"<a href="{}">{}</a>"#, escape(link.get()), escape(link.get())),
+            Expr::Label(label) => format!(r#"<span id="{}"></span>"#, escape(label.get())),
+            Expr::Ref(r) => self.reference(r.target()),
+            Expr::Equation(eq) => self.equation(source, eq),
+            Expr::Content(block) => self.inline_markup(source, dir, block.body()),
+            Expr::Str(s) => escape(&s.get()),
+            Expr::FuncCall(call) => self.call(source, dir, call).0,
+            Expr::Heading(head) => self.inline_markup(source, dir, head.body()),
+            Expr::Ident(_) | Expr::FieldAccess(_) | Expr::MethodCall(_) | Expr::Conditional(_) | Expr::For(_) => {
+                self.eval(source, expr)
+            },
+            _ => String::new()
+        }
+    }
+
+    fn block_markup(&mut self, source : &Source, dir : &Path, markup : Markup) -> String {
+        let mut out = String::new();
+        let mut par = String::new();
+
+        // Tag of the list being written (ul, ol or dl), if any.
+        let mut list : Option<&'static str> = None;
+
+        fn flush(out : &mut String, par : &mut String) {
+            let txt = par.trim();
+            if !txt.is_empty() {
+                write!(out, "<p>{}</p>\n", txt).unwrap();
+            }
+            par.clear();
+        }
+
+        fn close_list(out : &mut String, list : &mut Option<&'static str>) {
+            if let Some(tag) = list.take() {
+                write!(out, "</{}>\n", tag).unwrap();
+            }
+        }
+
+        fn open_list(out : &mut String, list : &mut Option<&'static str>, tag : &'static str) {
+            if *list != Some(tag) {
+                close_list(out, list);
+                write!(out, "<{}>\n", tag).unwrap();
+                *list = Some(tag);
+            }
+        }
+
+        for expr in markup.exprs() {
+            match &expr {
+                Expr::Parbreak(_) => flush(&mut out, &mut par),
+                Expr::Space(_) => par.push(' '),
+                Expr::Heading(head) => {
+                    flush(&mut out, &mut par);
+                    close_list(&mut out, &mut list);
+                    let level = head.level().get().min(6);
+                    let mut body = self.inline_markup(source, dir, head.body()).trim().to_string();
+                    if let Some(numbering) = self.heading_numbering(source, head.span()) {
+                        body = format!("{} {}", escape(&numbering), body);
+                    }
+                    write!(out, "<h{level}>{}</h{level}>\n", body).unwrap();
+                },
+                Expr::List(item) => {
+                    flush(&mut out, &mut par);
+                    open_list(&mut out, &mut list, "ul");
+                    let body = self.list_body(source, dir, item.body());
+                    write!(out, "<li>{}</li>\n", body).unwrap();
+                },
+                Expr::Enum(item) => {
+                    flush(&mut out, &mut par);
+                    open_list(&mut out, &mut list, "ol");
+                    let body = self.list_body(source, dir, item.body());
+                    write!(out, "<li>{}</li>\n", body).unwrap();
+                },
+                Expr::Term(item) => {
+                    flush(&mut out, &mut par);
+                    open_list(&mut out, &mut list, "dl");
+                    let term = self.inline_markup(source, dir, item.term());
+                    let desc = self.list_body(source, dir, item.description());
+                    write!(out, "<dt>{}</dt><dd>{}</dd>\n", term.trim(), desc).unwrap();
+                },
+                Expr::Raw(raw) if raw.block() => {
+                    flush(&mut out, &mut par);
+                    close_list(&mut out, &mut list);
+                    out += &raw_block(raw);
+                },
+                Expr::Equation(eq) if eq.block() => {
+                    flush(&mut out, &mut par);
+                    close_list(&mut out, &mut list);
+                    out += &self.equation(source, eq);
+                },
+                Expr::Include(inc) => {
+                    flush(&mut out, &mut par);
+                    close_list(&mut out, &mut list);
+                    out += &self.include(dir, &inc.source());
+                },
+                Expr::FuncCall(call) => {
+                    let (html, is_block) = self.call(source, dir, call);
+                    if is_block {
+                        flush(&mut out, &mut par);
+                        close_list(&mut out, &mut list);
+                        out += &html;
+                    } else {
+                        close_list(&mut out, &mut list);
+                        par += &html;
+                    }
+                },
+                Expr::Let(_) | Expr::Set(_) | Expr::Show(_) | Expr::Import(_) | Expr::Code(_) => { },
+                other => {
+                    close_list(&mut out, &mut list);
+                    par += &self.inline(source, dir, other);
+                }
+            }
+        }
+        flush(&mut out, &mut par);
+        close_list(&mut out, &mut list);
+        out
+    }
+
+    // Items of tight lists are written without paragraphs.
+    fn list_body(&mut self, source : &Source, dir : &Path, markup : Markup) -> String {
+        let body = self.block_markup(source, dir, markup);
+        let body = body.trim();
+        match body.strip_prefix("<p>").and_then(|b| b.strip_suffix("</p>") ) {
+            Some(inner) if !inner.contains("<p>") => inner.to_string(),
+            _ => body.to_string()
+        }
+    }
+
+}
+
+fn callee_name(call : &ast::FuncCall) -> Option<String> {
+    match call.callee() {
+        Expr::Ident(id) => Some(id.get().to_string()),
+        _ => None
+    }
+}
+
+fn cite_mark(ix : usize) -> String {
+    format!("<!-- cite {} -->", ix)
+}
+
+fn raw_block(raw : &ast::Raw) -> String {
+    match raw.lang() {
+        Some(lang) => format!("<pre><code class=\"language-{}\">{}</code></pre>\n", escape(lang), escape(&raw.text())),
+        None => format!("<pre><code>{}</code></pre>\n", escape(&raw.text()))
+    }
+}
+
+/// Converts the main source of a world into a standalone HTML document. Images and
+/// equations are written to the given assets folder, referred to by the HTML as
+/// assets_url. The document must be the result of compiling the world as it is.
+pub fn to_html(
+    world : &SystemWorld,
+    doc : &Document,
+    fonts : &Fonts,
+    title : &str,
+    assets_dir : &Path,
+    assets_url : &str
+) -> Result<String, String> {
+    let source = world.main();
+    let dir = world.source_path(source)
+        .and_then(|p| p.parent().map(|p| p.to_owned() ) )
+        .unwrap_or(world.root().to_owned());
+    let markup = source.ast().map_err(|_| String::from("The document has syntax errors") )?;
+
+    // Show rules without a selector transform the whole document (e.g. templates that
+    // add a title page), so they would also apply to each evaluated piece.
+    let mut preamble = String::new();
+    let mut bib_keys = Vec::new();
+    for expr in markup.exprs() {
+        match &expr {
+            Expr::Let(_) | Expr::Import(_) | Expr::Set(_) => {
+                writeln!(preamble, "#{}", expr_text(source, &expr)).unwrap();
+            },
+            Expr::Show(show) if show.selector().is_some() => {
+                writeln!(preamble, "#{}", expr_text(source, &expr)).unwrap();
+            },
+            Expr::FuncCall(call) if callee_name(call).as_deref() == Some("bibliography") => {
+                if let Some(rel) = pos_args(call).first().and_then(str_arg) {
+                    bib_keys = load_bib_keys(&super::resolve_path(world.root(), &dir, &rel))?;
+                }
+            },
+            _ => { }
+        }
+    }
+
+    let mut writer = HtmlWriter {
+        fonts,
+        root : world.root().to_owned(),
+        dir : dir.clone(),
+        assets_dir : assets_dir.to_owned(),
+        assets_url : assets_url.to_string(),
+        n_assets : 0,
+        preamble,
+        eval_world : None,
+        outline : super::outline::outline(doc, source),
+        main : source.id(),
+        bib_keys,
+        bib_call : None,
+        cites : Vec::new(),
+        n_figures : 0,
+        n_tables : 0,
+        n_listings : 0,
+        include_depth : 0
+    };
+    let mut body = writer.block_markup(source, &dir, markup);
+
+    let (cite_texts, bib_lines) = match writer.citations() {
+        Some((cites, bib)) => (cites, bib),
+        None => (writer.cites.iter().map(|keys| format!("[{}]", keys.join(", ")) ).collect(), Vec::new())
+    };
+    for (ix, text) in cite_texts.iter().enumerate() {
+        body = body.replace(&cite_mark(ix), &escape(text));
+    }
+    let mut bibliography = String::from("<section id=\"bibliography\">\n");
+    let titled = writer.bib_call.as_ref().map(|(_, titled)| *titled ).unwrap_or(false);
+    let mut bib_lines = bib_lines.iter();
+    if titled {
+        if let Some(title) = bib_lines.next() {
+            writeln!(bibliography, "<h2>{}</h2>", escape(title)).unwrap();
+        }
+    }
+    for entry in bib_lines {
+        writeln!(bibliography, "<p>{}</p>", escape(entry)).unwrap();
+    }
+    bibliography += "</section>\n";
+    let body = body.replace(BIBLIOGRAPHY_MARK, &bibliography);
+    if writer.eval_world.is_some() {
+        comemo::evict(30);
+    }
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+        <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body
+    ))
+}
+
+#[test]
+fn html_is_escaped() {
+    assert_eq!(escape("<a href=\"x\">&</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;");
+}
+
+#[test]
+fn document_structure_is_converted() {
+    let dir = std::env::temp_dir().join(format!("drafts-html-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("refs.bib"),
+        "@article{knuth,\n  author = {Knuth, Donald},\n  title = {Literate Programming},\n  journal = {The Computer Journal},\n  year = {1984}\n}\n"
+    ).unwrap();
+    let main = dir.join("main.typ");
+    std::fs::write(&main, concat!(
+        "#set heading(numbering : \"1.\")\n= Intro\n\nSome _emphasis_ here, see @knuth for more.\n\n- First\n- Second\n\n",
+        "#figure([A cat], caption : [A *black* cat]) <cat>\n\n#bibliography(\"refs.bib\", title : [Works])\n"
+    )).unwrap();
+
+    let fonts = super::Fonts::for_tests();
+    let mut world = SystemWorld::new(dir.clone(), fonts.clone());
+    world.set_main(&main).unwrap();
+    let doc = super::compile(&mut world).unwrap();
+    let html = to_html(&world, &doc, &fonts, "Test", &dir.join("main_assets"), "main_assets").unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    for expected in [
+        "<title>Test</title>",
+        "<h1>1. Intro</h1>",
+        "<em>emphasis</em>",
+        r##"<a class="citation" href="#bibliography">[1]</a>"##,
+        "<ul>\n<li>First</li>\n<li>Second</li>\n</ul>",
+        "<figure>\n<p>A cat</p>\n<figcaption>Figure 1: A <strong>black</strong> cat</figcaption></figure>",
+        r#"<span id="cat"></span>"#,
+        "<h2>Works</h2>",
+        "Literate Programming"
+    ] {
+        assert!(html.contains(expected), "Missing {:?} at:\n{}", expected, html);
+    }
+    assert!(!html.contains("<!-- cite"));
+}
@@ -31,49 +31,178 @@ use same_file::{is_same_file, Handle};
 use typst::World;
 use siphasher::sip128::{Hasher128, SipHasher};
 use std::error::Error;
-use codespan_reporting::diagnostic::{Diagnostic, Label};
+use crate::diagnostic::{Diagnostic, Severity};
 use codespan_reporting::term::{self, termcolor};
 use std::cell::RefMut;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::fs::File;
 use std::io::Read;
 use elsa::FrozenVec;
-use typst::syntax::{ast::{Expr, Markup, Arg, AstNode}};
-use crate::tex::{Section, Subsection, Item};
-use typst::diag::{FileError, FileResult, SourceError, StrResult};
+use typst::syntax::{ast::{self, Expr, Markup, Arg, AstNode}, SyntaxKind, SyntaxNode};
+use typst::diag::{ErrorPos, FileError, FileResult, SourceError, StrResult};
 use std::rc::Rc;
 use gtk4::gio;
 use std::sync::Arc;
+use std::time::SystemTime;
+use typst::doc::Document;
 
-pub fn compile(path : &Path, fonts : Fonts) -> Result<Vec<u8>, Vec<(usize, String)>> {
-    let parent_path = path.parent()
-        // .ok_or(vec![String::from("Missing parent directory"))?
-        .unwrap()
-        .to_owned();
-    let mut world = SystemWorld::new(parent_path, fonts);
+pub mod export;
 
-    world.reset();
-    world.main = world.resolve(&path).unwrap();
-        //.map_err(|err| err.to_string())?;
+pub mod html;
 
-    match typst::compile(&world) {
+pub mod font_cache;
+
+pub mod fonts;
+
+pub mod preview;
+
+pub mod outline;
+
+pub mod stats;
+
+pub mod crossref;
+
+use font_cache::FontCache;
+
+/// Compiles the main source of a long-lived world into a laid-out document. Sources and files that
+/// changed on disk since the previous call are updated in place (keeping their ids), so
+/// typst re-uses its parsed sources and memoized layouts for everything that did not change.
+pub fn compile(world : &mut SystemWorld) -> Result<Document, Vec<Diagnostic>> {
+    world.refresh();
+    world.reset_accessed();
+
+    let start = std::time::Instant::now();
+    let res = typst::compile(&*world);
+    log::info!("Document compiled in {} ms", start.elapsed().as_millis());
+
+    // Drop memoized results not used in the last compilations, as the typst CLI does in watch mode.
+    comemo::evict(30);
+
+    match res {
         Ok(doc) => {
-            Ok(typst::export::pdf(&doc))
+            Ok(doc)
         },
         Err(errs) => {
-            let mut out_errs = Vec::new();
-            for e in errs.iter() {
-                if let Some(src) = world.sources.iter().find(|s| s.id() == e.span.source() ) {
-                    let line = src.byte_to_line(src.range(e.span).start).unwrap_or(0);
-                    let msg = e.message.to_string();
-                    out_errs.push((line, msg));
-                }
+            Err(world_diagnostics(world, &errs))
+        }
+    }
+
+}
+
+/// Watches the root directory of a world (where all files a document reads must be)
+/// for changes. Events are forwarded as they come; use SystemWorld::relevant to decide
+/// whether they require a new compilation.
+pub struct WorldWatcher {
+    root : PathBuf,
+    _watcher : RecommendedWatcher
+}
+
+impl WorldWatcher {
+
+    pub fn new<F>(root : &Path, f : F) -> Result<Self, String>
+    where
+        F : Fn(notify::Event) + Send + 'static
+    {
+        let mut watcher = notify::recommended_watcher(move |res : notify::Result<notify::Event>| {
+            match res {
+                Ok(event) => f(event),
+                Err(e) => log::warn!("File watch error: {}", e)
             }
-            Err(out_errs)
+        }).map_err(|e| e.to_string() )?;
+        watcher.watch(root, RecursiveMode::Recursive).map_err(|e| format!("Unable to watch {}: {}", root.display(), e) )?;
+        Ok(Self { root : root.to_owned(), _watcher : watcher })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+}
+
+/// Files marking a directory as the root of a project. Only an explicit marker is used
+/// (and not, say, a .git folder), so that documents inside a larger repository are not
+/// rooted at the repository top.
+pub const ROOT_MARKERS : [&str; 1] = [".drafts-root"];
+
+/// Directory where absolute paths of a document (such as "/lib.typ") are resolved, and outside
+/// of which it cannot read files. This is the root configured by the user when it contains the
+/// document, or else the closest ancestor with one of the ROOT_MARKERS, or else the directory
+/// of the document itself.
+pub fn project_root(file : &Path, configured : Option<&Path>) -> Option<PathBuf> {
+    let dir = file.parent()?;
+    if let Some(configured) = configured {
+        if dir.starts_with(configured) {
+            return Some(configured.to_owned());
+        } else {
+            log::warn!("Project root {} does not contain {}", configured.display(), file.display());
         }
     }
+    let marked = dir.ancestors()
+        .find(|anc| ROOT_MARKERS.iter().any(|marker| anc.join(marker).exists() ) );
+    Some(marked.unwrap_or(dir).to_owned())
+}
 
+/// Resolves a path written at a document in the given directory, as typst does: paths
+/// starting with "/" are relative to the project root, and all others to the directory.
+pub fn resolve_path(root : &Path, dir : &Path, rel : &str) -> PathBuf {
+    match rel.strip_prefix('/') {
+        Some(from_root) => root.join(from_root),
+        None => dir.join(rel)
+    }
+}
+
+/// Path used to refer to a file from a document: relative to the document directory when the
+/// file is there, relative to the project root (starting with "/") when it is elsewhere in
+/// the project, and the full path otherwise (which typst refuses to read).
+pub fn document_path(path : &Path, file : Option<&Path>, root : Option<&Path>) -> String {
+    if let Some(stripped) = file.and_then(|f| f.parent() ).and_then(|dir| path.strip_prefix(dir).ok() ) {
+        stripped.display().to_string()
+    } else if let Some(stripped) = root.and_then(|r| path.strip_prefix(r).ok() ) {
+        format!("/{}", stripped.display())
+    } else {
+        path.display().to_string()
+    }
+}
+
+/// Byte range of an error at its source, taking into account whether the error
+/// refers to the whole span or only to its start or end.
+fn error_range(source : &Source, e : &SourceError) -> std::ops::Range<usize> {
+    let full = source.range(e.span);
+    match e.pos {
+        ErrorPos::Full => full,
+        ErrorPos::Start => full.start..full.start,
+        ErrorPos::End => full.end..full.end
+    }
+}
+
+/// Converts compilation errors into diagnostics located at the source (main, imported
+/// or included) where each error happened. Errors with spans that cannot be resolved to
+/// any source are kept, just without a position.
+pub fn world_diagnostics(world : &SystemWorld, errs : &[SourceError]) -> Vec<Diagnostic> {
+    let mut out = Vec::with_capacity(errs.len());
+    for e in errs {
+        let mut diag = match world.find_source(e.span.source()) {
+            Some(source) => {
+                let range = error_range(source, e);
+                Diagnostic::at_source(Severity::Error, e.message.to_string(), source, world.source_path(source), range)
+            },
+            None => Diagnostic::error(e.message.to_string())
+        };
+        for point in e.trace.iter() {
+            let hint = match world.find_source(point.span.source()) {
+                Some(source) => {
+                    let line = source.byte_to_line(source.range(point.span).start).unwrap_or(0);
+                    let name = source.path().file_name().and_then(|n| n.to_str() ).unwrap_or("");
+                    format!("{} ({} line {})", point.v, name, line + 1)
+                },
+                None => point.v.to_string()
+            };
+            diag = diag.with_hint(hint);
+        }
+        out.push(diag);
+    }
+    out
 }
 
 fn first_text(mark : &Markup) -> String {
@@ -88,164 +217,325 @@ fn first_text(mark : &Markup) -> String {
     String::new()
 }
 
-pub fn push_to_curr_items(curr_subsec : &mut Option<(Subsection, usize)>, curr_sec : &mut Option<(Section, usize)>, its : &mut Vec<Item>, it : Item) {
-    if let Some(sub) = curr_subsec.as_mut() {
-        sub.0.items.push(it);
-    } else if let Some(sec) = curr_sec.as_mut() {
-        sec.0.items.push(it);
-    } else {
-        its.push(it);
-    }
-}
-
-fn process_errors(source : &Source, errs : Vec<SourceError>) -> Vec<(usize, String)> {
+fn process_errors(source : &Source, path : Option<&Path>, errs : Vec<SourceError>) -> Vec<Diagnostic> {
     let mut out = Vec::with_capacity(errs.len());
     for e in errs {
-        let line = source.byte_to_line(source.range(e.span).start).unwrap_or(0);
+        let range = error_range(source, &e);
         let msg = e.message.to_string();
-        out.push((line, msg));
+        out.push(Diagnostic::at_source(Severity::Error, msg, source, path.map(|p| p.to_owned() ), range));
     }
     out
 }
 
-pub fn parse_doc(path : &Path, txt : String) -> Result<crate::tex::Document, Vec<(usize, String)>> {
-
-    use crate::tex::*;
-
-    let mut items = Vec::new();
-
-    let mut curr_sec : Option<(Section, usize)> = None;
-    let mut curr_subsec : Option<(Subsection, usize)> = None;
-    let mut curr_sec_index = 1;
-    let mut curr_subsec_local_index = 1;
-    let mut curr_subsec_global_index = 1;
-    let source = Source::new(SourceId::detached(), path, txt);
-    let mut eq_ix = 1;
-    let mut tbl_ix = 1;
-    let mut img_ix = 1;
-    let mut code_ix = 1;
-    let ast = source.ast().map_err(|e| process_errors(&source, *e) )?;
-    let mut line;
-    for expr in ast.exprs() {
-        line = source.byte_to_line(source.range(expr.span()).start).unwrap_or(0);
-        match expr {
-            Expr::Heading(head) => {
-                match head.level().get() {
-                    1 => {
-                        curr_subsec_local_index = 1;
-
-                        if let (Some(sub), Some(sec)) = (curr_subsec.take(), curr_sec.as_mut()) {
-                            sec.0.items.push(Item::Subsection(sub.0, sub.1));
-                        }
+// Text of a caption given as a string or as content, without markup.
+fn caption_text(expr : &Expr) -> Option<String> {
+    let txt = match expr {
+        Expr::Str(s) => s.get().to_string(),
+        Expr::Content(block) => {
+            let mut txt = String::new();
+            stats::collect_prose(block.body().as_untyped(), &mut txt);
+            txt
+        },
+        _ => String::new()
+    };
+    let txt = txt.split_whitespace().collect::<Vec<_>>().join(" ");
+    if txt.is_empty() {
+        None
+    } else {
+        Some(txt)
+    }
+}
 
-                        if let Some(prev) = curr_sec.take() {
-                            items.push(Item::Section(prev.0, prev.1));
-                            curr_sec_index += 1;
-                        }
-                        curr_sec = Some((Section {
-                            name : first_text(&head.body()),
-                            index : curr_sec_index,
-                            items : Vec::new()
-                        }, line));
-                    },
-                    2 => {
-                        if let Some(prev) = curr_subsec.take() {
-                            if let Some(sec) = curr_sec.as_mut() {
-                                sec.0.items.push(Item::Subsection(prev.0, prev.1));
-                                curr_subsec_local_index += 1;
-                                curr_subsec_global_index += 1;
-                            }
-                        }
-                        curr_subsec = Some((Subsection {
-                            name : first_text(&head.body()),
-                            parent_index : curr_sec_index,
-                            local_index : curr_subsec_local_index,
-                            global_index : curr_subsec_global_index,
-                            items : Vec::new()
-                        }, line));
-                    },
-                    _ => { }
+// Walks a typst source, building the document tree from the headings and objects found
+// at the document markup and at content nested in it (such as the body of #block[...]).
+// Function definitions (e.g. the transformation of a show rule) are not walked,
+// since their content only shows up where they are called.
+struct DocWalker<'a> {
+
+    source : &'a Source,
+
+    tree : crate::tex::TreeBuilder,
+
+    eq_ix : usize,
+
+    tbl_ix : usize,
+
+    img_ix : usize,
+
+    code_ix : usize,
+
+    fig_ix : usize,
+
+    // Figures being walked, whose images and tables are not listed apart from the figure.
+    figure_depth : usize
+
+}
+
+impl<'a> DocWalker<'a> {
+
+    fn line(&self, node : &SyntaxNode) -> usize {
+        self.source.byte_to_line(self.source.range(node.span()).start).unwrap_or(0)
+    }
+
+    fn marker(&self, name : &str, node : &SyntaxNode) -> crate::tex::Marker {
+        let range = self.source.range(node.span());
+        crate::tex::Marker {
+            name : name.to_string(),
+            line : self.source.byte_to_line(range.start).unwrap_or(0),
+            range
+        }
+    }
+
+    fn push_object(&mut self, obj : crate::tex::Object, line : usize) {
+        self.tree.push(crate::tex::Item::Object(obj, line));
+    }
+
+    // Walks the children of a node. Top-level nodes are also counted (after being walked,
+    // so that a heading is counted at the heading it opens).
+    fn walk_children(&mut self, node : &SyntaxNode, top_level : bool) {
+        let mut after_figure = false;
+        for child in node.children() {
+            match child.kind() {
+                SyntaxKind::Space => { },
+                SyntaxKind::Label => {
+                    if let Some(label) = child.cast::<ast::Label>() {
+                        let label = self.marker(label.get(), child);
+                        self.tree.label(label, after_figure);
+                    }
+                    after_figure = false;
+                },
+                _ => {
+                    after_figure = self.walk(child);
                 }
+            }
+            if top_level {
+                self.tree.feed(child);
+            }
+        }
+    }
+
+    // Walks a node, returning whether it is a figure.
+    fn walk(&mut self, node : &SyntaxNode) -> bool {
+        use crate::tex::Object;
+        let line = self.line(node);
+        match node.kind() {
+            SyntaxKind::Closure | SyntaxKind::Raw => { },
+            SyntaxKind::Heading => {
+                if let Some(head) = node.cast::<ast::Heading>() {
+                    self.tree.open_heading(first_text(&head.body()), head.level().get(), line);
+                }
+                self.walk_children(node, false);
             },
-            Expr::Equation(_) => {
-                let it = Item::Object(Object::Equation(eq_ix, ObjectIndex::Root(0), Some(String::new())), line);
-                push_to_curr_items(&mut curr_subsec, &mut curr_sec, &mut items, it);
-                eq_ix += 1;
+            SyntaxKind::Equation => {
+                let block = node.cast::<ast::Equation>().map(|eq| eq.block() ).unwrap_or(false);
+                if block && self.figure_depth == 0 {
+                    let obj = Object::Equation(self.eq_ix, self.tree.next_index(), None);
+                    self.push_object(obj, line);
+                    self.eq_ix += 1;
+                }
             },
-            Expr::Code(_) => {
-                let it = Item::Object(Object::Code(code_ix, ObjectIndex::Root(0), Some(String::new())), line);
-                push_to_curr_items(&mut curr_subsec, &mut curr_sec, &mut items, it);
-                code_ix += 1;
+            SyntaxKind::CodeBlock => {
+                let obj = Object::Code(self.code_ix, self.tree.next_index(), None);
+                self.push_object(obj, line);
+                self.code_ix += 1;
+                self.walk_children(node, false);
             },
-            Expr::FuncCall(call) => {
-                match call.callee() {
-                    Expr::Ident(id) => {
-                        let func = id.get().to_string();
-                        match call.args().items().next() {
-                            Some(Arg::Pos(expr)) => {
-                                match expr {
-                                    Expr::Str(s) => {
-                                        let arg = s.get().to_string();
-                                        match &func[..] {
-                                            "image" => {
-                                                let it = Item::Object(Object::Image(img_ix, ObjectIndex::Root(0), Some(arg)), line);
-                                                push_to_curr_items(&mut curr_subsec, &mut curr_sec, &mut items, it);
-                                                img_ix += 1;
-                                            },
-                                            "bibliography" => {
-                                                let it = Item::Object(Object::Bibliography(0, arg), line);
-                                                push_to_curr_items(&mut curr_subsec, &mut curr_sec, &mut items, it);
-                                            },
-                                            "table" => {
-                                                let it = Item::Object(Object::Table(tbl_ix, ObjectIndex::Root(0), Some(arg)), line);
-                                                push_to_curr_items(&mut curr_subsec, &mut curr_sec, &mut items, it);
-                                                tbl_ix += 1;
-                                            },
-                                            "code" => {
-
-                                            },
-                                            _ => {
-
-                                            }
-                                        }
-                                    },
-                                    _ => { }
-                                }
-                            },
-                            _ => { }
-                        }
-                    },
-                    _ => { }
+            SyntaxKind::Ref => {
+                if let Some(reference) = node.cast::<ast::Ref>() {
+                    let reference = self.marker(reference.target(), node);
+                    self.tree.reference(reference);
                 }
             },
-            _ => { }
+            SyntaxKind::ModuleInclude => {
+                if let Some(Expr::Str(path)) = node.cast::<ast::ModuleInclude>().map(|inc| inc.source() ) {
+                    let include = self.marker(path.get().as_str(), node);
+                    self.tree.include(include);
+                }
+            },
+            SyntaxKind::FuncCall => {
+                return self.walk_call(node, line);
+            },
+            _ => {
+                self.walk_children(node, false);
+            }
         }
+        false
     }
 
-    if let (Some(sub), Some(sec)) = (curr_subsec.take(), curr_sec.as_mut()) {
-        sec.0.items.push(Item::Subsection(sub.0, sub.1));
+    fn walk_call(&mut self, node : &SyntaxNode, line : usize) -> bool {
+        use crate::tex::{Object, FigureInfo};
+        let call = match node.cast::<ast::FuncCall>() {
+            Some(call) => call,
+            None => return false
+        };
+        let func = match call.callee() {
+            Expr::Ident(id) => id.get().to_string(),
+            _ => String::new()
+        };
+        let first_str = match call.args().items().next() {
+            Some(Arg::Pos(Expr::Str(s))) => Some(s.get().to_string()),
+            _ => None
+        };
+        match &func[..] {
+            "figure" => {
+                let caption = call.args().items().find_map(|arg| {
+                    match arg {
+                        Arg::Named(named) if named.name().get().as_str() == "caption" => caption_text(&named.expr()),
+                        _ => None
+                    }
+                });
+                let obj = Object::Figure(self.fig_ix, self.tree.next_index(), FigureInfo { caption, label : None });
+                self.push_object(obj, line);
+                self.fig_ix += 1;
+                self.figure_depth += 1;
+                self.walk_children(node, false);
+                self.figure_depth -= 1;
+                return true;
+            },
+            "image" if self.figure_depth == 0 => {
+                let obj = Object::Image(self.img_ix, self.tree.next_index(), first_str);
+                self.push_object(obj, line);
+                self.img_ix += 1;
+            },
+            "table" if self.figure_depth == 0 => {
+                let obj = Object::Table(self.tbl_ix, self.tree.next_index(), first_str);
+                self.push_object(obj, line);
+                self.tbl_ix += 1;
+            },
+            "bibliography" => {
+                if let Some(path) = first_str {
+                    self.push_object(Object::Bibliography(0, path), line);
+                }
+            },
+            _ => { }
+        }
+        self.walk_children(node, false);
+        false
     }
 
-    if let Some(sec) = curr_sec.take() {
-        items.push(Item::Section(sec.0, sec.1));
-    }
+}
+
+/// Parses the document model (headings, objects, labels and references) of a typst
+/// source. The path is the file the text was read from, or None for documents not saved yet.
+pub fn parse_doc(path : Option<&Path>, txt : String) -> Result<crate::tex::Document, Vec<Diagnostic>> {
+    let source = Source::new(SourceId::detached(), path.unwrap_or(Path::new("")), txt);
+    source.ast().map_err(|e| process_errors(&source, path, *e) )?;
+    let mut walker = DocWalker {
+        source : &source,
+        tree : crate::tex::TreeBuilder::default(),
+        eq_ix : 1,
+        tbl_ix : 1,
+        img_ix : 1,
+        code_ix : 1,
+        fig_ix : 1,
+        figure_depth : 0
+    };
+    walker.walk_children(source.root(), true);
+    let mut doc = walker.tree.finish();
+    doc.text_hash = outline::text_hash(source.text());
+    Ok(doc)
+}
 
-    Ok(crate::tex::Document { items })
+/// Path of the index of system fonts at the user data directory.
+pub fn font_cache_path() -> Option<PathBuf> {
+    let mut path = filecase::get_datadir(crate::APP_ID)?;
+    path.push(crate::FONT_CACHE_FILE);
+    Some(path)
+}
+
+/// Name of the folder, at the project root, with fonts used by the project.
+pub const PROJECT_FONTS_DIR : &str = "fonts";
+
+/// Directories searched for fonts besides the system font directories: the
+/// directories configured by the user and the fonts folder of the project (if any).
+pub fn font_dirs(user_dirs : &[PathBuf], root : Option<&Path>) -> Vec<PathBuf> {
+    let mut dirs = user_dirs.to_vec();
+    if let Some(project_dir) = root.map(|r| r.join(PROJECT_FONTS_DIR) ).filter(|d| d.is_dir() ) {
+        if !dirs.contains(&project_dir) {
+            dirs.push(project_dir);
+        }
+    }
+    dirs
 }
 
 #[derive(Clone)]
 pub struct Fonts {
     pub book : Arc<Prehashed<FontBook>>,
-    pub fonts : Arc<[FontSlot]>
+    pub fonts : Arc<[FontSlot]>,
+
+    // Fonts found at startup (at the system font directories and embedded in the
+    // binary), which are kept when the fonts are re-indexed with other directories.
+    base : Arc<[(FontInfo, FontSlot)]>
 }
 
 impl Fonts {
 
+    fn from_searcher(searcher : FontSearcher) -> Self {
+        let base : Vec<_> = searcher.fonts.iter().enumerate()
+            .filter_map(|(i, slot)| Some((searcher.book.info(i)?.clone(), slot.clone())) )
+            .collect();
+        Self {
+            fonts : searcher.fonts.into(),
+            book : Arc::new(Prehashed::new(searcher.book)),
+            base : base.into()
+        }
+    }
+
+    /// Fonts embedded in the binary, which are available immediately.
+    pub fn embedded(res : &gio::Resource) -> Self {
+        let mut searcher = FontSearcher::empty();
+        searcher.add_embedded(res);
+        Self::from_searcher(searcher)
+    }
+
+    /// Fonts at the system font directories and embedded in the binary.
     pub fn new(res : &gio::Resource) -> Self {
-        let searcher = FontSearcher::new(res);
+        Self::embedded(res).discover(None)
+    }
+
+    /// Fonts embedded in the binary, taken from the resources compiled by the build script.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        let bytes = gtk4::glib::Bytes::from_static(include_bytes!(concat!(env!("OUT_DIR"), "/compiled.gresource")));
+        Self::embedded(&gio::Resource::from_data(&bytes).unwrap())
+    }
+
+    /// Adds the fonts at the system font directories to these fonts. Files indexed at the
+    /// cache at the given path are only parsed again when they changed, and the cache
+    /// is updated with all files found.
+    pub fn discover(&self, cache_path : Option<&Path>) -> Self {
+        let start = std::time::Instant::now();
+        let mut searcher = FontSearcher::empty();
+        searcher.cache = cache_path.map(FontCache::load);
+        searcher.search_system();
+        if let (Some(cache), Some(path)) = (searcher.cache.as_mut(), cache_path) {
+            cache.save(path);
+        }
+        log::info!("{} system fonts indexed in {} ms", searcher.fonts.len(), start.elapsed().as_millis());
+        for (info, slot) in self.base.iter() {
+            searcher.book.push(info.clone());
+            searcher.fonts.push(slot.clone());
+        }
+        Self::from_searcher(searcher)
+    }
+
+    /// Returns the fonts found at startup plus all fonts at the given directories. Fonts at
+    /// the directories come first, so they are preferred over system fonts with the same
+    /// family and variant.
+    pub fn with_dirs(&self, dirs : &[PathBuf]) -> Self {
+        let mut searcher = FontSearcher::empty();
+        for dir in dirs {
+            searcher.search_dir(dir);
+        }
+        log::info!("{} fonts found at {} font directories", searcher.fonts.len(), dirs.len());
+        for (info, slot) in self.base.iter() {
+            searcher.book.push(info.clone());
+            searcher.fonts.push(slot.clone());
+        }
         Self {
             fonts : searcher.fonts.into(),
             book : Arc::new(Prehashed::new(searcher.book)),
+            base : self.base.clone()
         }
     }
 
@@ -265,11 +555,14 @@ mod imported {
     pub struct FontSearcher {
         pub book: FontBook,
         pub fonts: Vec<FontSlot>,
+
+        // Fonts of files indexed before, if they are cached.
+        pub cache: Option<FontCache>,
     }
 
     impl FontSearcher {
 
-        fn add_embedded(&mut self, resource : &gio::Resource) {
+        pub fn add_embedded(&mut self, resource : &gio::Resource) {
             let mut add = |bytes: &[u8]| {
 
                 // Unsafe required because the returned gio::Bytes doesn't have 'static lifetime.
@@ -292,6 +585,8 @@ mod imported {
                 "LinLibertine_RB.ttf",
                 "LinLibertine_RBI.ttf",
                 "LinLibertine_RI.ttf",
+                "NewCM10-Regular.otf",
+                "NewCM10-Bold.otf",
                 "NewCMMath-Book.otf",
                 "NewCMMath-Regular.otf",
                 "DejaVuSansMono.ttf",
@@ -303,16 +598,13 @@ mod imported {
             }
         }
 
-        /// Create a new, empty system searcher.
-        pub fn new(res : &gio::Resource) -> Self {
-            let mut searcher = Self { book: FontBook::new(), fonts: vec![] };
-            searcher.search_system();
-            searcher.add_embedded(res);
-            searcher
+        /// Create a new, empty searcher.
+        pub fn empty() -> Self {
+            Self { book: FontBook::new(), fonts: vec![], cache: None }
         }
 
         /// Search for fonts in the linux system font directories.
-        fn search_system(&mut self) {
+        pub fn search_system(&mut self) {
             self.search_dir("/usr/share/fonts");
             self.search_dir("/usr/local/share/fonts");
             if let Some(dir) = dirs::font_dir() {
@@ -321,7 +613,7 @@ mod imported {
         }
 
         /// Search for all fonts in a directory recursively.
-        fn search_dir(&mut self, path: impl AsRef<Path>) {
+        pub fn search_dir(&mut self, path: impl AsRef<Path>) {
             for entry in WalkDir::new(path)
                 .follow_links(true)
                 .sort_by(|a, b| a.file_name().cmp(b.file_name()))
@@ -341,17 +633,28 @@ mod imported {
         /// Index the fonts in the file at the given path.
         fn search_file(&mut self, path: impl AsRef<Path>) {
             let path = path.as_ref();
-            if let Ok(file) = File::open(path) {
-                if let Ok(mmap) = unsafe { Mmap::map(&file) } {
-                    for (i, info) in FontInfo::iter(&mmap).enumerate() {
-                        self.book.push(info);
-                        self.fonts.push(FontSlot {
-                            path: path.into(),
-                            index: i as u32,
-                            font: OnceCell::new(),
-                        });
+            let cached = self.cache.as_mut()
+                .and_then(|cache| cache.lookup(path) )
+                .map(|infos| infos.to_vec() );
+            let infos = match cached {
+                Some(infos) => infos,
+                None => {
+                    let Ok(file) = File::open(path) else { return };
+                    let Ok(mmap) = (unsafe { Mmap::map(&file) }) else { return };
+                    let infos : Vec<FontInfo> = FontInfo::iter(&mmap).collect();
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.insert(path, infos.clone());
                     }
+                    infos
                 }
+            };
+            for (i, info) in infos.into_iter().enumerate() {
+                self.book.push(info);
+                self.fonts.push(FontSlot {
+                    path: path.into(),
+                    index: i as u32,
+                    font: OnceCell::new(),
+                });
             }
         }
     }
@@ -366,9 +669,20 @@ mod imported {
         paths: RefCell<HashMap<PathHash, PathSlot>>,
         pub sources: FrozenVec<Box<Source>>,
         pub main: SourceId,
+
+        // Source holding text set by overlay_main or overlay_file instead of the file content.
+        overlaid: Option<SourceId>,
+
+        // Whether the main source is virtual (set by set_untitled_main).
+        untitled: bool,
+
+        // Normalized paths of all files the last compilation tried to read (including
+        // files that were not found).
+        accessed: RefCell<HashSet<PathBuf>>,
     }
 
     /// Holds details about the location of a font and lazily the font itself.
+    #[derive(Clone)]
     pub struct FontSlot {
         path: PathBuf,
         index: u32,
@@ -376,12 +690,24 @@ mod imported {
     }
 
     /// Holds canonical data for all paths pointing to the same entity.
-    #[derive(Default)]
     struct PathSlot {
+        path: PathBuf,
+        modified: Option<SystemTime>,
         source: OnceCell<FileResult<SourceId>>,
         buffer: OnceCell<FileResult<Buffer>>,
     }
 
+    impl PathSlot {
+        fn new(path: &Path) -> Self {
+            Self {
+                path: path.to_owned(),
+                modified: modified_time(path),
+                source: OnceCell::new(),
+                buffer: OnceCell::new(),
+            }
+        }
+    }
+
     impl SystemWorld {
         pub fn new(root: PathBuf, fonts : Fonts) -> Self {
             Self {
@@ -393,6 +719,85 @@ mod imported {
                 paths: RefCell::default(),
                 sources: FrozenVec::new(),
                 main: SourceId::detached(),
+                overlaid: None,
+                untitled: false,
+                accessed: RefCell::default(),
+            }
+        }
+
+        /// Sets the source that is compiled by this world.
+        pub fn set_main(&mut self, path: &Path) -> FileResult<()> {
+            self.main = self.resolve(path)?;
+            self.untitled = false;
+            Ok(())
+        }
+
+        /// Sets an empty virtual source as main, for documents that were not saved yet.
+        /// It is never read from disk, so its content must be set with overlay_main. Relative
+        /// paths in it are resolved against the world root.
+        pub fn set_untitled_main(&mut self) {
+            let root = self.root.clone();
+            self.set_virtual_main(&root);
+        }
+
+        /// Sets an empty virtual source as main, as set_untitled_main does, but placed at
+        /// the given directory, against which relative paths in it are resolved.
+        pub fn set_virtual_main(&mut self, dir: &Path) {
+            self.main = self.insert(&dir.join(UNTITLED), String::new());
+            self.untitled = true;
+        }
+
+        /// Returns the source with the given id, if it was loaded by this world.
+        /// Unlike World::source, this does not panic for detached ids.
+        pub fn find_source(&self, id: SourceId) -> Option<&Source> {
+            self.sources.iter().find(|s| s.id() == id )
+        }
+
+        /// Path of the file of a source, or None for the main source of untitled documents.
+        pub fn source_path(&self, source: &Source) -> Option<PathBuf> {
+            if self.untitled && source.id() == self.main {
+                None
+            } else {
+                Some(source.path().to_owned())
+            }
+        }
+
+        /// Uses the given text (e.g. the unsaved editor buffer) as the content of the main
+        /// source. The file on disk is not read anymore by refresh once the main source is
+        /// overlaid, but the other sources it imports or includes still are.
+        pub fn overlay_main(&mut self, text: String) {
+            self.overlay(self.main, text);
+        }
+
+        /// Uses the given text as the content of a file the main source includes (e.g. while
+        /// the included file is edited), as overlay_main does for the main source.
+        pub fn overlay_file(&mut self, path: &Path, text: String) -> FileResult<()> {
+            let id = self.resolve(path)?;
+            self.overlay(id, text);
+            Ok(())
+        }
+
+        /// The overlaid source (the one being edited), or the main source if none is.
+        pub fn edited(&self) -> &Source {
+            self.source(self.overlaid.unwrap_or(self.main))
+        }
+
+        fn overlay(&mut self, id: SourceId, text: String) {
+            let sources = self.sources.as_mut();
+
+            // A source that is not edited anymore might hold unsaved text, so its file is read again.
+            if let Some(prev) = self.overlaid.filter(|prev| *prev != id ) {
+                let source = &mut sources[prev.into_u16() as usize];
+                if let Ok(text) = std::fs::read_to_string(source.path()) {
+                    if source.text() != &text[..] {
+                        source.replace(text);
+                    }
+                }
+            }
+            self.overlaid = Some(id);
+            let source = &mut sources[id.into_u16() as usize];
+            if source.text() != &text[..] {
+                source.replace(text);
             }
         }
     }
@@ -449,6 +854,12 @@ mod imported {
 
     impl SystemWorld {
         fn slot(&self, path: &Path) -> FileResult<RefMut<PathSlot>> {
+            let mut accessed = self.accessed.borrow_mut();
+            accessed.insert(path.normalize());
+            if let Ok(canon) = path.canonicalize() {
+                accessed.insert(canon);
+            }
+            drop(accessed);
             let mut hashes = self.hashes.borrow_mut();
             let hash = match hashes.get(path).cloned() {
                 Some(hash) => hash,
@@ -463,7 +874,7 @@ mod imported {
             }?;
 
             Ok(std::cell::RefMut::map(self.paths.borrow_mut(), |paths| {
-                paths.entry(hash).or_default()
+                paths.entry(hash).or_insert_with(|| PathSlot::new(path))
             }))
         }
 
@@ -474,16 +885,18 @@ mod imported {
             id
         }
 
-        fn relevant(&mut self, event: &notify::Event) -> bool {
+        /// Whether a file system event should trigger a new compilation, because it
+        /// changed a file the last compilation read (or tried to read).
+        pub fn relevant(&self, event: &notify::Event) -> bool {
             match &event.kind {
                 notify::EventKind::Any => {}
                 notify::EventKind::Access(_) => return false,
-                notify::EventKind::Create(_) => return true,
+                notify::EventKind::Create(_) => {}
                 notify::EventKind::Modify(kind) => match kind {
                     notify::event::ModifyKind::Any => {}
                     notify::event::ModifyKind::Data(_) => {}
                     notify::event::ModifyKind::Metadata(_) => return false,
-                    notify::event::ModifyKind::Name(_) => return true,
+                    notify::event::ModifyKind::Name(_) => {}
                     notify::event::ModifyKind::Other => return false,
                 },
                 notify::EventKind::Remove(_) => {}
@@ -493,10 +906,73 @@ mod imported {
             event.paths.iter().any(|path| self.dependant(path))
         }
 
-        fn dependant(&self, path: &Path) -> bool {
-            self.hashes.borrow().contains_key(&path.normalize())
-                || PathHash::new(path)
-                    .map_or(false, |hash| self.paths.borrow().contains_key(&hash))
+        /// Whether the last compilation read the given file. The main source always
+        /// counts, since it is read before the compilation starts, unless its content
+        /// is overlaid (as is the overlaid source of an included file): saving the
+        /// editor buffer does not change what is typeset.
+        pub fn dependant(&self, path: &Path) -> bool {
+            let path = path.normalize();
+            let is_source = |id: SourceId| self.find_source(id).map(|s| {
+                s.path().normalize() == path || s.path().canonicalize().map_or(false, |canon| canon == path )
+            }).unwrap_or(false);
+            if self.overlaid.map(is_source).unwrap_or(false) {
+                return false;
+            }
+            if !self.untitled && is_source(self.main) {
+                return true;
+            }
+            let accessed = self.accessed.borrow();
+            accessed.contains(&path)
+                || path.canonicalize().map_or(false, |canon| accessed.contains(&canon) )
+        }
+
+        /// Starts tracking the files read by a new compilation.
+        pub fn reset_accessed(&mut self) {
+            self.accessed.get_mut().clear();
+        }
+
+        /// Updates the sources and files read by previous compilations that changed on disk
+        /// since then. Sources are edited in place so their ids (and the memoized results of
+        /// sources that did not change) stay valid. Returns whether anything changed.
+        pub fn refresh(&mut self) -> bool {
+
+            // Paths that could not be found before might exist now.
+            self.hashes.get_mut().retain(|_, hash| hash.is_ok());
+
+            let mut changed = false;
+            let sources = self.sources.as_mut();
+            for slot in self.paths.get_mut().values_mut() {
+                let modified = modified_time(&slot.path);
+                if modified == slot.modified {
+                    continue;
+                }
+                slot.modified = modified;
+                changed = true;
+                match slot.source.get().cloned() {
+                    Some(Ok(id)) if self.overlaid == Some(id) => { },
+                    Some(Ok(id)) => {
+                        match read(&slot.path).and_then(|buf| Ok(String::from_utf8(buf)?) ) {
+                            Ok(text) => {
+                                let source = &mut sources[id.into_u16() as usize];
+                                if source.text() != &text[..] {
+                                    source.replace(text);
+                                }
+                            },
+                            Err(_) => {
+                                slot.source = OnceCell::new();
+                            }
+                        }
+                    },
+                    Some(Err(_)) => {
+                        slot.source = OnceCell::new();
+                    },
+                    None => { }
+                }
+
+                // Binary files are cheap to re-read lazily.
+                slot.buffer = OnceCell::new();
+            }
+            changed
         }
 
         pub fn reset(&mut self) {
@@ -520,6 +996,14 @@ mod imported {
         }
     }
 
+    /// Name of the main source of documents without a file.
+    pub const UNTITLED: &str = "untitled.typ";
+
+    /// Last modification time of a file, if available.
+    fn modified_time(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|meta| meta.modified() ).ok()
+    }
+
     /// Read a file.
     fn read(path: &Path) -> FileResult<Vec<u8>> {
         let f = |e| FileError::from_io(e, path);
@@ -588,3 +1072,86 @@ mod imported {
 }
 
 
+
+#[test]
+fn parse_errors_are_located() {
+    let txt = String::from("= Introduction\n\nSome text #let x = (1, 2\n");
+    let errs = parse_doc(Some(Path::new("/tmp/doc.typ")), txt).unwrap_err();
+    assert!(!errs.is_empty());
+    assert_eq!(errs[0].line, 2);
+    assert_eq!(errs[0].path.as_deref(), Some(Path::new("/tmp/doc.typ")));
+}
+
+#[test]
+fn headings_nest_at_any_depth() {
+    let txt = String::from("= A\n== B\n=== C\n$ x $\n= D\n");
+    let doc = parse_doc(None, txt).unwrap();
+    let headings : Vec<_> = doc.headings().iter().map(|h| (h.name.clone(), h.number_label()) ).collect();
+    assert_eq!(headings, vec![
+        (String::from("A"), String::from("1")),
+        (String::from("B"), String::from("1.1")),
+        (String::from("C"), String::from("1.1.1")),
+        (String::from("D"), String::from("2"))
+    ]);
+    assert_eq!(doc.get_line(&[0, 0, 0, 0]), Some(3));
+}
+
+#[test]
+fn outline_walks_nested_markup() {
+    use crate::tex::{Object, FigureInfo};
+    let txt = String::from(concat!(
+        "#block[\n= Abstract\nSee @cat for a cat.\n]\n",
+        "#let note(body) = [= Not a heading]\n",
+        "= Results\n",
+        "#figure(image(\"cat.png\"), caption : [A *black* cat]) <cat>\n",
+        "Inline $x$ and block\n$ y $\n"
+    ));
+    let doc = parse_doc(None, txt).unwrap();
+    let headings : Vec<_> = doc.headings().iter().map(|h| h.name.clone() ).collect();
+    assert_eq!(headings, vec![String::from("Abstract"), String::from("Results")]);
+    let objs = doc.objects();
+    assert_eq!(objs.len(), 2);
+    match &objs[0] {
+        Object::Figure(1, _, info) => assert_eq!(info, &FigureInfo {
+            caption : Some(String::from("A black cat")),
+            label : Some(String::from("cat"))
+        }),
+        other => panic!("Expected figure, found {:?}", other)
+    }
+    assert!(matches!(objs[1], Object::Equation(1, _, None)));
+    assert_eq!(doc.labels.iter().map(|l| (&l.name[..], l.line) ).collect::<Vec<_>>(), vec![("cat", 6)]);
+    assert_eq!(doc.refs.iter().map(|r| (&r.name[..], r.line) ).collect::<Vec<_>>(), vec![("cat", 2)]);
+}
+
+#[test]
+fn document_paths_are_relative_to_project() {
+    let file = Path::new("/thesis/chapters/intro.typ");
+    let root = Path::new("/thesis");
+    assert_eq!(document_path(Path::new("/thesis/chapters/fig.png"), Some(file), Some(root)), "fig.png");
+    assert_eq!(document_path(Path::new("/thesis/lib.typ"), Some(file), Some(root)), "/lib.typ");
+    assert_eq!(document_path(Path::new("/other/lib.typ"), Some(file), Some(root)), "/other/lib.typ");
+    assert_eq!(project_root(file, Some(root)), Some(root.to_owned()));
+}
+
+#[test]
+fn unchanged_sources_are_not_parsed_again() {
+    let dir = std::env::temp_dir().join(format!("drafts-reuse-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let main = dir.join("main.typ");
+    std::fs::write(&main, "#import \"lib.typ\": name\n= Intro\nHello #name\n").unwrap();
+    std::fs::write(dir.join("lib.typ"), "#let name = [world]\n").unwrap();
+    let mut world = SystemWorld::new(dir.clone(), Fonts::for_tests());
+    world.set_main(&main).unwrap();
+    compile(&mut world).unwrap();
+    let parsed : Vec<*const Source> = world.sources.iter().map(|s| s as *const Source ).collect();
+    assert_eq!(parsed.len(), 2);
+
+    // Neither a new compilation nor an overlay with the same text adds sources, so both
+    // reuse the syntax trees (and the memoized results) of the first compilation.
+    compile(&mut world).unwrap();
+    let text = world.main().text().to_string();
+    world.overlay_main(text);
+    compile(&mut world).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert_eq!(world.sources.iter().map(|s| s as *const Source ).collect::<Vec<_>>(), parsed);
+}
@@ -0,0 +1,183 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use typst::doc::{Document, Frame, FrameItem};
+use typst::geom::Point;
+use typst::model::{Introspector, Selector, StyleChain};
+use typst::syntax::{Source, Span};
+use typst_library::meta::{HeadingElem, Numbering};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use super::preview::Location;
+
+/// A heading of the main source, as laid out at the compiled document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeadingAnchor {
+
+    // Zero-based line of the heading at the main source.
+    pub line : usize,
+
+    // Number shown by typst before the heading (e.g. "1.2"), if headings are numbered.
+    pub numbering : Option<String>,
+
+    pub location : Location
+
+}
+
+/// Where the content of the main source ended up at the compiled document, so that items
+/// of the document model (which only know their lines) can be found at the preview.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Outline {
+
+    pub headings : Vec<HeadingAnchor>,
+
+    // First place where content of each line of the main source was laid out, ordered by line.
+    pub lines : Vec<(usize, Location)>,
+
+    // Hash of the text of the main source that was typeset (see text_hash).
+    pub text_hash : u64
+
+}
+
+impl Outline {
+
+    pub fn heading_at(&self, line : usize) -> Option<&HeadingAnchor> {
+        self.headings.iter().find(|h| h.line == line )
+    }
+
+    /// Where the content at or after the given line was laid out (objects such as images
+    /// have no text of their own at the line, so the next content laid out is used).
+    pub fn location_at(&self, line : usize) -> Option<Location> {
+        if let Some(h) = self.heading_at(line) {
+            return Some(h.location);
+        }
+        let ix = self.lines.partition_point(|(l, _)| *l < line );
+        self.lines.get(ix).map(|(_, loc)| *loc )
+    }
+
+    /// Page (counting from one) where the content at or after the given line was laid out.
+    pub fn page_at(&self, line : usize) -> Option<usize> {
+        self.location_at(line).map(|loc| loc.page + 1 )
+    }
+
+    /// Whether the outline was taken from the typesetting of the given text, so that its
+    /// lines still point to the same content.
+    pub fn is_from(&self, txt_hash : u64) -> bool {
+        self.text_hash == txt_hash
+    }
+
+}
+
+/// Hash identifying a version of the text of a source (the same for the editor and the worker process).
+pub fn text_hash(txt : &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    txt.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Line of the main source a span points to, if any.
+fn main_line(source : &Source, span : Span) -> Option<usize> {
+    if span.source() != source.id() {
+        return None;
+    }
+    source.byte_to_line(source.range(span).start)
+}
+
+fn visit_frame(
+    frame : &Frame,
+    page : usize,
+    origin : Point,
+    source : &Source,
+    lines : &mut BTreeMap<usize, Location>
+) {
+    for (pos, item) in frame.items() {
+        let pos = origin + *pos;
+        let mut mark = |span : Span| {
+            if let Some(line) = main_line(source, span) {
+                lines.entry(line).or_insert(Location { page, x : pos.x.to_pt(), y : pos.y.to_pt() });
+            }
+        };
+        match item {
+            FrameItem::Group(group) => visit_frame(&group.frame, page, pos, source, lines),
+            FrameItem::Text(text) => {
+                if let Some(glyph) = text.glyphs.first() {
+                    mark(glyph.span);
+                }
+            },
+            FrameItem::Shape(_, span) => mark(*span),
+            FrameItem::Image(_, _, span) => mark(*span),
+            _ => { }
+        }
+    }
+}
+
+/// Applies a heading counter step at the given level, as typst does.
+pub(crate) fn step_counter(counts : &mut Vec<usize>, level : usize) {
+    if counts.len() >= level {
+        counts[level - 1] += 1;
+        counts.truncate(level);
+    }
+    while counts.len() < level {
+        counts.push(1);
+    }
+}
+
+/// Finds the pages (and the numbering) of the headings of the main source of a compiled
+/// document. Headings numbered by a function are listed without a number, since the
+/// function can only be called while the document is compiled.
+pub fn outline(doc : &Document, source : &Source) -> Outline {
+    let introspector = Introspector::new(&doc.pages);
+    let mut headings = Vec::new();
+    let mut counts : Vec<usize> = Vec::new();
+    for elem in introspector.query(Selector::Elem(HeadingElem::func(), None)) {
+        let heading = match elem.to::<HeadingElem>() {
+            Some(heading) => heading,
+            None => continue
+        };
+        let numbering = heading.numbering(StyleChain::default());
+        if numbering.is_some() {
+            step_counter(&mut counts, heading.level(StyleChain::default()).get());
+        }
+        let (line, loc) = match (main_line(source, elem.span()), elem.location()) {
+            (Some(line), Some(loc)) => (line, loc),
+            _ => continue
+        };
+        let pos = introspector.position(loc);
+        headings.push(HeadingAnchor {
+            line,
+            numbering : match numbering {
+                Some(Numbering::Pattern(pattern)) => Some(pattern.apply(&counts).trim().to_string()),
+                _ => None
+            },
+            location : Location { page : pos.page.get() - 1, x : pos.point.x.to_pt(), y : pos.point.y.to_pt() }
+        });
+    }
+
+    let mut lines = BTreeMap::new();
+    for (page, frame) in doc.pages.iter().enumerate() {
+        visit_frame(frame, page, Point::zero(), source, &mut lines);
+    }
+    Outline { headings, lines : lines.into_iter().collect(), text_hash : text_hash(source.text()) }
+}
+
+#[test]
+fn heading_counter_steps_as_typst() {
+    let mut counts = Vec::new();
+    for level in [1, 2, 2, 1, 3] {
+        step_counter(&mut counts, level);
+    }
+    assert_eq!(counts, vec![2, 1, 1]);
+}
+
+#[test]
+fn outline_is_only_used_for_the_text_it_came_from() {
+    let txt = "= Intro\nSome text.\n";
+    let doc = super::parse_doc(None, txt.to_string()).unwrap();
+    let outline = Outline { text_hash : text_hash(txt), ..Default::default() };
+    assert!(outline.is_from(doc.text_hash));
+    let edited = super::parse_doc(None, format!("{}= Methods\n", txt)).unwrap();
+    assert!(!outline.is_from(edited.text_hash));
+}
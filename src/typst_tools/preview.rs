@@ -0,0 +1,197 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use typst::doc::{Document, Frame};
+use typst::geom::{Abs, Color, Point};
+use typst::ide::Jump;
+use typst::syntax::Source;
+use typst::World;
+use std::path::PathBuf;
+use super::SystemWorld;
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use base64::Engine;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Pixels per point used for the preview when nothing else was requested (i.e. 72 dpi).
+pub const DEFAULT_PREVIEW_SCALE : f32 = 1.0;
+
+/// Largest number of pixels per point used for the preview. Each page at this scale takes
+/// about 32MB for an A4 page, so pages zoomed beyond it are drawn scaled up instead.
+pub const MAX_PREVIEW_SCALE : f32 = 4.0;
+
+/// A page rasterized straight from its frame, for the preview. The pixels are kept
+/// in the layout cairo expects, so that the editor can draw them without conversion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageImage {
+
+    // Page size, in points.
+    pub width : f64,
+
+    pub height : f64,
+
+    // Pixels per point the page was rasterized with.
+    pub scale : f32,
+
+    pub pixel_width : u32,
+
+    pub pixel_height : u32,
+
+    // Premultiplied ARGB pixels, each a native-endian 32-bit integer (cairo's ARGB32 format).
+    #[serde(serialize_with = "encode_pixels", deserialize_with = "decode_pixels")]
+    pub data : Vec<u8>
+
+}
+
+fn encode_pixels<S : Serializer>(data : &Vec<u8>, s : S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+fn decode_pixels<'de, D : Deserializer<'de>>(d : D) -> Result<Vec<u8>, D::Error> {
+    let txt = String::deserialize(d)?;
+    base64::engine::general_purpose::STANDARD.decode(txt).map_err(serde::de::Error::custom)
+}
+
+/// The pages of a compiled document sent to the editor. Pages are only rasterized when
+/// the editor shows them, so most pages might be sent without an image.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Preview {
+
+    // Identifies the compilation the pages come from.
+    pub revision : u64,
+
+    // Size of every page, in points.
+    pub sizes : Vec<(f64, f64)>,
+
+    // Hash of the content of every page, so that pages which did not change across
+    // compilations can be told apart (and need not be rasterized again).
+    pub hashes : Vec<u64>,
+
+    // Images of some of the pages, with their (zero-based) indices.
+    pub images : Vec<(usize, PageImage)>
+
+}
+
+/// A point at a page of the document.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+
+    // Zero-based page index.
+    pub page : usize,
+
+    // Distance from the top-left corner of the page, in points. For text, y is at the baseline.
+    pub x : f64,
+
+    pub y : f64
+
+}
+
+/// Finds where the text at the given byte offset of a source was laid out.
+pub fn locate(doc : &Document, source : &Source, offset : usize) -> Option<Location> {
+    let pos = typst::ide::jump_from_cursor(&doc.pages, source, offset)?;
+    Some(Location { page : pos.page.get() - 1, x : pos.point.x.to_pt(), y : pos.point.y.to_pt() })
+}
+
+/// What was clicked at the preview, as found by an inverse search.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClickTarget {
+
+    // Source text that produced the clicked glyph, at a zero-based line and column (in characters).
+    // The path is None for the main source of untitled documents.
+    Source { path : Option<PathBuf>, line : usize, column : usize },
+
+    // Destination of an internal link.
+    Location(Location),
+
+    // Destination of an external link.
+    Url(String)
+
+}
+
+/// Finds what is at the given point of the document (e.g. the source text that produced a glyph).
+pub fn jump_from_click(world : &SystemWorld, doc : &Document, loc : &Location) -> Option<ClickTarget> {
+    let frame = doc.pages.get(loc.page)?;
+    let point = Point::new(Abs::pt(loc.x), Abs::pt(loc.y));
+    match typst::ide::jump_from_click(world, &doc.pages, frame, point)? {
+        Jump::Source(id, offset) => {
+            let source = world.find_source(id)?;
+            Some(ClickTarget::Source {
+                path : world.source_path(source),
+                line : source.byte_to_line(offset)?,
+                column : source.byte_to_column(offset)?
+            })
+        },
+        Jump::Position(pos) => Some(ClickTarget::Location(Location {
+            page : pos.page.get() - 1,
+            x : pos.point.x.to_pt(),
+            y : pos.point.y.to_pt()
+        })),
+        Jump::Url(url) => Some(ClickTarget::Url(url.to_string()))
+    }
+}
+
+// Converts premultiplied RGBA bytes (as written by typst) to cairo's ARGB32 layout.
+fn rgba_to_argb(rgba : &[u8]) -> Vec<u8> {
+    let mut argb = Vec::with_capacity(rgba.len());
+    for px in rgba.chunks_exact(4) {
+        let [r, g, b, a] = [px[0], px[1], px[2], px[3]].map(|c| c as u32 );
+        argb.extend_from_slice(&((a << 24) | (r << 16) | (g << 8) | b).to_ne_bytes());
+    }
+    argb
+}
+
+/// Identifies the content of a page.
+pub fn page_hash(frame : &Frame) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    frame.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Rasterizes a page with the given number of pixels per point, over a white background.
+pub fn render_page(frame : &Frame, scale : f32) -> PageImage {
+    let pixmap = typst::export::render(frame, scale, Color::WHITE);
+    PageImage {
+        width : frame.width().to_pt(),
+        height : frame.height().to_pt(),
+        scale,
+        pixel_width : pixmap.width(),
+        pixel_height : pixmap.height(),
+        data : rgba_to_argb(pixmap.data())
+    }
+}
+
+#[test]
+fn pixels_are_converted_to_argb() {
+    let argb = rgba_to_argb(&[0x10, 0x20, 0x30, 0xff]);
+    assert_eq!(u32::from_ne_bytes([argb[0], argb[1], argb[2], argb[3]]), 0xff102030);
+}
+
+#[test]
+fn cursor_and_click_positions_round_trip() {
+    let dir = std::env::temp_dir().join(format!("drafts-jump-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let main = dir.join("main.typ");
+    let txt = "= Intro\nHello world\n";
+    std::fs::write(&main, txt).unwrap();
+    let mut world = SystemWorld::new(dir.clone(), super::Fonts::for_tests());
+    world.set_main(&main).unwrap();
+    let doc = super::compile(&mut world).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    let offset = txt.find("world").unwrap();
+    let loc = locate(&doc, world.main(), offset).unwrap();
+    assert_eq!(loc.page, 0);
+
+    // Clicks are searched within the glyph boxes, which are above the baseline.
+    let click = Location { page : 0, x : loc.x + 1.0, y : loc.y - 2.0 };
+    match jump_from_click(&world, &doc, &click) {
+        Some(ClickTarget::Source { path, line, column }) => {
+            assert_eq!(path, Some(main));
+            assert_eq!(line, 1);
+            assert!(column <= "Hello world".len());
+        },
+        other => panic!("Unexpected target {:?}", other)
+    }
+}
@@ -0,0 +1,121 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use typst::syntax::{Source, SourceId, SyntaxKind, SyntaxNode};
+use std::path::Path;
+
+/// Words read per minute, used to estimate the reading time of a text.
+pub const WORDS_PER_MINUTE : usize = 230;
+
+/// Counts of the prose of a document (or of a part of it). Markup delimiters, code, math
+/// and raw text are not counted, but the text they emphasize (e.g. *strong* text) is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextStats {
+
+    pub words : usize,
+
+    // Characters of the words (i.e. not counting spaces).
+    pub chars : usize,
+
+    pub paragraphs : usize
+
+}
+
+impl TextStats {
+
+    /// Estimated reading time, in minutes (rounded up).
+    pub fn reading_minutes(&self) -> usize {
+        (self.words + WORDS_PER_MINUTE - 1) / WORDS_PER_MINUTE
+    }
+
+}
+
+// Appends the prose of a markup node, replacing what is not prose by a space
+// (so that, for instance, an inline equation still separates the words around it).
+pub(crate) fn collect_prose(node : &SyntaxNode, out : &mut String) {
+    match node.kind() {
+        SyntaxKind::Text | SyntaxKind::SmartQuote => out.push_str(node.text()),
+        SyntaxKind::Escape => out.push_str(node.text().trim_start_matches('\\')),
+        SyntaxKind::Markup | SyntaxKind::Strong | SyntaxKind::Emph | SyntaxKind::Heading |
+        SyntaxKind::ListItem | SyntaxKind::EnumItem | SyntaxKind::TermItem => {
+            for child in node.children() {
+                collect_prose(child, out);
+            }
+        },
+        SyntaxKind::Star | SyntaxKind::Underscore | SyntaxKind::HeadingMarker |
+        SyntaxKind::ListMarker | SyntaxKind::EnumMarker | SyntaxKind::TermMarker | SyntaxKind::Colon => { },
+        _ => out.push(' ')
+    }
+}
+
+/// Accumulates the statistics of consecutive top-level nodes of a typst source.
+#[derive(Debug, Clone, Default)]
+pub struct StatsCounter {
+
+    stats : TextStats,
+
+    // Prose since the last paragraph break.
+    pending : String,
+
+    // Whether the current paragraph was already counted.
+    par_open : bool
+
+}
+
+impl StatsCounter {
+
+    fn flush(&mut self, paragraph : bool) {
+        let words = self.pending.split_whitespace().count();
+        self.stats.words += words;
+        self.stats.chars += self.pending.chars().filter(|c| !c.is_whitespace() ).count();
+        if paragraph && words > 0 && !self.par_open {
+            self.stats.paragraphs += 1;
+            self.par_open = true;
+        }
+        self.pending.clear();
+    }
+
+    pub fn feed(&mut self, node : &SyntaxNode) {
+        match node.kind() {
+            SyntaxKind::Parbreak => {
+                self.flush(true);
+                self.par_open = false;
+            },
+
+            // Headings count as words, but not as paragraphs.
+            SyntaxKind::Heading => {
+                self.flush(true);
+                self.par_open = false;
+                collect_prose(node, &mut self.pending);
+                self.flush(false);
+            },
+            _ => collect_prose(node, &mut self.pending)
+        }
+    }
+
+    pub fn finish(mut self) -> TextStats {
+        self.flush(true);
+        self.stats
+    }
+
+}
+
+/// Counts the prose of a typst source. The text is parsed even when it has syntax
+/// errors, so that the counts can be shown while the user is typing.
+pub fn text_stats(txt : &str) -> TextStats {
+    let source = Source::new(SourceId::detached(), Path::new(""), txt.to_string());
+    let mut counter = StatsCounter::default();
+    for node in source.root().children() {
+        counter.feed(node);
+    }
+    counter.finish()
+}
+
+#[test]
+fn prose_is_counted_without_markup_code_and_math() {
+    let stats = text_stats("= Intro\n\nSome *bold* text with $x^2$ math.\n\n#let a = 1\nDon't stop.");
+    assert_eq!(stats.words, 8);
+    assert_eq!(stats.paragraphs, 2);
+}
@@ -0,0 +1,330 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use gtk4::*;
+use gtk4::prelude::*;
+use super::*;
+use crate::analyzer::Analyzer;
+use crate::diagnostic::{Diagnostic, Severity};
+use stateful::Callbacks;
+
+#[derive(Debug, Default)]
+struct PanelState {
+
+    // Problems found by the last typesetting and by the last analysis of the document.
+    compile : Vec<Diagnostic>,
+
+    parse : Vec<Diagnostic>,
+
+    // Warnings about the document found by the analyzer.
+    lint : Vec<Diagnostic>,
+
+    // File open at the editor.
+    file : Option<PathBuf>,
+
+    // Diagnostics in the order of their rows (rows store the index into this vector).
+    rows : Vec<Diagnostic>,
+
+    // Diagnostic selected at another file, to be reached once that file is opened.
+    pending : Option<Diagnostic>
+
+}
+
+/// Lists all compile and parse diagnostics, grouped by file.
+#[derive(Debug, Clone)]
+pub struct DiagnosticsPanel {
+    pub tree_view : TreeView,
+    store : TreeStore,
+    pub bx : Box,
+    title : PackedImageLabel,
+    state : Rc<RefCell<PanelState>>,
+    on_jump : Callbacks<(usize, usize)>,
+    on_open_request : Callbacks<PathBuf>
+}
+
+// Index column value of rows that group the diagnostics of a file.
+const FILE_ROW : i64 = -1;
+
+impl DiagnosticsPanel {
+
+    pub fn build() -> Self {
+        let tree_view = TreeView::new();
+        tree_view.set_valign(Align::Fill);
+        tree_view.set_vexpand(true);
+        let store = configure_tree_view(&tree_view);
+
+        let title = PackedImageLabel::build("dialog-error-symbolic", "Problems");
+        title.bx.set_vexpand(false);
+        title.bx.set_valign(Align::Start);
+        super::set_border_to_title(&title.bx);
+        let bx = Box::new(Orientation::Vertical, 0);
+
+        let scroll = ScrolledWindow::new();
+        scroll.set_vexpand(true);
+        scroll.set_valign(Align::Fill);
+        scroll.set_child(Some(&tree_view));
+        bx.append(&title.bx);
+        bx.append(&scroll);
+
+        let state : Rc<RefCell<PanelState>> = Default::default();
+        let on_jump : Callbacks<(usize, usize)> = Default::default();
+        let on_open_request : Callbacks<PathBuf> = Default::default();
+
+        tree_view.selection().connect_changed({
+            let state = state.clone();
+            let on_jump = on_jump.clone();
+            let on_open_request = on_open_request.clone();
+            move |sel| {
+                let (model, iter) = match sel.selected() {
+                    Some(selected) => selected,
+                    None => return
+                };
+                let ix = model.get::<i64>(&iter, 2);
+                if ix == FILE_ROW {
+                    return;
+                }
+                // The selection also changes while the rows are being rebuilt.
+                let mut state = match state.try_borrow_mut() {
+                    Ok(state) => state,
+                    Err(_) => return
+                };
+                if let Some(diag) = state.rows.get(ix as usize).cloned() {
+                    if diag.is_at(state.file.as_deref()) || diag.range.is_none() {
+                        if diag.range.is_some() {
+                            on_jump.call((diag.line, diag.column));
+                        }
+                    } else if let Some(path) = diag.path.clone() {
+                        state.pending = Some(diag);
+                        drop(state);
+                        on_open_request.call(path);
+                    }
+                }
+            }
+        });
+
+        Self { tree_view, store, bx, title, state, on_jump, on_open_request }
+    }
+
+    /// Called with the zero-based line and column of a diagnostic selected at the
+    /// document currently open.
+    pub fn connect_jump<F>(&self, f : F)
+    where
+        F : Fn((usize, usize)) + 'static
+    {
+        self.on_jump.bind(f);
+    }
+
+    /// Called with the path of a file that must be opened to show a diagnostic selected
+    /// by the user. The jump is emitted once the file is opened.
+    pub fn connect_open_request<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.on_open_request.bind(f);
+    }
+
+}
+
+fn configure_tree_view(tree_view : &TreeView) -> TreeStore {
+    let model = TreeStore::new(&[Type::STRING, Type::STRING, Type::I64]);
+    tree_view.set_model(Some(&model));
+    let pix_renderer = CellRendererPixbuf::new();
+    pix_renderer.set_padding(6, 6);
+    let txt_renderer = CellRendererText::new();
+    txt_renderer.set_wrap_mode(pango::WrapMode::WordChar);
+    txt_renderer.set_wrap_width(240);
+
+    let pix_col = TreeViewColumn::new();
+    pix_col.pack_start(&pix_renderer, false);
+    pix_col.add_attribute(&pix_renderer, "icon-name", 0);
+
+    let txt_col = TreeViewColumn::new();
+    txt_col.pack_start(&txt_renderer, true);
+    txt_col.add_attribute(&txt_renderer, "text", 1);
+
+    tree_view.append_column(&pix_col);
+    tree_view.append_column(&txt_col);
+    tree_view.set_show_expanders(true);
+    tree_view.set_can_focus(false);
+    tree_view.set_has_tooltip(false);
+    tree_view.set_headers_visible(false);
+    model
+}
+
+fn file_label(path : Option<&Path>, curr_file : Option<&Path>) -> String {
+    match path {
+        Some(path) => path.file_name()
+            .and_then(|name| name.to_str() )
+            .map(|name| name.to_string() )
+            .unwrap_or_else(|| path.display().to_string() ),
+        None => if curr_file.is_some() {
+            String::from("General")
+        } else {
+            String::from("Untitled document")
+        }
+    }
+}
+
+fn row_label(diag : &Diagnostic) -> String {
+    let mut label = if diag.range.is_some() {
+        format!("Line {}: {}", diag.line + 1, diag.message)
+    } else {
+        diag.message.clone()
+    };
+    for hint in diag.hints.iter() {
+        label += "\n";
+        label += hint;
+    }
+    label
+}
+
+fn update_panel(store : &TreeStore, title : &PackedImageLabel, tree_view : &TreeView, state : &mut PanelState) {
+    store.clear();
+    let mut rows : Vec<Diagnostic> = state.compile.iter()
+        .chain(state.parse.iter())
+        .chain(state.lint.iter())
+        .cloned()
+        .collect();
+
+    // Diagnostics of the document being edited come first, then those of other files.
+    let curr_file = state.file.clone();
+    rows.sort_by(|a, b| {
+        let a_curr = !a.is_at(curr_file.as_deref());
+        let b_curr = !b.is_at(curr_file.as_deref());
+        (a_curr, &a.path, a.line, a.column).cmp(&(b_curr, &b.path, b.line, b.column))
+    });
+
+    let mut parent : Option<(Option<PathBuf>, TreeIter)> = None;
+    for (ix, diag) in rows.iter().enumerate() {
+        let same_file = parent.as_ref().map(|(path, _)| path == &diag.path ).unwrap_or(false);
+        if !same_file {
+            let file_iter = store.append(None);
+            let label = file_label(diag.path.as_deref(), curr_file.as_deref());
+            store.set(&file_iter, &[(0, &"text-x-generic-symbolic"), (1, &label), (2, &FILE_ROW)]);
+            parent = Some((diag.path.clone(), file_iter));
+        }
+        let icon = match diag.severity {
+            Severity::Error => "dialog-error-symbolic",
+            Severity::Warning => "dialog-warning-symbolic"
+        };
+        let iter = store.append(parent.as_ref().map(|(_, iter)| iter ));
+        store.set(&iter, &[(0, &icon), (1, &row_label(diag)), (2, &(ix as i64))]);
+    }
+
+    let n_errors = rows.iter().filter(|d| d.is_error() ).count();
+    let n_warnings = rows.len() - n_errors;
+    if rows.is_empty() {
+        title.change_label("Problems");
+    } else {
+        title.change_label(&format!("Problems ({} errors, {} warnings)", n_errors, n_warnings));
+    }
+    state.rows = rows;
+    tree_view.expand_all();
+}
+
+impl React<Typesetter> for DiagnosticsPanel {
+
+    fn react(&self, typesetter : &Typesetter) {
+        typesetter.connect_error({
+            let panel = self.clone();
+            move |diagnostics| {
+                let mut state = panel.state.borrow_mut();
+                state.compile = diagnostics;
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+            }
+        });
+        typesetter.connect_done({
+            let panel = self.clone();
+            move |_| {
+                let mut state = panel.state.borrow_mut();
+                if !state.compile.is_empty() {
+                    state.compile.clear();
+                    update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+                }
+            }
+        });
+    }
+
+}
+
+impl React<Analyzer> for DiagnosticsPanel {
+
+    fn react(&self, analyzer : &Analyzer) {
+        analyzer.connect_doc_error({
+            let panel = self.clone();
+            move |diagnostics| {
+                let mut state = panel.state.borrow_mut();
+                state.parse = diagnostics;
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+            }
+        });
+        analyzer.connect_doc_changed({
+            let panel = self.clone();
+            move |_| {
+                let mut state = panel.state.borrow_mut();
+                if !state.parse.is_empty() {
+                    state.parse.clear();
+                    update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+                }
+            }
+        });
+        analyzer.connect_lint({
+            let panel = self.clone();
+            move |diagnostics| {
+                let mut state = panel.state.borrow_mut();
+                state.lint = diagnostics;
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+            }
+        });
+    }
+
+}
+
+impl React<FileManager> for DiagnosticsPanel {
+
+    fn react(&self, manager : &FileManager) {
+        manager.connect_opened({
+            let panel = self.clone();
+            move |(path, _)| {
+                let mut state = panel.state.borrow_mut();
+                state.file = Some(PathBuf::from(&path));
+
+                // Compile diagnostics carry their own paths, so they stay valid until the
+                // next typesetting. Parse diagnostics refer to the previous buffer.
+                state.parse.clear();
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+
+                if let Some(diag) = state.pending.take() {
+                    if diag.is_at(state.file.as_deref()) {
+
+                        // Jump only after the editor received the new file content.
+                        let on_jump = panel.on_jump.clone();
+                        glib::idle_add_local_once(move || {
+                            on_jump.call((diag.line, diag.column));
+                        });
+                    }
+                }
+            }
+        });
+        manager.connect_save({
+            let panel = self.clone();
+            move |path| {
+                let mut state = panel.state.borrow_mut();
+                state.file = Some(PathBuf::from(&path));
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+            }
+        });
+        manager.connect_new({
+            let panel = self.clone();
+            move |_| {
+                let mut state = panel.state.borrow_mut();
+                *state = PanelState::default();
+                update_panel(&panel.store, &panel.title, &panel.tree_view, &mut state);
+            }
+        });
+    }
+
+}
+
@@ -7,12 +7,13 @@ use gtk4::*;
 use gtk4::prelude::*;
 use super::*;
 use crate::analyzer::Analyzer;
-use crate::tex::{Difference, Token, Command, CommandArg, Object, ObjectIndex};
+use crate::tex::{Difference, Token, Command, CommandArg, Object, Heading, Item};
 use gio::prelude::*;
-use crate::tex::Subsection;
-use crate::tex::Section;
-use either::Either;
 use gdk_pixbuf::Pixbuf;
+use crate::typesetter::Typesetter;
+use crate::typst_tools::outline::Outline;
+use crate::typst_tools::preview::Location;
+use crate::typst_tools::stats::TextStats;
 
 #[derive(Debug, Clone)]
 pub struct DocIcons {
@@ -30,9 +31,27 @@ pub struct DocTree {
     pub tree_view : TreeView,
     store : TreeStore,
     pub bx : Box,
-    doc_icons : DocIcons
+    doc_icons : DocIcons,
+
+    // Document model shown by the tree, and where its items were laid out at the last typesetting.
+    doc : Rc<RefCell<crate::tex::Document>>,
+    outline : Rc<RefCell<Outline>>,
+
+    on_location_selected : Callbacks<Location>
 }
 
+// Columns of the tree model: icon, label shown, page, name of the item (without numbering),
+// word count and tooltip (with all counts) of headings.
+const LABEL_COL : u32 = 1;
+
+const PAGE_COL : u32 = 2;
+
+const NAME_COL : u32 = 3;
+
+const WORDS_COL : u32 = 4;
+
+const TOOLTIP_COL : u32 = 5;
+
 impl DocTree {
 
     pub fn build() -> Self {
@@ -86,12 +105,60 @@ impl DocTree {
             err_icon : icons.remove("dialog-error-symbolic").unwrap(),
             bib_icon : icons.remove("user-bookmarks-symbolic").unwrap(),
         };
-        Self { tree_view, bx, store, doc_icons }
+        let doc : Rc<RefCell<crate::tex::Document>> = Default::default();
+        let outline : Rc<RefCell<Outline>> = Default::default();
+        let on_location_selected : Callbacks<Location> = Default::default();
+        tree_view.selection().connect_changed({
+            let doc = doc.clone();
+            let outline = outline.clone();
+            let on_location_selected = on_location_selected.clone();
+            move |sel| {
+                if let Some((model, iter)) = sel.selected() {
+                    let ixs : Vec<usize> = model.path(&iter).indices().iter().map(|ix| *ix as usize ).collect();
+                    let loc = doc.borrow().get_line(&ixs[..]).and_then(|line| outline.borrow().location_at(line) );
+                    if let Some(loc) = loc {
+                        on_location_selected.call(loc);
+                    }
+                }
+            }
+        });
+        Self { tree_view, bx, store, doc_icons, doc, outline, on_location_selected }
     }
+
+    /// Called with where the item selected by the user was laid out at the last typesetting.
+    pub fn connect_location_selected<F>(&self, f : F)
+    where
+        F : Fn(Location) + 'static
+    {
+        self.on_location_selected.bind(f);
+    }
+
+}
+
+// Shows the numbering typst gave to each heading and the page of each item. Items are
+// found at the document model by their position at the tree, which mirrors the model.
+// While the text was changed after the last typesetting, the lines of the outline might
+// point to other items, so the numbering and pages are cleared until it is typeset again.
+fn label_items(store : &TreeStore, doc : &crate::tex::Document, outline : &Outline) {
+    let stale = Outline::default();
+    let outline = if outline.is_from(doc.text_hash) { outline } else { &stale };
+    store.foreach(|model, path, iter| {
+        let ixs : Vec<usize> = path.indices().iter().map(|ix| *ix as usize ).collect();
+        if let Some(line) = doc.get_line(&ixs[..]) {
+            let name = model.get::<String>(iter, NAME_COL as i32);
+            let label = match outline.heading_at(line).and_then(|h| h.numbering.as_ref() ) {
+                Some(numbering) if !numbering.is_empty() => format!("{} {}", numbering, name),
+                _ => name
+            };
+            let page = outline.page_at(line).map(|p| p.to_string() ).unwrap_or_default();
+            store.set(iter, &[(LABEL_COL, &label), (PAGE_COL, &page)]);
+        }
+        false
+    });
 }
 
 fn configure_tree_view(tree_view : &TreeView) -> TreeStore {
-    let model = TreeStore::new(&[Pixbuf::static_type(), Type::STRING]);
+    let model = TreeStore::new(&[Pixbuf::static_type(), Type::STRING, Type::STRING, Type::STRING, Type::STRING, Type::STRING]);
     tree_view.set_model(Some(&model));
     let pix_renderer = CellRendererPixbuf::new();
     pix_renderer.set_padding(6, 6);
@@ -105,47 +172,75 @@ fn configure_tree_view(tree_view : &TreeView) -> TreeStore {
     txt_col.pack_start(&txt_renderer, true);
     txt_col.add_attribute(&txt_renderer, "text", 1);
 
+    // Words of each heading.
+    let words_renderer = CellRendererText::new();
+    words_renderer.set_xalign(1.0);
+    words_renderer.set_property("foreground", "#808080");
+    words_renderer.set_property("scale", 0.85);
+    let words_col = TreeViewColumn::new();
+    words_col.pack_end(&words_renderer, false);
+    words_col.add_attribute(&words_renderer, "text", WORDS_COL as i32);
+
+    // Page of each item at the last typesetting.
+    let page_renderer = CellRendererText::new();
+    page_renderer.set_xalign(1.0);
+    page_renderer.set_padding(6, 0);
+    page_renderer.set_property("foreground", "#808080");
+    let page_col = TreeViewColumn::new();
+    page_col.pack_end(&page_renderer, false);
+    page_col.add_attribute(&page_renderer, "text", PAGE_COL as i32);
+
     tree_view.append_column(&pix_col);
     tree_view.append_column(&txt_col);
+    tree_view.append_column(&words_col);
+    tree_view.append_column(&page_col);
     tree_view.set_show_expanders(true);
     tree_view.set_can_focus(false);
-    tree_view.set_has_tooltip(false);
+    tree_view.set_tooltip_column(TOOLTIP_COL as i32);
     tree_view.set_headers_visible(false);
     model
 }
 
-fn insert_section(iter : TreeIter, store : &TreeStore, sec : Section, icons : &DocIcons) {
-    store.set(&iter, &[(0, &icons.section_icon), (1, &sec.name)]);
+// Description of the counts of a heading, shown as its tooltip.
+fn stats_tooltip(stats : &TextStats) -> String {
+    format!(
+        "{} words, {} characters, {} paragraphs ({} min read)",
+        stats.words,
+        stats.chars,
+        stats.paragraphs,
+        stats.reading_minutes()
+    )
 }
 
-fn insert_subsection(iter : TreeIter, store : &TreeStore, sub : Subsection, icons : &DocIcons) {
-    store.set(&iter, &[(0, &icons.section_icon), (1, &sub.name)]);
+fn insert_heading(iter : TreeIter, store : &TreeStore, heading : &Heading, icons : &DocIcons) {
+    store.set(&iter, &[
+        (0, &icons.section_icon),
+        (LABEL_COL, &heading.name),
+        (NAME_COL, &heading.name),
+        (WORDS_COL, &heading.stats.words.to_string()),
+        (TOOLTIP_COL, &stats_tooltip(&heading.stats))
+    ]);
 }
 
-fn insert_object(iter : TreeIter, store : &TreeStore, tree_view : &TreeView, obj : Object, doc_ix : ObjectIndex, icons : &DocIcons) {
+fn insert_object(iter : TreeIter, store : &TreeStore, obj : &Object, icons : &DocIcons) {
     let (icon, name) = match obj {
         Object::Table(order, _, _) => (&icons.tbl_icon, format!("Table {}", order)),
         Object::Image(order, _, _) => (&icons.img_icon, format!("Image {}", order)),
         Object::Equation(order, _, _) => (&icons.eq_icon, format!("Equation {}", order)),
         Object::Code(order, _, _) => (&icons.code_icon, format!("Listing {}", order)),
+        Object::Figure(order, _, _) => (&icons.img_icon, format!("Figure {}", order)),
         Object::Bibliography(_, _) => (&icons.code_icon, format!("Bibliography")),
     };
-    /*let (parent_iter, pos) = match doc_ix {
-        ObjectIndex::Root(ix) => {
-            (None, ix as i32)
-        },
-        ObjectIndex::Section(sec, obj) => {
-            (Some(tree_view.model().unwrap().iter_nth_child(None, sec as i32).unwrap()), obj as i32)
-        },
-        ObjectIndex::Subsection(sec, sub, obj) => {
-            let model = tree_view.model().unwrap();
-            let sec_iter = model.iter_nth_child(None, sec as i32).unwrap();
-            let subsec_iter = model.iter_nth_child(Some(&sec_iter), sub as i32).unwrap();
-            (Some(subsec_iter), obj as i32)
-        }
-    };*/
-    // let iter = store.insert(parent_iter.as_ref(), pos);
-    store.set(&iter, &[(0, &icon), (1, &name)]);
+    store.set(&iter, &[(0, &icon), (LABEL_COL, &name), (NAME_COL, &name)]);
+    if let Object::Figure(_, _, info) = obj {
+        let tooltip = match (&info.caption, &info.label) {
+            (Some(caption), Some(label)) => format!("{} <{}>", caption, label),
+            (Some(caption), None) => caption.clone(),
+            (None, Some(label)) => format!("<{}>", label),
+            (None, None) => return
+        };
+        store.set(&iter, &[(TOOLTIP_COL, &tooltip)]);
+    }
 }
 
 impl React<Analyzer> for DocTree {
@@ -155,59 +250,29 @@ impl React<Analyzer> for DocTree {
             let store = self.store.clone();
             let doc_icons = self.doc_icons.clone();
             let tree_view = self.tree_view.clone();
+            let doc = self.doc.clone();
+            let outline = self.outline.clone();
             move |new_doc| {
                 store.clear();
 
-                for (ix, tk_ix, item) in new_doc.root_items() {
-                    match item {
-                        Either::Left(sec) => {
-                            let iter = store.append(None);
-                            insert_section(iter, &store, sec, &doc_icons);
-                        },
-                        Either::Right(obj) => {
-                            let iter = store.append(None);
-                            insert_object(iter, &store, &tree_view, obj, ObjectIndex::Root(ix), &doc_icons);
-                        }
-                    }
-                }
-
-                for ([root_ix, sec_ix], tk_ix, item) in new_doc.level_one_items() {
-                    let section_iter = tree_view.model().unwrap().iter_nth_child(None, root_ix as i32).unwrap();
-                    let iter = store.append(Some(&section_iter));
+                // Headings come before the items under them, so the row of the parent
+                // of each item (which mirrors its path at the document) is already there.
+                for (path, item) in new_doc.all_items() {
+                    let parent_path : Vec<i32> = path[..path.len()-1].iter().map(|ix| *ix as i32 ).collect();
+                    let parent = if parent_path.is_empty() {
+                        None
+                    } else {
+                        store.iter(&TreePath::from_indices(&parent_path[..]))
+                    };
+                    let iter = store.append(parent.as_ref());
                     match item {
-                        Either::Left(sub) => {
-                            insert_subsection(iter, &store, sub, &doc_icons);
-                        },
-                        Either::Right(obj) => {
-                            insert_object(iter, &store, &tree_view, obj, ObjectIndex::Section(root_ix, sec_ix), &doc_icons);
-                        }
+                        Item::Heading(heading, _) => insert_heading(iter, &store, heading, &doc_icons),
+                        Item::Object(obj, _) => insert_object(iter, &store, obj, &doc_icons)
                     }
                 }
 
-                for ([root_ix, sec_ix, sub_ix], tk_ix, obj) in new_doc.level_two_items() {
-                    let model = tree_view.model().unwrap();
-                    let sec_iter = model.iter_nth_child(None, root_ix as i32).unwrap();
-                    let subsec_iter = model.iter_nth_child(Some(&sec_iter), sec_ix as i32).unwrap();
-                    let iter = store.append(Some(&subsec_iter));
-                    insert_object(iter, &store, &tree_view, obj, ObjectIndex::Subsection(root_ix, sec_ix, sub_ix), &doc_icons);
-                }
-
-                /*for section in new_doc.sections() {
-                    let iter = store.append(None);
-                    store.set(&iter, &[(0, &section_icon), (1, &section.name)]);
-                }
-
-                for subsection in new_doc.subsections() {
-                    let section_iter = tree_view.model().unwrap().iter_nth_child(None, subsection.parent_index as i32).unwrap();
-                    let iter = store.insert(Some(&section_iter), subsection.local_index as i32);
-                    store.set(&iter, &[(0, &section_icon), (1, &subsection.name)]);
-                }
-
-                for object in new_doc.objects() {
-                    let iter = store.insert(iter.as_ref(), pos);
-                    insert_object(iter, &store, obj, &doc_icons)
-                }*/
-
+                label_items(&store, &new_doc, &outline.borrow());
+                doc.replace(new_doc);
                 tree_view.expand_all();
             }
         });
@@ -256,15 +321,32 @@ impl React<Analyzer> for DocTree {
         analyzer.connect_doc_error({
             let store = self.store.clone();
             let doc_icons = self.doc_icons.clone();
-            let tree_view = self.tree_view.clone();
-            move |err| {
+            let doc = self.doc.clone();
+            move |diagnostics| {
+                doc.replace(Default::default());
                 store.clear();
-                let iter = store.append(None);
-                store.set(&iter, &[(0, &doc_icons.err_icon), (1, &err.msg)]);
+                for diag in diagnostics.iter() {
+                    let iter = store.append(None);
+                    store.set(&iter, &[(0, &doc_icons.err_icon), (1, &diag.to_string())]);
+                }
             }
         });
     }
 
 }
 
+impl React<Typesetter> for DocTree {
 
+    fn react(&self, typesetter : &Typesetter) {
+        typesetter.connect_outline({
+            let store = self.store.clone();
+            let doc = self.doc.clone();
+            let outline = self.outline.clone();
+            move |new_outline| {
+                label_items(&store, &doc.borrow(), &new_outline);
+                outline.replace(new_outline);
+            }
+        });
+    }
+
+}
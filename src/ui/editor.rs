@@ -6,6 +6,9 @@ For a copy, see http://www.gnu.org/licenses.*/
 use super::*;
 use crate::analyzer::Analyzer;
 use glib::signal::SignalHandlerId;
+use crate::diagnostic::{Diagnostic, Severity};
+use std::ops::Range;
+use crate::typst_tools::preview::ClickTarget;
 
 #[derive(Debug, Clone)]
 pub struct PapersEditor {
@@ -17,7 +20,178 @@ pub struct PapersEditor {
     pub buf_change_handler : Rc<RefCell<Option<SignalHandlerId>>>,
     pub curr_toast : Rc<RefCell<Option<libadwaita::Toast>>>,
     pub pdf_viewer : PdfViewer,
-    pub popover : Popover
+    pub popover : Popover,
+    pub diagnostics : Rc<RefCell<EditorDiagnostics>>,
+    pub status_bar : StatusBar,
+
+    // Position in another file (e.g. clicked at the preview), to be reached once that file is opened.
+    pending_jump : Rc<RefCell<Option<(PathBuf, usize, usize)>>>,
+
+    on_open_request : Callbacks<PathBuf>
+}
+
+/// Problems shown as markers over the text. Compile and parse diagnostics are kept
+/// separately, so that a new typesetting does not clear the syntax errors found by
+/// the analyzer (and vice-versa).
+#[derive(Debug, Default)]
+pub struct EditorDiagnostics {
+
+    compile : Vec<Diagnostic>,
+
+    parse : Vec<Diagnostic>,
+
+    lint : Vec<Diagnostic>,
+
+    // File open at the editor. Diagnostics from other files (e.g. included
+    // sources) are not shown as markers.
+    file : Option<PathBuf>,
+
+    // Character range, line and tooltip text of the markers currently shown.
+    shown : Vec<(Range<i32>, i32, String)>
+
+}
+
+impl EditorDiagnostics {
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn all(&self) -> impl Iterator<Item=&Diagnostic> {
+        self.compile.iter().chain(self.parse.iter()).chain(self.lint.iter())
+    }
+
+    fn tooltip_at_offset(&self, offset : i32) -> Option<String> {
+        let txts : Vec<_> = self.shown.iter()
+            .filter(|(chars, _, _)| chars.start <= offset && offset < chars.end )
+            .map(|(_, _, txt)| &txt[..] )
+            .collect();
+        if txts.is_empty() {
+            None
+        } else {
+            Some(txts.join("\n"))
+        }
+    }
+
+    fn tooltip_at_line(&self, line : i32) -> String {
+        self.shown.iter()
+            .filter(|(_, l, _)| *l == line )
+            .map(|(_, _, txt)| &txt[..] )
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+}
+
+const ERROR_TAG : &str = "diagnostic-error";
+
+const WARNING_TAG : &str = "diagnostic-warning";
+
+const ERROR_MARK : &str = "diagnostic-error";
+
+const WARNING_MARK : &str = "diagnostic-warning";
+
+fn configure_diagnostic_markers(view : &View, diagnostics : &Rc<RefCell<EditorDiagnostics>>) {
+    let buffer = view.buffer();
+    let error_tag = TextTag::builder()
+        .name(ERROR_TAG)
+        .underline(pango::Underline::Error)
+        .build();
+    let warning_tag = TextTag::builder()
+        .name(WARNING_TAG)
+        .underline(pango::Underline::Error)
+        .underline_rgba(&gdk::RGBA::new(0.96, 0.47, 0.0, 1.0))
+        .build();
+    buffer.tag_table().add(&error_tag);
+    buffer.tag_table().add(&warning_tag);
+
+    for (category, icon, priority) in [(ERROR_MARK, "dialog-error-symbolic", 2), (WARNING_MARK, "dialog-warning-symbolic", 1)] {
+        let attrs = sourceview5::MarkAttributes::new();
+        attrs.set_icon_name(icon);
+        attrs.connect_query_tooltip_text({
+            let diagnostics = diagnostics.clone();
+            let buffer = buffer.clone();
+            move |_, mark| {
+                let line = buffer.iter_at_mark(mark).line();
+                diagnostics.borrow().tooltip_at_line(line)
+            }
+        });
+        view.set_mark_attributes(category, &attrs, priority);
+    }
+
+    view.set_has_tooltip(true);
+    view.connect_query_tooltip({
+        let diagnostics = diagnostics.clone();
+        move |view, x, y, _, tooltip| {
+            let (bx, by) = view.window_to_buffer_coords(TextWindowType::Widget, x, y);
+            if let Some(iter) = view.iter_at_location(bx, by) {
+                if let Some(txt) = diagnostics.borrow().tooltip_at_offset(iter.offset()) {
+                    tooltip.set_text(Some(&txt));
+                    return true;
+                }
+            }
+            false
+        }
+    });
+}
+
+// Char offset at the text corresponding to a byte offset. None if the byte offset is not
+// valid for the current text (which might have changed since it was typeset or parsed).
+fn char_offset(text : &str, byte : usize) -> Option<i32> {
+    Some(text.get(..byte)?.chars().count() as i32)
+}
+
+fn tooltip_text(diag : &Diagnostic) -> String {
+    let mut txt = diag.message.clone();
+    for hint in diag.hints.iter() {
+        txt += "\n";
+        txt += hint;
+    }
+    txt
+}
+
+/// Replaces the markers at the buffer by the current set of diagnostics.
+fn show_diagnostic_markers(view : &View, diagnostics : &mut EditorDiagnostics) {
+    let buffer = view.buffer().downcast::<sourceview5::Buffer>().unwrap();
+    let (start, end) = buffer.bounds();
+    buffer.remove_tag_by_name(ERROR_TAG, &start, &end);
+    buffer.remove_tag_by_name(WARNING_TAG, &start, &end);
+    buffer.remove_source_marks(&start, &end, Some(ERROR_MARK));
+    buffer.remove_source_marks(&start, &end, Some(WARNING_MARK));
+    diagnostics.shown.clear();
+
+    let text = buffer.text(&start, &end, true).to_string();
+    let file = diagnostics.file.clone();
+    for diag in diagnostics.compile.iter().chain(diagnostics.parse.iter()).chain(diagnostics.lint.iter()) {
+        if !diag.is_at(file.as_deref()) {
+            continue;
+        }
+        let (start_off, end_off) = match &diag.range {
+            Some(range) => match (char_offset(&text, range.start), char_offset(&text, range.end)) {
+                (Some(start_off), Some(end_off)) => (start_off, end_off),
+                _ => continue
+            },
+            None => continue
+        };
+        let mut start_iter = buffer.iter_at_offset(start_off);
+        let mut end_iter = buffer.iter_at_offset(end_off);
+
+        // Problems at a single position (e.g. a missing delimiter) are underlined at
+        // the next character, or at the previous one at the end of the text.
+        if start_off == end_off {
+            if !end_iter.forward_char() {
+                start_iter.backward_char();
+            }
+        }
+
+        let (tag, category) = match diag.severity {
+            Severity::Error => (ERROR_TAG, ERROR_MARK),
+            Severity::Warning => (WARNING_TAG, WARNING_MARK)
+        };
+        buffer.apply_tag_by_name(tag, &start_iter, &end_iter);
+        buffer.create_source_mark(None, category, &start_iter);
+        diagnostics.shown.push((start_iter.offset()..end_iter.offset(), start_iter.line(), tooltip_text(diag)));
+    }
 }
 
 const TEXT_WIDTH : i32 = 820;
@@ -34,6 +208,9 @@ impl PapersEditor {
 
         configure_view(&view);
 
+        let diagnostics : Rc<RefCell<EditorDiagnostics>> = Default::default();
+        configure_diagnostic_markers(&view, &diagnostics);
+
         let scroll = ScrolledWindow::new();
 
         view.set_margin_top(TEXT_VERTICAL_PADDING);
@@ -62,7 +239,14 @@ impl PapersEditor {
         sub_paned.set_shrink_start_child(false);
         sub_paned.set_resize_start_child(false);
         let pdf_viewer = PdfViewer::new(zoom_action);
-        sub_paned.set_start_child(Some(&scroll));
+
+        // The status bar stays under the text, at the side of the preview.
+        let status_bar = StatusBar::build();
+        let text_bx = Box::new(Orientation::Vertical, 0);
+        scroll.set_vexpand(true);
+        text_bx.append(&scroll);
+        text_bx.append(&status_bar.bx);
+        sub_paned.set_start_child(Some(&text_bx));
 
         sub_paned.set_end_child(Some(&pdf_viewer.bx));
 
@@ -73,14 +257,93 @@ impl PapersEditor {
         let curr_toast : Rc<RefCell<Option<libadwaita::Toast>>> = Rc::new(RefCell::new(None));
 
         let popover = Popover::new();
-        Self { scroll, view, overlay, sub_paned, ignore_file_save_action, buf_change_handler : Rc::new(RefCell::new(None)), curr_toast, pdf_viewer, popover }
+        Self {
+            scroll,
+            view,
+            overlay,
+            sub_paned,
+            ignore_file_save_action,
+            buf_change_handler : Rc::new(RefCell::new(None)),
+            curr_toast,
+            pdf_viewer,
+            popover,
+            diagnostics,
+            status_bar,
+            pending_jump : Default::default(),
+            on_open_request : Default::default()
+        }
+    }
+
+    /// Called with the path of a file that must be opened to show the source text clicked
+    /// at the preview (e.g. an included file). The cursor is moved once the file is opened.
+    pub fn connect_open_request<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.on_open_request.bind(f);
+    }
+
+    /// Moves the cursor to a zero-based line and column (in chars) of a file, requesting the
+    /// file to be opened when it is not the one at the editor. A position without a file
+    /// is taken to be at the document currently edited.
+    pub fn jump_to(&self, path : Option<PathBuf>, line : usize, column : usize) {
+        let file = self.diagnostics.borrow().file().map(|f| f.to_owned() );
+        match path {
+            Some(path) if Some(&path) != file.as_ref() => {
+                *self.pending_jump.borrow_mut() = Some((path.clone(), line, column));
+                self.on_open_request.call(path);
+            },
+            _ => {
+                move_cursor_to(&self.view, &self.popover, line, column);
+            }
+        }
     }
+
 }
 
 impl React<FileManager> for PapersEditor {
 
     fn react(&self, manager : &FileManager) {
         filecase::connect_manager_to_editor(manager, &self.view, &self.buf_change_handler);
+        manager.connect_opened({
+            let view = self.view.clone();
+            let popover = self.popover.clone();
+            let diagnostics = self.diagnostics.clone();
+            let pending_jump = self.pending_jump.clone();
+            move |(path, _)| {
+                let path = PathBuf::from(path);
+                let mut diagnostics = diagnostics.borrow_mut();
+                *diagnostics = EditorDiagnostics::default();
+                diagnostics.file = Some(path.clone());
+                show_diagnostic_markers(&view, &mut diagnostics);
+
+                if let Some((file, line, column)) = pending_jump.borrow_mut().take() {
+                    if file == path {
+
+                        // Jump only after the editor received the new file content.
+                        let (view, popover) = (view.clone(), popover.clone());
+                        glib::idle_add_local_once(move || {
+                            move_cursor_to(&view, &popover, line, column);
+                        });
+                    }
+                }
+            }
+        });
+        manager.connect_new({
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
+            move |_| {
+                let mut diagnostics = diagnostics.borrow_mut();
+                *diagnostics = EditorDiagnostics::default();
+                show_diagnostic_markers(&view, &mut diagnostics);
+            }
+        });
+        manager.connect_save({
+            let diagnostics = self.diagnostics.clone();
+            move |path| {
+                diagnostics.borrow_mut().file = Some(PathBuf::from(path));
+            }
+        });
         manager.connect_close_confirm({
             let overlay = self.overlay.clone();
             let curr_toast = self.curr_toast.clone();
@@ -126,16 +389,86 @@ impl React<FileManager> for PapersEditor {
 impl React<Typesetter> for PapersEditor {
 
     fn react(&self, typesetter : &Typesetter) {
+
+        // Inverse search: moves the cursor to the source text clicked at the preview.
+        typesetter.connect_clicked({
+            let editor = self.clone();
+            move |target| {
+                match target {
+                    ClickTarget::Source { path, line, column } => {
+                        editor.jump_to(path, line, column);
+                    },
+                    ClickTarget::Location(loc) => {
+                        editor.pdf_viewer.show_location(loc, false);
+                    },
+                    ClickTarget::Url(url) => {
+                        gtk4::show_uri(None::<&Window>, &url, 0);
+                    }
+                }
+            }
+        });
+
         typesetter.connect_error({
             let overlay = self.overlay.clone();
             let curr_toast = self.curr_toast.clone();
-            move |e| {
+            move |diagnostics| {
                 let mut last_toast = curr_toast.borrow_mut();
                 if let Some(t) = last_toast.take() {
                     t.dismiss();
                 }
+                let msg = crate::diagnostic::summarize(&diagnostics[..])
+                    .unwrap_or(String::from("Unknown error"));
                 let toast = libadwaita::Toast::builder()
-                    .title(&e)
+                    .title(&msg)
+                    .priority(libadwaita::ToastPriority::High)
+                    .timeout(0)
+                    .build();
+                connect_toast_dismissed(&toast, &curr_toast);
+                overlay.add_toast(&toast);
+                *last_toast = Some(toast);
+            }
+        });
+        typesetter.connect_error({
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
+            move |new_diagnostics| {
+                let mut diagnostics = diagnostics.borrow_mut();
+                diagnostics.compile = new_diagnostics;
+                show_diagnostic_markers(&view, &mut diagnostics);
+            }
+        });
+        typesetter.connect_exported({
+            let overlay = self.overlay.clone();
+            let curr_toast = self.curr_toast.clone();
+            move |paths| {
+                let mut last_toast = curr_toast.borrow_mut();
+                if let Some(t) = last_toast.take() {
+                    t.dismiss();
+                }
+                let msg = match &paths[..] {
+                    [path] => format!("Exported {}", path.display()),
+                    _ => format!("Exported {} files", paths.len())
+                };
+                let toast = libadwaita::Toast::builder()
+                    .title(&msg)
+                    .priority(libadwaita::ToastPriority::Normal)
+                    .timeout(3)
+                    .build();
+                connect_toast_dismissed(&toast, &curr_toast);
+                overlay.add_toast(&toast);
+                *last_toast = Some(toast);
+            }
+        });
+        typesetter.connect_export_error({
+            let overlay = self.overlay.clone();
+            let curr_toast = self.curr_toast.clone();
+            move |msg| {
+                let mut last_toast = curr_toast.borrow_mut();
+                if let Some(t) = last_toast.take() {
+                    t.dismiss();
+                }
+                let toast = libadwaita::Toast::builder()
+                    .title(&msg)
                     .priority(libadwaita::ToastPriority::High)
                     .timeout(0)
                     .build();
@@ -146,10 +479,17 @@ impl React<Typesetter> for PapersEditor {
         });
         typesetter.connect_done({
             let curr_toast = self.curr_toast.clone();
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
             move |_| {
                 if let Some(toast) = &*curr_toast.borrow() {
                     toast.dismiss();
                 }
+                let mut diagnostics = diagnostics.borrow_mut();
+                if !diagnostics.compile.is_empty() {
+                    diagnostics.compile.clear();
+                    show_diagnostic_markers(&view, &mut diagnostics);
+                }
             }
         });
     }
@@ -360,22 +700,18 @@ impl React<Titlebar> for PapersEditor {
             insert_at_cursor_from_action(action, view.clone(), popover.clone(), cmd);
         }
 
-        titlebar.fmt_popover.font_btn.connect_font_set({
+        titlebar.fmt_popover.font_chooser.connect_font_set({
             let view = view.clone();
             let popover = popover.clone();
-            move|btn| {
-                if let Some(font) = btn.font().map(|f| f.to_string() ) {
-                    if let Some(data) = FontData::new_from_string(&font) {
-                        let font_txt = format!(
-                            "#set text(font : \"{}\", style : \"{}\", weight : \"{}\", size : {}pt)",
-                            data.family,
-                            data.style,
-                            data.weight,
-                            data.size
-                        );
-                        insert_at_cursor(view.clone(), popover.clone(), &font_txt);
-                    }
-                }
+            move|data| {
+                let font_txt = format!(
+                    "#set text(font : \"{}\", style : \"{}\", weight : \"{}\", size : {}pt)",
+                    data.family,
+                    data.style,
+                    data.weight,
+                    data.size
+                );
+                insert_at_cursor(view.clone(), popover.clone(), &font_txt);
             }
         });
 
@@ -434,22 +770,70 @@ impl React<Analyzer> for PapersEditor {
         let view = self.view.clone();
         let popover = self.popover.clone();
         analyzer.connect_line_selection(move |line| {
-            let buffer = view.buffer();
-            if let Some(mut iter) = buffer.iter_at_line(line as i32) {
-                popover.popdown();
-                buffer.place_cursor(&iter);
-                view.scroll_to_iter(&mut iter, 0.0, true, 0.0, 0.5);
-                view.grab_focus();
-            } else {
-                eprintln!("No iter at line {}", line);
-            }
+            move_cursor_to(&view, &popover, line, 0);
 
             // view.buffer().place_cursor(&iter);
             // view.buffer().move_mark(&mark, &iter);
         });
+        analyzer.connect_doc_error({
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
+            move |new_diagnostics| {
+                let mut diagnostics = diagnostics.borrow_mut();
+                diagnostics.parse = new_diagnostics;
+                show_diagnostic_markers(&view, &mut diagnostics);
+            }
+        });
+        analyzer.connect_doc_changed({
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
+            move |_| {
+                let mut diagnostics = diagnostics.borrow_mut();
+                if !diagnostics.parse.is_empty() {
+                    diagnostics.parse.clear();
+                    show_diagnostic_markers(&view, &mut diagnostics);
+                }
+            }
+        });
+        analyzer.connect_lint({
+            let view = self.view.clone();
+            let diagnostics = self.diagnostics.clone();
+            move |lints| {
+                let mut diagnostics = diagnostics.borrow_mut();
+                diagnostics.lint = lints;
+                show_diagnostic_markers(&view, &mut diagnostics);
+            }
+        });
     }
 }
 
+// Moves the cursor to a zero-based line and column (in chars), showing it at the editor.
+fn move_cursor_to(view : &View, popover : &Popover, line : usize, column : usize) {
+    let buffer = view.buffer();
+    let iter = buffer.iter_at_line_offset(line as i32, column as i32)
+        .or_else(|| buffer.iter_at_line(line as i32) );
+    if let Some(mut iter) = iter {
+        popover.popdown();
+        buffer.place_cursor(&iter);
+        view.scroll_to_iter(&mut iter, 0.0, true, 0.0, 0.5);
+        view.grab_focus();
+    } else {
+        eprintln!("No iter at line {}", line);
+    }
+}
+
+impl React<DiagnosticsPanel> for PapersEditor {
+
+    fn react(&self, panel : &DiagnosticsPanel) {
+        let view = self.view.clone();
+        let popover = self.popover.clone();
+        panel.connect_jump(move |(line, column)| {
+            move_cursor_to(&view, &popover, line, column);
+        });
+    }
+
+}
+
 fn move_backwards_to_command_start(buffer : &TextBuffer) -> Option<(TextIter, TextIter, String)> {
     let pos = buffer.cursor_position();
     let pos_iter = buffer.iter_at_offset(pos);
@@ -509,6 +893,17 @@ fn extend_citation(citation : &str, new_key : &str) -> Option<String>{
 impl React<BibPopover> for PapersEditor {
 
     fn react(&self, bib_popover : &BibPopover) {
+
+        // Backlinks of the bibliography entries move the cursor to the citation.
+        bib_popover.connect_citation_selected({
+            let editor = self.clone();
+            let popover = bib_popover.popover.clone();
+            move |citation| {
+                popover.popdown();
+                editor.jump_to(citation.path, citation.line, citation.column);
+            }
+        });
+
         let search_entry = bib_popover.search_entry.clone();
         let popover = bib_popover.popover.clone();
         let view = self.view.clone();
@@ -0,0 +1,125 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use gtk4::*;
+use gtk4::prelude::*;
+use super::*;
+use crate::typst_tools::export::{ExportFormat, ExportRequest};
+use stateful::Callbacks;
+
+const FORMAT_CHOICE : &str = "format";
+
+const DPI_CHOICE : &str = "dpi";
+
+/// Save dialog for the typeset document, which writes either a PDF, one image
+/// (PNG or SVG) per page or a web page (HTML). The file name might contain the
+/// patterns {name} (document name), {n} (page number) and {total} (number of pages).
+#[derive(Debug, Clone)]
+pub struct ExportDialog {
+    pub dialog : FileChooserDialog,
+    pub range_entry : Entry,
+
+    // File stem of the document being edited.
+    name : Rc<RefCell<String>>,
+
+    on_export : Callbacks<ExportRequest>
+}
+
+impl ExportDialog {
+
+    pub fn build() -> Self {
+        let save_dialog = filecase::SaveDialog::build(&["*.pdf", "*.png", "*.svg", "*.html"]);
+        let dialog = save_dialog.dialog.clone();
+        dialog.set_title(Some("Export"));
+        dialog.add_choice(FORMAT_CHOICE, "Format", &["pdf", "png", "svg", "html"], &["PDF", "PNG", "SVG", "HTML"]);
+        dialog.set_choice(FORMAT_CHOICE, "pdf");
+        dialog.add_choice(DPI_CHOICE, "Resolution (PNG)", &["72", "150", "300", "600"], &["72 dpi", "150 dpi", "300 dpi", "600 dpi"]);
+        dialog.set_choice(DPI_CHOICE, "300");
+
+        let range_entry = Entry::new();
+        range_entry.set_placeholder_text(Some("All pages"));
+        range_entry.set_tooltip_text(Some("Pages or page ranges separated by commas (e.g. 1-3,5)"));
+        range_entry.set_hexpand(true);
+        let range_lbl = Label::new(Some("Pages"));
+        let pattern_lbl = Label::new(Some("Use {name}, {n} (page) and {total} (pages) at the file name"));
+        pattern_lbl.add_css_class("dim-label");
+        pattern_lbl.set_halign(Align::End);
+        let range_bx = Box::new(Orientation::Horizontal, 12);
+        range_bx.append(&range_lbl);
+        range_bx.append(&range_entry);
+        range_bx.append(&pattern_lbl);
+        super::set_margins(&range_bx, 12, 12);
+        dialog.content_area().append(&range_bx);
+
+        let name = Rc::new(RefCell::new(String::from("untitled")));
+        let on_export : Callbacks<ExportRequest> = Default::default();
+        dialog.connect_response({
+            let range_entry = range_entry.clone();
+            let name = name.clone();
+            let on_export = on_export.clone();
+            move |dialog, resp| {
+                match resp {
+                    ResponseType::Accept => {
+                        if let Some(path) = dialog.file().and_then(|f| f.path() ) {
+                            let req = ExportRequest {
+                                format : selected_format(dialog),
+                                pages : range_entry.text().to_string(),
+                                path : strip_export_extension(&path),
+                                name : name.borrow().clone()
+                            };
+                            on_export.call(req);
+                        } else {
+                            eprintln!("No path available");
+                        }
+                    },
+                    _ => { }
+                }
+            }
+        });
+
+        Self { dialog, range_entry, name, on_export }
+    }
+
+    /// Called with the format, pages and output path chosen by the user.
+    pub fn connect_export<F>(&self, f : F)
+    where
+        F : Fn(ExportRequest) + 'static
+    {
+        self.on_export.bind(f);
+    }
+
+    /// Points the dialog to the directory of the document, unless the user already
+    /// chose where to export it.
+    pub fn init_path(&self, source_path : &str) {
+        if let Some(stem) = Path::new(source_path).file_stem().and_then(|s| s.to_str() ) {
+            *self.name.borrow_mut() = stem.to_string();
+        }
+        init_export_path(&self.dialog, source_path.to_string());
+    }
+
+}
+
+fn selected_format(dialog : &FileChooserDialog) -> ExportFormat {
+    match dialog.choice(FORMAT_CHOICE).as_deref() {
+        Some("png") => {
+            let dpi = dialog.choice(DPI_CHOICE)
+                .and_then(|dpi| dpi.parse::<f32>().ok() )
+                .unwrap_or(300.0);
+            ExportFormat::Png { dpi }
+        },
+        Some("svg") => ExportFormat::Svg,
+        Some("html") => ExportFormat::Html,
+        _ => ExportFormat::Pdf
+    }
+}
+
+// The extension is given by the chosen format, so that the user does not need to edit the
+// file name when changing it.
+fn strip_export_extension(path : &Path) -> PathBuf {
+    match path.extension().and_then(|e| e.to_str() ) {
+        Some("pdf") | Some("png") | Some("svg") | Some("html") => path.with_extension(""),
+        _ => path.to_owned()
+    }
+}
@@ -0,0 +1,278 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use gtk4::*;
+use gtk4::prelude::*;
+use super::*;
+use crate::typst_tools::fonts::{self, FontFamily};
+use crate::typesetter::Typesetter;
+use stateful::{Callbacks, React};
+
+/// Lists the directories searched for fonts, besides the system font directories and the
+/// fonts folder of each project. The directories are kept as the state of the action given
+/// to build (an array of paths), so that changing them here re-indexes the fonts.
+#[derive(Debug, Clone)]
+pub struct FontDirsDialog {
+    pub dialog : Dialog,
+    pub list : ListBox,
+    pub add_btn : Button,
+    pub folder_dialog : FileChooserDialog
+}
+
+impl FontDirsDialog {
+
+    pub fn build(font_dirs_action : &gio::SimpleAction) -> Self {
+        let dialog = Dialog::new();
+        dialog.set_title(Some("Font folders"));
+        dialog.set_modal(true);
+        dialog.set_hide_on_close(true);
+        dialog.set_default_size(480, 320);
+
+        let list = ListBox::new();
+        list.set_selection_mode(SelectionMode::None);
+        list.add_css_class("boxed-list");
+        let placeholder = Label::new(Some("Only system fonts and the fonts folder of the project are used"));
+        placeholder.add_css_class("dim-label");
+        set_margins(&placeholder, 12, 12);
+        list.set_placeholder(Some(&placeholder));
+        let scroll = ScrolledWindow::new();
+        scroll.set_child(Some(&list));
+        scroll.set_vexpand(true);
+
+        let add_btn = Button::with_label("Add folder");
+        add_btn.set_halign(Align::End);
+        let bx = Box::new(Orientation::Vertical, 12);
+        bx.append(&scroll);
+        bx.append(&add_btn);
+        set_margins(&bx, 12, 12);
+        dialog.content_area().append(&bx);
+
+        let folder_dialog = FileChooserDialog::new(
+            Some("Font folder"),
+            None::<&Window>,
+            FileChooserAction::SelectFolder,
+            &[("Cancel", ResponseType::Cancel), ("Add", ResponseType::Accept)]
+        );
+        folder_dialog.set_modal(true);
+        folder_dialog.set_transient_for(Some(&dialog));
+        folder_dialog.connect_response({
+            let font_dirs_action = font_dirs_action.clone();
+            move |folder_dialog, resp| {
+                if resp == ResponseType::Accept {
+                    if let Some(path) = folder_dialog.file().and_then(|f| f.path() ) {
+                        let mut dirs = font_dirs(&font_dirs_action);
+                        let path = path.display().to_string();
+                        if !dirs.contains(&path) {
+                            dirs.push(path);
+                            font_dirs_action.set_state(&dirs.to_variant());
+                        }
+                    }
+                }
+                folder_dialog.hide();
+            }
+        });
+        add_btn.connect_clicked({
+            let folder_dialog = folder_dialog.clone();
+            move |_| {
+                folder_dialog.show();
+            }
+        });
+
+        update_rows(&list, font_dirs_action);
+        font_dirs_action.connect_notify_local(Some("state"), {
+            let list = list.clone();
+            move |action, _| {
+                update_rows(&list, action);
+            }
+        });
+        Self { dialog, list, add_btn, folder_dialog }
+    }
+
+}
+
+fn font_dirs(font_dirs_action : &gio::SimpleAction) -> Vec<String> {
+    font_dirs_action.state().and_then(|s| s.get::<Vec<String>>() ).unwrap_or_default()
+}
+
+fn update_rows(list : &ListBox, font_dirs_action : &gio::SimpleAction) {
+    titlebar::clear_list(list);
+    for dir in font_dirs(font_dirs_action) {
+        let lbl = Label::new(Some(&dir));
+        lbl.set_halign(Align::Start);
+        lbl.set_hexpand(true);
+        lbl.set_ellipsize(pango::EllipsizeMode::Middle);
+        let remove_btn = Button::from_icon_name("user-trash-symbolic");
+        remove_btn.add_css_class("flat");
+        remove_btn.set_tooltip_text(Some("Stop using fonts of this folder"));
+        remove_btn.connect_clicked({
+            let font_dirs_action = font_dirs_action.clone();
+            move |_| {
+                let dirs : Vec<String> = font_dirs(&font_dirs_action).into_iter()
+                    .filter(|d| d != &dir )
+                    .collect();
+                font_dirs_action.set_state(&dirs.to_variant());
+            }
+        });
+        let bx = Box::new(Orientation::Horizontal, 6);
+        bx.append(&lbl);
+        bx.append(&remove_btn);
+        set_margins(&bx, 6, 6);
+        let row = ListBoxRow::new();
+        row.set_child(Some(&bx));
+        list.append(&row);
+    }
+}
+
+/// Chooses among the font families known to the typesetter (including those at font
+/// folders), listing the variants of the selected family. Unlike the GTK font chooser,
+/// it never offers fonts typst would replace by a fallback.
+#[derive(Debug, Clone)]
+pub struct FontChooser {
+    pub btn : MenuButton,
+    pub search_entry : SearchEntry,
+    pub family_list : ListBox,
+    pub variant_list : ListBox,
+    pub size_spin : SpinButton,
+    families : Rc<RefCell<Vec<FontFamily>>>,
+    on_font_set : Callbacks<FontData>
+}
+
+impl FontChooser {
+
+    pub fn build() -> Self {
+        let search_entry = SearchEntry::new();
+        search_entry.set_placeholder_text(Some("Search fonts"));
+
+        let family_list = ListBox::new();
+        family_list.set_selection_mode(SelectionMode::Browse);
+        let family_placeholder = Label::new(Some("Fonts are being indexed"));
+        family_placeholder.add_css_class("dim-label");
+        family_list.set_placeholder(Some(&family_placeholder));
+        let variant_list = ListBox::new();
+        variant_list.set_selection_mode(SelectionMode::None);
+        variant_list.set_activate_on_single_click(true);
+
+        let scroll_lists = [&family_list, &variant_list].map(|list| {
+            let scroll = ScrolledWindow::new();
+            scroll.set_child(Some(list));
+            scroll.set_size_request(200, 240);
+            scroll.set_has_frame(true);
+            scroll
+        });
+        let lists_bx = Box::new(Orientation::Horizontal, 6);
+        lists_bx.append(&scroll_lists[0]);
+        lists_bx.append(&scroll_lists[1]);
+
+        let size_spin = SpinButton::with_range(4.0, 96.0, 1.0);
+        size_spin.set_value(12.0);
+        let size_bx = Box::new(Orientation::Horizontal, 6);
+        size_bx.append(&Label::new(Some("Size (pt)")));
+        size_bx.append(&size_spin);
+        size_bx.set_halign(Align::End);
+
+        let bx = Box::new(Orientation::Vertical, 6);
+        bx.append(&search_entry);
+        bx.append(&lists_bx);
+        bx.append(&size_bx);
+        let popover = Popover::new();
+        popover.set_child(Some(&bx));
+        let btn = MenuButton::new();
+        btn.set_label("Choose font");
+        btn.set_popover(Some(&popover));
+
+        let families : Rc<RefCell<Vec<FontFamily>>> = Default::default();
+        let on_font_set : Callbacks<FontData> = Default::default();
+
+        family_list.set_filter_func({
+            let families = families.clone();
+            let search_entry = search_entry.clone();
+            move |row| {
+                let query = search_entry.text().to_lowercase();
+                families.borrow().get(row.index() as usize)
+                    .map(|f| f.name.to_lowercase().contains(&query) )
+                    .unwrap_or(false)
+            }
+        });
+        search_entry.connect_search_changed({
+            let family_list = family_list.clone();
+            move |_| {
+                family_list.invalidate_filter();
+            }
+        });
+        family_list.connect_row_selected({
+            let families = families.clone();
+            let variant_list = variant_list.clone();
+            move |_, row| {
+                titlebar::clear_list(&variant_list);
+                let Some(row) = row else { return };
+                if let Some(family) = families.borrow().get(row.index() as usize) {
+                    for variant in family.variants.iter() {
+                        let lbl = Label::new(Some(&fonts::variant_label(variant)));
+                        lbl.set_halign(Align::Start);
+                        set_margins(&lbl, 6, 6);
+                        variant_list.append(&lbl);
+                    }
+                }
+            }
+        });
+        variant_list.connect_row_activated({
+            let families = families.clone();
+            let family_list = family_list.clone();
+            let size_spin = size_spin.clone();
+            let popover = popover.clone();
+            let on_font_set = on_font_set.clone();
+            move |_, row| {
+                let Some(family_row) = family_list.selected_row() else { return };
+                let families = families.borrow();
+                let Some(family) = families.get(family_row.index() as usize) else { return };
+                if let Some(variant) = family.variants.get(row.index() as usize) {
+                    let data = FontData {
+                        family : family.name.clone(),
+                        weight : fonts::weight_name(variant.weight.to_number()).to_string(),
+                        style : fonts::style_name(variant.style).to_string(),
+                        size : size_spin.value_as_int().to_string()
+                    };
+                    popover.popdown();
+                    on_font_set.call(data);
+                }
+            }
+        });
+
+        Self { btn, search_entry, family_list, variant_list, size_spin, families, on_font_set }
+    }
+
+    /// Called with the family, variant and size chosen by the user.
+    pub fn connect_font_set<F>(&self, f : F)
+    where
+        F : Fn(FontData) + 'static
+    {
+        self.on_font_set.bind(f);
+    }
+
+    fn set_families(&self, families : Vec<FontFamily>) {
+        titlebar::clear_list(&self.variant_list);
+        titlebar::clear_list(&self.family_list);
+        for family in families.iter() {
+            let lbl = Label::new(Some(&family.name));
+            lbl.set_halign(Align::Start);
+            set_margins(&lbl, 6, 6);
+            self.family_list.append(&lbl);
+        }
+        *self.families.borrow_mut() = families;
+        self.family_list.invalidate_filter();
+    }
+
+}
+
+impl React<Typesetter> for FontChooser {
+
+    fn react(&self, typesetter : &Typesetter) {
+        let chooser = self.clone();
+        typesetter.connect_fonts_changed(move |families| {
+            chooser.set_families(families);
+        });
+    }
+
+}
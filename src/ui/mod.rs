@@ -5,7 +5,7 @@ For a copy, see http://www.gnu.org/licenses.*/
 
 use gtk4::*;
 use gtk4::prelude::*;
-use stateful::React;
+use stateful::{Callbacks, React};
 use sourceview5::*;
 use sourceview5::prelude::ViewExt;
 use sourceview5::prelude::BufferExt;
@@ -19,7 +19,9 @@ use gdk_pixbuf::Pixbuf;
 use std::path::{PathBuf, Path};
 use filecase::SingleArchiverImpl;
 use filecase::{OpenDialog, SaveDialog};
-use poppler::Document;
+use crate::PreviewDoc;
+use crate::typst_tools::preview::{Location, Preview, MAX_PREVIEW_SCALE};
+use std::time::Duration;
 use either::Either;
 use crate::state::PapersState;
 
@@ -29,25 +31,45 @@ mod titlebar;
 
 mod editor;
 
+mod diagnostics;
+
+mod export;
+
+mod fonts;
+
+mod statusbar;
+
 pub use titlebar::*;
 
+pub use diagnostics::*;
+
+pub use export::*;
+
+pub use fonts::*;
+
 pub use doctree::*;
 
 pub use editor::*;
 
+pub use statusbar::*;
+
 #[derive(Debug, Clone)]
 pub struct PapersWindow {
     pub window : ApplicationWindow,
     pub titlebar : Titlebar,
     pub editor : PapersEditor,
     pub doc_tree : DocTree,
+    pub diagnostics_panel : DiagnosticsPanel,
     pub stack : Stack,
     pub start_screen : StartScreen,
-    pub export_pdf_dialog : SaveDialog,
+    pub export_dialog : ExportDialog,
     pub import_csv_dialog : OpenDialog,
     pub import_img_dialog : OpenDialog,
     pub import_bib_dialog : OpenDialog,
     pub import_src_dialog : OpenDialog,
+
+    // Holds the project roots chosen for each document.
+    state : PapersState
 }
 
 const EMPTY_TEMPLATE : &'static str = r#""#;
@@ -102,7 +124,7 @@ const ARTICLE_TEMPLATE : &'static str = r#"
 const MINIMAL_TEMPLATE : &'static str = r#"
 #set page(paper: "a4", margin: 2.0cm)
 #set par(leading : 0.98em, first-line-indent : 15pt)
-#set text(font : "Liberation Serif", style : "normal", weight : "regular", size : 12pt)
+#set text(font : "Linux Libertine", style : "normal", weight : "regular", size : 12pt)
 "#;
 
 const REPORT_TEMPLATE : &'static str = r#""#;
@@ -118,7 +140,7 @@ const PRESENTATION_TEMPLATE : &'static str = r#"
     numbering : "1",
     number-align:right
 )
-#set text(size : 18pt, font : "Liberation Sans")
+#set text(size : 18pt, font : "New Computer Modern")
 #show heading.where(level : 1) : head => {
     pagebreak();
     head
@@ -149,6 +171,9 @@ fn start_document(view : &View, stack : &Stack, titlebar : &Titlebar, template :
     titlebar.main_menu.actions.save_as.set_enabled(true);
     titlebar.view_pdf_btn.set_active(false);
     titlebar.view_pdf_btn.set_sensitive(true);
+
+    // Documents can be typeset before they are saved to a file.
+    titlebar.pdf_btn.set_sensitive(true);
     titlebar.set_edit(true);
 }
 
@@ -496,8 +521,9 @@ impl PapersWindow {
         window.set_titlebar(Some(&titlebar.header));
         window.set_decorated(true);
         let doc_tree = DocTree::build();
+        let diagnostics_panel = DiagnosticsPanel::build();
         let editor = PapersEditor::build(&titlebar.zoom_action);
-        let start_screen = StartScreen::build(state);
+        let start_screen = StartScreen::build(state.clone());
         start_screen.recent_list.open_btn.connect_clicked({
             let open_action = titlebar.main_menu.actions.open.clone();
             move|_| {
@@ -505,8 +531,8 @@ impl PapersWindow {
             }
         });
 
-        let export_pdf_dialog = filecase::SaveDialog::build(&["*.pdf"]);
-        export_pdf_dialog.dialog.set_transient_for(Some(&window));
+        let export_dialog = ExportDialog::build();
+        export_dialog.dialog.set_transient_for(Some(&window));
 
         let import_csv_dialog = filecase::OpenDialog::build(&["*.csv"]);
         import_csv_dialog.dialog.set_transient_for(Some(&window));
@@ -524,32 +550,12 @@ impl PapersWindow {
         show_on_action(&titlebar.object_actions.source, &import_src_dialog.dialog);
         show_on_action(&titlebar.object_actions.table, &import_csv_dialog.dialog);
         show_on_action(&titlebar.object_actions.bibfile, &import_bib_dialog.dialog);
-        show_on_action(&titlebar.main_menu.export_action, &export_pdf_dialog.dialog);
-
-        export_pdf_dialog.dialog.connect_response({
-            let doc = editor.pdf_viewer.doc.clone();
-            move |dialog, resp| {
-                match resp {
-                    ResponseType::Accept => {
-                        if let Some(path) = dialog.file().and_then(|f| f.path() ) {
-                            if let Some(doc) = &*doc.borrow() {
-                                if let Err(e) = doc.save(&format!("file://{}", path.to_str().unwrap())) {
-                                    eprintln!("Document save error: {}", e);
-                                }
-                            } else {
-                                eprintln!("No current document to export");
-                            }
-                        } else {
-                            eprintln!("No path available");
-                        }
-                    },
-                    _ => { }
-                }
-            }
-        });
+        show_on_action(&titlebar.main_menu.export_action, &export_dialog.dialog);
 
         titlebar.main_menu.save_dialog.dialog.set_transient_for(Some(&window));
         titlebar.main_menu.open_dialog.dialog.set_transient_for(Some(&window));
+        titlebar.main_menu.root_dialog.set_transient_for(Some(&window));
+        titlebar.main_menu.font_dirs_dialog.dialog.set_transient_for(Some(&window));
         titlebar.react(&editor.pdf_viewer);
 
         // Keeps pdf paned hidden due to window changes. Maybe move to impl React<MainWindow> for Editor?
@@ -609,7 +615,17 @@ impl PapersWindow {
         window.add_action(&titlebar.main_menu.actions.save);
         window.add_action(&titlebar.main_menu.actions.save_as);
         window.add_action(&titlebar.main_menu.export_action);
+        window.add_action(&titlebar.main_menu.live_preview_action);
+        window.add_action(&titlebar.main_menu.preview_delay_action);
+        window.add_action(&titlebar.main_menu.watch_action);
+        window.add_action(&titlebar.main_menu.follow_cursor_action);
+        window.add_action(&titlebar.main_menu.project_root_action);
+        window.add_action(&titlebar.main_menu.choose_root_action);
+        window.add_action(&titlebar.main_menu.font_dirs_action);
+        window.add_action(&titlebar.main_menu.show_font_dirs_action);
         window.add_action(&titlebar.typeset_action);
+        window.add_action(&titlebar.cancel_typesetting_action);
+        window.add_action(&titlebar.show_in_preview_action);
 
         window.add_action(&titlebar.sidebar_hide_action);
         window.add_action(&titlebar.zoom_action);
@@ -639,7 +655,18 @@ impl PapersWindow {
         // editor.popover.set_pointing_to(Some(&titlebar.explore_toggle.allocation()));
         titlebar.explore_toggle.set_popover(Some(&editor.popover));
 
-        editor.popover.set_child(Some(&doc_tree.bx));
+        // The outline and the list of problems share the popover.
+        let sidebar_stack = Stack::new();
+        sidebar_stack.add_titled(&doc_tree.bx, Some("outline"), "Outline");
+        sidebar_stack.add_titled(&diagnostics_panel.bx, Some("problems"), "Problems");
+        let sidebar_switcher = StackSwitcher::new();
+        sidebar_switcher.set_stack(Some(&sidebar_stack));
+        sidebar_switcher.set_halign(Align::Center);
+        set_margins(&sidebar_switcher, 6, 6);
+        let sidebar_bx = Box::new(Orientation::Vertical, 0);
+        sidebar_bx.append(&sidebar_switcher);
+        sidebar_bx.append(&sidebar_stack);
+        editor.popover.set_child(Some(&sidebar_bx));
         editor.popover.set_position(PositionType::Bottom);
         editor.popover.set_width_request(320);
         editor.popover.set_height_request(640);
@@ -674,13 +701,15 @@ impl PapersWindow {
             titlebar,
             editor,
             doc_tree,
+            diagnostics_panel,
             stack,
             start_screen,
-            export_pdf_dialog,
+            export_dialog,
             import_csv_dialog,
             import_img_dialog,
             import_bib_dialog,
-            import_src_dialog
+            import_src_dialog,
+            state
         }
     }
 
@@ -698,6 +727,7 @@ fn show_on_action(action : &gio::SimpleAction, dialog : &FileChooserDialog) {
 fn write_on_import(
     view : sourceview5::View,
     dialog : &OpenDialog,
+    main_menu : &MainMenu,
     manager : &FileManager,
     prefix : Either<&'static str, Rc<dyn Fn(&Path)->String + 'static>>,
     suffix : Either<&'static str, Rc<dyn Fn(&Path)->String + 'static>>
@@ -723,21 +753,15 @@ fn write_on_import(
     });
     dialog.dialog.connect_response({
         let curr_path = curr_path.clone();
+        let main_menu = main_menu.clone();
         move |dialog, resp| {
             match resp {
                 ResponseType::Accept => {
                     let Some(path) = dialog.file().and_then(|f| f.path() ) else { return };
-                    let res_path = if let Some(src_parent) = curr_path.borrow().as_ref()
-                        .and_then(|src_path| src_path.parent().to_owned() )
-                    {
-                        if let Ok(stripped) = path.strip_prefix(src_parent) {
-                            stripped.to_owned()
-                        } else {
-                            path.to_owned()
-                        }
-                    } else {
-                        path.to_owned()
-                    };
+                    let curr_path = curr_path.borrow();
+                    let root = curr_path.as_deref()
+                        .and_then(|src_path| crate::typst_tools::project_root(src_path, main_menu.configured_root().as_deref()) );
+                    let res_path = crate::typst_tools::document_path(&path, curr_path.as_deref(), root.as_deref());
 
                     let prefix = match prefix.clone() {
                         Either::Left(pre) => pre.to_string(),
@@ -747,7 +771,7 @@ fn write_on_import(
                         Either::Left(suff) => suff.to_string(),
                         Either::Right(f) => f(&path)
                     };
-                    let txt = format!("{}\"{}\"{}", prefix, res_path.to_str().unwrap(), suffix);
+                    let txt = format!("{}\"{}\"{}", prefix, res_path, suffix);
                     let buffer = view.buffer();
                     buffer.insert_at_cursor(&txt);
                     view.grab_focus();
@@ -768,12 +792,14 @@ impl React<FileManager> for PapersWindow {
             let window = self.window.clone();
             let paned = self.editor.sub_paned.clone();
             let bib_list = self.titlebar.bib_popover.list.clone();
+            let pdf_viewer = self.editor.pdf_viewer.clone();
             move |_| {
                 window.set_title(Some("Drafts"));
                 paned.set_position(i32::MAX);
                 stack.set_visible_child_name("start");
                 titlebar.set_prepared(false);
                 titlebar.clear_pages();
+                pdf_viewer.clear_pages();
                 titlebar::clear_list(&bib_list);
                 titlebar::create_init_row(&bib_list);
 
@@ -784,31 +810,24 @@ impl React<FileManager> for PapersWindow {
         manager.connect_opened({
             let stack = self.stack.clone();
             let titlebar = self.titlebar.clone();
-            let export_pdf_dialog = self.export_pdf_dialog.clone();
-            let bar = self.editor.pdf_viewer.bar.clone();
+            let export_dialog = self.export_dialog.clone();
+            let pdf_viewer = self.editor.pdf_viewer.clone();
             move |(path, _)| {
                 stack.set_visible_child_name("editor");
                 titlebar.set_prepared(true);
                 titlebar.clear_pages();
-                init_export_path(&export_pdf_dialog.dialog, path);
+                pdf_viewer.clear_pages();
+                export_dialog.init_path(&path);
                 titlebar.set_edit(true);
-                bar.set_revealed(false);
-            }
-        });
-        manager.connect_file_changed({
-            let bar = self.editor.pdf_viewer.bar.clone();
-            move |_| {
-                bar.set_revealed(true);
             }
         });
         manager.connect_save({
-            let export_pdf_dialog = self.export_pdf_dialog.clone();
-            let bar = self.editor.pdf_viewer.bar.clone();
+            let export_dialog = self.export_dialog.clone();
             move |path| {
-                bar.set_revealed(false);
-                init_export_path(&export_pdf_dialog.dialog, path);
+                export_dialog.init_path(&path);
             }
         });
+        connect_project_roots(&self.titlebar.main_menu, &self.state, manager);
 
         let csv_func = Rc::new(|path : &Path| -> String {
             let ncols = csv::Reader::from_path(path).ok()
@@ -818,20 +837,73 @@ impl React<FileManager> for PapersWindow {
                 .unwrap_or(1);
             format!("#table(columns:{},..csv(", ncols)
         });
-        write_on_import(self.editor.view.clone(), &self.import_csv_dialog, manager, Either::Right(csv_func), Either::Left(").flatten())"));
-        write_on_import(self.editor.view.clone(), &self.import_img_dialog, manager, Either::Left("#image("), Either::Left(")"));
-        write_on_import(self.editor.view.clone(), &self.import_bib_dialog, manager, Either::Left("#bibliography("), Either::Left(")"));
-        write_on_import(self.editor.view.clone(), &self.import_src_dialog, manager, Either::Left("#import"), Either::Left(": *"));
+        write_on_import(self.editor.view.clone(), &self.import_csv_dialog, &self.titlebar.main_menu, manager, Either::Right(csv_func), Either::Left(").flatten())"));
+        write_on_import(self.editor.view.clone(), &self.import_img_dialog, &self.titlebar.main_menu, manager, Either::Left("#image("), Either::Left(")"));
+        write_on_import(self.editor.view.clone(), &self.import_bib_dialog, &self.titlebar.main_menu, manager, Either::Left("#bibliography("), Either::Left(")"));
+        write_on_import(self.editor.view.clone(), &self.import_src_dialog, &self.titlebar.main_menu, manager, Either::Left("#import"), Either::Left(": *"));
     }
 
 }
 
-fn init_export_path(export_pdf_dialog : &FileChooserDialog, source_path : String) {
-    if export_pdf_dialog.file().is_none() {
+// Restores the project root chosen for a document when it is opened, and
+// remembers the root chosen for the current document.
+fn connect_project_roots(main_menu : &MainMenu, state : &PapersState, manager : &FileManager) {
+    let curr_path : Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    manager.connect_new({
+        let curr_path = curr_path.clone();
+        let root_action = main_menu.project_root_action.clone();
+        move |_| {
+            *curr_path.borrow_mut() = None;
+            root_action.set_state(&"".to_variant());
+        }
+    });
+    manager.connect_opened({
+        let curr_path = curr_path.clone();
+        let root_action = main_menu.project_root_action.clone();
+        let state = state.clone();
+        move |(path, _)| {
+            *curr_path.borrow_mut() = Some(path.clone());
+            let root = state.borrow().project_roots.get(&path).cloned().unwrap_or_default();
+            root_action.set_state(&root.to_variant());
+        }
+    });
+
+    // A document saved under a new name keeps the root chosen for it.
+    manager.connect_save({
+        let curr_path = curr_path.clone();
+        let main_menu = main_menu.clone();
+        let state = state.clone();
+        move |path| {
+            *curr_path.borrow_mut() = Some(path.clone());
+            if let Some(root) = main_menu.configured_root() {
+                state.borrow_mut().project_roots.insert(path, root.display().to_string());
+            }
+        }
+    });
+    main_menu.project_root_action.connect_notify_local(Some("state"), {
+        let main_menu = main_menu.clone();
+        let state = state.clone();
+        move |_, _| {
+            let Some(path) = curr_path.borrow().clone() else { return };
+            let mut state = state.borrow_mut();
+            match main_menu.configured_root() {
+                Some(root) => {
+                    state.project_roots.insert(path, root.display().to_string());
+                },
+                None => {
+                    state.project_roots.remove(&path);
+                }
+            }
+        }
+    });
+}
+
+fn init_export_path(export_dialog : &FileChooserDialog, source_path : String) {
+    if export_dialog.file().is_none() {
         if let Some(parent) = Path::new(&source_path).parent() {
-            if let Ok(_) = export_pdf_dialog.set_current_folder(Some(&gio::File::for_path(parent.to_str().unwrap()))) {
+            if let Ok(_) = export_dialog.set_current_folder(Some(&gio::File::for_path(parent.to_str().unwrap()))) {
                 if let Some(stem) = Path::new(&source_path).file_stem() {
-                    export_pdf_dialog.set_current_name(&format!("{}.pdf", stem.to_str().unwrap()));
+                    export_dialog.set_current_name(&format!("{}.pdf", stem.to_str().unwrap()));
                 }
             }
         }
@@ -1029,8 +1101,9 @@ fn update_titlebar(titlebar : &Titlebar, pdf_viewer : &PdfViewer) {
     if let Some(doc) = &*doc.borrow() {
         let n = doc.n_pages();
         titlebar.page_button.set_label(&format!("of {}", n));
-        titlebar.page_entry.set_text("1");
+        titlebar.page_entry.set_text(&format!("{}", pdf_viewer.curr_page() + 1));
     }
+    titlebar.mark_changed_pages(&pdf_viewer.changed_pages(), pdf_viewer.curr_page());
 }
 
 impl React<Typesetter> for PapersWindow {
@@ -1041,36 +1114,44 @@ impl React<Typesetter> for PapersWindow {
         let titlebar = self.titlebar.clone();
         typesetter.connect_done(move |target| {
             match target {
-                TypesetterTarget::File(path) => {
-                    let doc = poppler::Document::from_file(&format!("file://{}", path), None).unwrap();
-                    editor.pdf_viewer.update(&doc, &titlebar.zoom_action);
+                TypesetterTarget::Preview(preview) => {
+                    editor.pdf_viewer.update(preview, &titlebar.zoom_action);
                     update_titlebar(&titlebar, &editor.pdf_viewer);
-                },
-                TypesetterTarget::PDFContent(bytes) => {
-                    use std::io::Write;
-                    // let mut f = std::fs::File::create("/tmp/out.pdf").unwrap();
-                    // f.write_all(&bytes).unwrap();
-                    match poppler::Document::from_data(&bytes[..], None) {
-                        Ok(doc) => {
-                            editor.pdf_viewer.update(&doc, &titlebar.zoom_action);
-                            update_titlebar(&titlebar, &editor.pdf_viewer);
-                        },
-                        Err(e) => {
-                            eprintln!("Poppler error: {}", e);
-                        }
-                    }
-                },
-                _ => {
-                    eprintln!("Unimplemented typesetting target");
                 }
             }
         });
 
+        typesetter.connect_located({
+            let pdf_viewer = self.editor.pdf_viewer.clone();
+            let follow_cursor_action = self.titlebar.main_menu.follow_cursor_action.clone();
+            move |loc| {
+                let follow = follow_cursor_action.state().and_then(|s| s.get::<bool>() ).unwrap_or(false);
+                pdf_viewer.show_location(loc, follow);
+            }
+        });
+        self.titlebar.main_menu.follow_cursor_action.connect_notify_local(Some("state"), {
+            let pdf_viewer = self.editor.pdf_viewer.clone();
+            move |action, _| {
+                if !action.state().and_then(|s| s.get::<bool>() ).unwrap_or(false) {
+                    pdf_viewer.clear_location();
+                }
+            }
+        });
+
+        typesetter.connect_rendered({
+            let pdf_viewer = self.editor.pdf_viewer.clone();
+            move |preview| {
+                pdf_viewer.set_images(preview);
+            }
+        });
+
         typesetter.connect_error({
             let titlebar = self.titlebar.clone();
             move |_| {
-                titlebar.page_button.set_label("of 0");
-                titlebar.page_entry.set_text("0");
+                if !titlebar.is_live_preview() {
+                    titlebar.page_button.set_label("of 0");
+                    titlebar.page_entry.set_text("0");
+                }
             }
         });
     }
@@ -1092,15 +1173,31 @@ pub struct PdfViewer {
     scroll : ScrolledWindow,
     pages_bx : Box,
     das : Rc<RefCell<Vec<DrawingArea>>>,
-    doc : Rc<RefCell<Option<poppler::Document>>>,
+    doc : Rc<RefCell<Option<PreviewDoc>>>,
     da1 : DrawingArea,
     da2 : DrawingArea,
     curr_page : Rc<RefCell<usize>>,
     stack : Stack,
     turn_action : gio::SimpleAction,
-    bar : ActionBar,
-    bar_lbl : Label,
-    bx : Box
+    bx : Box,
+    zoom_action : gio::SimpleAction,
+    on_render : Callbacks<(Vec<usize>, f32)>,
+    on_click : Callbacks<Location>,
+
+    // Location highlighted after a forward search, and the timeout that removes the highlight.
+    location : Rc<RefCell<Option<Location>>>,
+    location_timeout : Rc<RefCell<Option<glib::SourceId>>>
+}
+
+impl React<DocTree> for PdfViewer {
+
+    fn react(&self, tree : &DocTree) {
+        let viewer = self.clone();
+        tree.connect_location_selected(move |loc| {
+            viewer.show_location(loc, false);
+        });
+    }
+
 }
 
 impl React<Titlebar> for PdfViewer {
@@ -1108,10 +1205,12 @@ impl React<Titlebar> for PdfViewer {
         titlebar.zoom_action.connect_activate({
             let das = self.das.clone();
             let (da1, da2) = (self.da1.clone(), self.da2.clone());
+            let viewer = self.clone();
             move |_,_| {
                 das.borrow().iter().for_each(|da| da.queue_draw() );
                 da1.queue_draw();
                 da2.queue_draw();
+                viewer.request_pages();
             }
         });
         /*titlebar.pdf_btn.connect_toggled({
@@ -1188,7 +1287,7 @@ impl React<Titlebar> for PdfViewer {
 }
 
 fn go_to_page(
-    doc : &Rc<RefCell<Option<poppler::Document>>>,
+    doc : &Rc<RefCell<Option<PreviewDoc>>>,
     da1 : &DrawingArea,
     da2 : &DrawingArea,
     curr_page : &Rc<RefCell<usize>>,
@@ -1196,25 +1295,54 @@ fn go_to_page(
     stack : &Stack,
     new_page : i32
 ) {
-    if new_page >= 1 {
-        let doc = doc.borrow();
-        if new_page <= doc.as_ref().map(|d| d.n_pages() ).unwrap_or(0) {
-            let mut curr_page = curr_page.borrow_mut();
-            if new_page == *curr_page as i32 {
-                return;
-            }
-            if new_page > *curr_page as i32 {
-                stack.set_transition_type(StackTransitionType::SlideLeft);
-            } else {
-                stack.set_transition_type(StackTransitionType::SlideRight);
-            }
-            *curr_page = new_page as usize - 1;
-            turn_action.set_state(&(new_page - 1).to_variant());
-            draw_at_even_or_odd(&stack, &da1, &da2, new_page as usize - 1);
+    let n_pages = doc.borrow().as_ref().map(|d| d.n_pages() ).unwrap_or(0);
+    let mut curr_page = curr_page.borrow_mut();
+    if let Some(page) = jumped_page(*curr_page, n_pages, new_page) {
+        if page > *curr_page {
+            stack.set_transition_type(StackTransitionType::SlideLeft);
+        } else {
+            stack.set_transition_type(StackTransitionType::SlideRight);
         }
+        *curr_page = page;
+        turn_action.set_state(&(page as i32).to_variant());
+        draw_at_even_or_odd(&stack, &da1, &da2, page);
+    }
+}
+
+// Index of the page shown after going to the page numbered new_page (counting from one), or None
+// when the page does not exist or is already shown.
+fn jumped_page(curr_page : usize, n_pages : usize, new_page : i32) -> Option<usize> {
+    if new_page < 1 || new_page as usize > n_pages || new_page as usize - 1 == curr_page {
+        None
+    } else {
+        Some(new_page as usize - 1)
+    }
+}
+
+// Index of the page shown after turning the current page to the left or to the right,
+// or None at the first or last page.
+fn turned_page(curr_page : usize, n_pages : usize, left : bool) -> Option<usize> {
+    if left {
+        curr_page.checked_sub(1)
+    } else if curr_page + 1 < n_pages {
+        Some(curr_page + 1)
+    } else {
+        None
     }
 }
 
+// Whether a page must be rasterized again to be shown at the given scale, because it has no
+// image or its image was rasterized for another zoom.
+fn stale_image(image_scale : Option<f32>, scale : f32) -> bool {
+    image_scale.map(|s| (s - scale).abs() > 1.0e-3 ).unwrap_or(true)
+}
+
+// Time (in milliseconds) a location found by a forward search stays highlighted.
+const LOCATION_HIGHLIGHT : u64 = 1500;
+
+// Space between the page and the top and left edges of the viewer (as set by configure_da_for_doc).
+const PAGE_MARGIN : f64 = 16.0;
+
 // Equivalent to 0xdc
 // const PAGE_BORDER_COLOR : f64 = 0.859375;
 
@@ -1235,7 +1363,7 @@ fn draw_at_even_or_odd(stack : &Stack, da1 : &DrawingArea, da2 : &DrawingArea, c
 
 fn turn_page(
     stack : &Stack,
-    doc : &Rc<RefCell<Option<Document>>>,
+    doc : &Rc<RefCell<Option<PreviewDoc>>>,
     curr_page : &Rc<RefCell<usize>>,
     da1 : &DrawingArea,
     da2 : &DrawingArea,
@@ -1246,25 +1374,17 @@ fn turn_page(
     // da2.queue_draw();
     let mut cp = curr_page.borrow_mut();
     let n_pages = if let Ok(doc) = doc.try_borrow() {
-        doc.as_ref().map(|d| d.n_pages() as usize ).unwrap_or(0)
+        doc.as_ref().map(|d| d.n_pages() ).unwrap_or(0)
     } else {
         return;
     };
-    if n_pages == 0 {
+    let Some(page) = turned_page(*cp, n_pages, left) else {
         return;
-    }
-    if *cp == 0 && left {
-        return;
-    }
-    if (*cp == n_pages-1 && !left) {
-        return;
-    }
-
+    };
+    *cp = page;
     if left {
-        *cp -= 1;
         stack.set_transition_type(StackTransitionType::SlideRight);
     } else {
-        *cp += 1;
         stack.set_transition_type(StackTransitionType::SlideLeft);
     }
 
@@ -1276,10 +1396,109 @@ fn turn_page(
 
 impl PdfViewer {
 
-    pub fn doc(&self) -> &Rc<RefCell<Option<poppler::Document>>> {
+    pub fn doc(&self) -> &Rc<RefCell<Option<PreviewDoc>>> {
         &self.doc
     }
 
+    /// Called with the pages that should be rasterized (and the number of pixels per point
+    /// they should be rasterized with), because they are shown without an image or with an
+    /// image rasterized for another zoom.
+    pub fn connect_render_request<F>(&self, f : F)
+    where
+        F : Fn((Vec<usize>, f32)) + 'static
+    {
+        self.on_render.bind(f);
+    }
+
+    /// Called with the point of the current page clicked by the user, to find the source
+    /// text that produced it (inverse search).
+    pub fn connect_click<F>(&self, f : F)
+    where
+        F : Fn(Location) + 'static
+    {
+        self.on_click.bind(f);
+    }
+
+    /// Asks for an image of the current page when it is missing or was rasterized for
+    /// another zoom. Without a document, only informs the scale of the next document.
+    pub fn request_pages(&self) {
+        let z = self.zoom_action.state().and_then(|s| s.get::<f64>() ).unwrap_or(1.0);
+        let scale = ((z * self.stack.scale_factor() as f64) as f32).min(MAX_PREVIEW_SCALE);
+        // The page is taken from the action state, since this might be called while the
+        // current page is being changed.
+        let page = self.turn_action.state().and_then(|s| s.get::<i32>() ).unwrap_or(0).max(0) as usize;
+        let missing = match &*self.doc.borrow() {
+            Some(doc) => doc.page(page)
+                .map(|p| stale_image(p.image.as_ref().map(|(s, _)| *s ), scale) )
+                .unwrap_or(false),
+            None => true
+        };
+        if missing {
+            self.on_render.call((vec![page], scale));
+        }
+    }
+
+    /// Indices of pages that changed at the last typesetting.
+    pub fn changed_pages(&self) -> Vec<usize> {
+        self.doc.borrow().as_ref().map(|d| d.changed_pages() ).unwrap_or_default()
+    }
+
+    /// Shows the page of a location found by a forward search, highlighting the location. Unless
+    /// the highlight is kept (i.e. while the preview follows the cursor), it is removed after a while.
+    pub fn show_location(&self, loc : Location, keep : bool) {
+        go_to_page(&self.doc, &self.da1, &self.da2, &self.curr_page, &self.turn_action, &self.stack, loc.page as i32 + 1);
+        self.turn_action.activate(None);
+        self.location.replace(Some(loc));
+        if let Some(id) = self.location_timeout.borrow_mut().take() {
+            id.remove();
+        }
+        if !keep {
+            let id = glib::timeout_add_local_once(Duration::from_millis(LOCATION_HIGHLIGHT), {
+                let viewer = self.clone();
+                move || {
+                    // The source is removed after this returns, so it must not be removed again.
+                    viewer.location_timeout.borrow_mut().take();
+                    viewer.clear_location();
+                }
+            });
+            *self.location_timeout.borrow_mut() = Some(id);
+        }
+        self.da1.queue_draw();
+        self.da2.queue_draw();
+
+        // Centers the location, once the page area is adjusted to the page size.
+        glib::idle_add_local_once({
+            let scroll = self.scroll.clone();
+            let zoom_action = self.zoom_action.clone();
+            move || {
+                let z = zoom_action.state().and_then(|s| s.get::<f64>() ).unwrap_or(1.0);
+                let (hadj, vadj) = (scroll.hadjustment(), scroll.vadjustment());
+                hadj.set_value(loc.x * z + PAGE_MARGIN - hadj.page_size() / 2.0);
+                vadj.set_value(loc.y * z + PAGE_MARGIN - vadj.page_size() / 2.0);
+            }
+        });
+    }
+
+    pub fn clear_location(&self) {
+        self.location.replace(None);
+        self.da1.queue_draw();
+        self.da2.queue_draw();
+    }
+
+    /// Shows pages rasterized after a render request.
+    pub fn set_images(&self, preview : Preview) {
+        if let Some(doc) = self.doc.borrow_mut().as_mut() {
+            doc.set_images(preview);
+        }
+        self.da1.queue_draw();
+        self.da2.queue_draw();
+    }
+
+    // Index of the page currently shown (counting from zero).
+    pub fn curr_page(&self) -> usize {
+        *self.curr_page.borrow()
+    }
+
     pub fn clear_pages(&self) {
         while let Some(child) = self.pages_bx.last_child() {
             self.pages_bx.remove(&child);
@@ -1300,7 +1519,8 @@ impl PdfViewer {
         stack.set_vexpand(true);
         let click = GestureClick::new();
         let curr_page = Rc::new(RefCell::new(0));
-        let doc = Rc::new(RefCell::new(None));
+        let doc : Rc<RefCell<Option<PreviewDoc>>> = Rc::new(RefCell::new(None));
+        let location : Rc<RefCell<Option<Location>>> = Rc::new(RefCell::new(None));
 
         click.connect_pressed({
             let stack = stack.clone();
@@ -1412,13 +1632,17 @@ impl PdfViewer {
                 let zoom_action = zoom_action.clone();
                 let doc = doc.clone();
                 let curr_page = curr_page.clone();
+                let location = location.clone();
                 move |da, ctx, _, _| {
                     let cp = curr_page.borrow();
                     let doc = doc.borrow();
                     if let Some(doc) = &*doc {
-                        if let Some(page) = doc.page(*cp as i32) {
+                        if let Some(page) = doc.page(*cp) {
                             crate::adjust_dimension_for_page(da, zoom_action.clone(), &page);
                             crate::draw_page_content(da, ctx, &zoom_action.clone(), &page, true);
+                            if let Some(loc) = location.borrow().filter(|loc| loc.page == *cp ) {
+                                crate::draw_location(da, ctx, &zoom_action, &loc);
+                            }
                         } else {
                             eprintln!("No page {} at draw", *cp);
                         }
@@ -1432,41 +1656,69 @@ impl PdfViewer {
         crate::configure_da_for_doc(&da1);
         crate::configure_da_for_doc(&da2);
 
-        scroll.set_child(Some(&stack));
-        let bar = ActionBar::new();
-        bar.set_height_request(48);
-        bar.set_revealed(true);
-        let bar_lbl = Label::new(Some("Unsaved changes must be committed to file before typesetting"));
-        let bar_bx = Box::new(Orientation::Horizontal, 0);
-        bar_bx.append(&bar_lbl);
-        bar_bx.set_hexpand(true);
-        bar_bx.set_halign(Align::Fill);
-        let close_btn = Button::from_icon_name("window-close-symbolic");
-        close_btn.connect_clicked({
-            let bar = bar.clone();
-            move |_| {
-                if bar.is_revealed() {
-                    bar.set_revealed(false);
+        // Page areas are drawn from their top-left corner, so clicked points are
+        // converted to page points by undoing the zoom.
+        let on_click : Callbacks<Location> = Default::default();
+        for da in [&da1, &da2] {
+            let page_click = GestureClick::new();
+            page_click.connect_released({
+                let on_click = on_click.clone();
+                let doc = doc.clone();
+                let curr_page = curr_page.clone();
+                let zoom_action = zoom_action.clone();
+                move |_, n_press, x, y| {
+                    if n_press != 1 {
+                        return;
+                    }
+                    let z = zoom_action.state().and_then(|s| s.get::<f64>() ).unwrap_or(1.0);
+                    let page = *curr_page.borrow();
+                    if doc.borrow().as_ref().and_then(|d| d.page(page) ).is_some() {
+                        on_click.call(Location { page, x : x / z, y : y / z });
+                    }
                 }
-            }
-        });
-        bar_bx.append(&close_btn);
-        close_btn.style_context().add_class("flat");
-        close_btn.set_hexpand(true);
-        close_btn.set_halign(Align::End);
-        bar_lbl.set_hexpand(true);
-        bar_lbl.set_justify(Justification::Left);
-        bar_lbl.set_halign(Align::Start);
-
-        bar.set_center_widget(Some(&bar_bx));
+            });
+            da.add_controller(&page_click);
+        }
+
+        scroll.set_child(Some(&stack));
         let bx = Box::new(Orientation::Vertical, 0);
         bx.append(&scroll);
-        bx.append(&bar);
 
-        Self { scroll, das, pages_bx, doc, da1, da2, curr_page, stack, turn_action, bar, bar_lbl, bx }
+        let viewer = Self {
+            scroll,
+            das,
+            pages_bx,
+            doc,
+            da1,
+            da2,
+            curr_page,
+            stack,
+            turn_action,
+            bx,
+            zoom_action : zoom_action.clone(),
+            on_render : Default::default(),
+            on_click,
+            location,
+            location_timeout : Default::default()
+        };
+
+        // Pages are only rasterized when they are shown (or when the screen they are shown at changes).
+        viewer.turn_action.connect_notify_local(Some("state"), {
+            let viewer = viewer.clone();
+            move |_, _| {
+                viewer.request_pages();
+            }
+        });
+        viewer.stack.connect_scale_factor_notify({
+            let viewer = viewer.clone();
+            move |_| {
+                viewer.request_pages();
+            }
+        });
+        viewer
     }
 
-    pub fn update_contiguous(&self, doc : &poppler::Document, zoom_action : &gio::SimpleAction) {
+    pub fn update_contiguous(&self, doc : &PreviewDoc, zoom_action : &gio::SimpleAction) {
         self.turn_action.set_state(&(0i32).to_variant());
         self.turn_action.activate(None);
         {
@@ -1483,20 +1735,42 @@ impl PdfViewer {
         self.doc.replace(Some(doc.clone()));
     }
 
-    pub fn update(&self, doc : &poppler::Document, zoom_action : &gio::SimpleAction) {
-        self.turn_action.set_state(&(0i32).to_variant());
+    /// Shows a newly typeset version of the document. The current page (as long as the new
+    /// version still has it) and the scroll position are preserved, so that the preview does
+    /// not jump back to the first page every time the document is typeset again. Call
+    /// clear_pages before this to show a different document from its first page.
+    pub fn update(&self, preview : Preview, zoom_action : &gio::SimpleAction) {
+        let prev = self.doc.borrow_mut().take();
+        let doc = PreviewDoc::new(preview, prev);
+
+        // Locations refer to the previous version of the document.
+        self.location.replace(None);
+        let n_pages = doc.n_pages().max(1);
+        let page = {
+            let mut curr_page = self.curr_page.borrow_mut();
+            *curr_page = (*curr_page).min(n_pages - 1);
+            *curr_page
+        };
+        let hpos = self.scroll.hadjustment().value();
+        let vpos = self.scroll.vadjustment().value();
+        self.doc.replace(Some(doc));
+        self.turn_action.set_state(&(page as i32).to_variant());
         self.turn_action.activate(None);
-        // crate::draw_page_at_area(doc, 0, &self.da1, zoom_action);
-        // if doc.n_pages() > 1 {
-        //    crate::draw_page_at_area(doc, 1, &self.da2, zoom_action);
-        // }
-        self.doc.replace(Some(doc.clone()));
-        {
-            *(self.curr_page.borrow_mut()) = 0;
-        }
         self.da1.queue_draw();
         self.da2.queue_draw();
-        self.stack.set_visible_child_name("left");
+        self.stack.set_transition_type(StackTransitionType::None);
+        draw_at_even_or_odd(&self.stack, &self.da1, &self.da2, page);
+        self.request_pages();
+
+        // The page dimensions are only adjusted when the drawing areas are drawn again,
+        // which might clamp the adjustments, so they are restored after that.
+        glib::idle_add_local_once({
+            let scroll = self.scroll.clone();
+            move || {
+                scroll.hadjustment().set_value(hpos);
+                scroll.vadjustment().set_value(vpos);
+            }
+        });
     }
 
 }
@@ -1687,3 +1961,24 @@ impl React<FileManager> for RecentList {
 
 }
 
+#[test]
+fn pages_are_turned_within_the_document() {
+    assert_eq!(turned_page(0, 3, true), None);
+    assert_eq!(turned_page(0, 3, false), Some(1));
+    assert_eq!(turned_page(2, 3, true), Some(1));
+    assert_eq!(turned_page(2, 3, false), None);
+    assert_eq!(turned_page(0, 0, false), None);
+    assert_eq!(jumped_page(0, 3, 3), Some(2));
+    assert_eq!(jumped_page(2, 3, 1), Some(0));
+    assert_eq!(jumped_page(1, 3, 2), None);
+    assert_eq!(jumped_page(0, 3, 4), None);
+    assert_eq!(jumped_page(0, 3, 0), None);
+}
+
+#[test]
+fn images_are_replaced_after_zoom_changes() {
+    assert!(stale_image(None, 1.0));
+    assert!(!stale_image(Some(1.5), 1.5));
+    assert!(stale_image(Some(1.0), 1.5));
+    assert!(stale_image(Some(2.0), 1.5));
+}
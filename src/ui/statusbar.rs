@@ -0,0 +1,116 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+use gtk4::*;
+use gtk4::prelude::*;
+use super::*;
+use crate::typst_tools::stats::text_stats;
+
+// Idle time (in milliseconds) after the text changes before its words are counted again.
+const COUNT_DELAY : u64 = 300;
+
+/// Shows the words of the document, the cursor position and how long the
+/// last typesetting took, under the editor.
+#[derive(Debug, Clone)]
+pub struct StatusBar {
+    pub bx : Box,
+    words : Label,
+    position : Label,
+    elapsed : Label
+}
+
+fn status_label() -> Label {
+    let lbl = Label::new(None);
+    lbl.add_css_class("dim-label");
+    lbl.add_css_class("caption");
+    lbl
+}
+
+impl StatusBar {
+
+    pub fn build() -> Self {
+        let bx = Box::new(Orientation::Horizontal, 18);
+        bx.set_margin_start(12);
+        bx.set_margin_end(12);
+        bx.set_margin_top(3);
+        bx.set_margin_bottom(3);
+        let words = status_label();
+        let position = status_label();
+        let elapsed = status_label();
+        words.set_hexpand(true);
+        words.set_halign(Align::Start);
+        bx.append(&words);
+        bx.append(&position);
+        bx.append(&elapsed);
+        Self { bx, words, position, elapsed }
+    }
+
+}
+
+fn update_words(lbl : &Label, view : &sourceview5::View) {
+    let buffer = view.buffer();
+    let stats = text_stats(&buffer.text(&buffer.start_iter(), &buffer.end_iter(), true));
+    lbl.set_text(&format!("{} words", stats.words));
+    lbl.set_tooltip_text(Some(&format!(
+        "{} characters, {} paragraphs ({} min read)",
+        stats.chars,
+        stats.paragraphs,
+        stats.reading_minutes()
+    )));
+}
+
+fn update_position(lbl : &Label, view : &sourceview5::View) {
+    let buffer = view.buffer();
+    let iter = buffer.iter_at_offset(buffer.cursor_position());
+    lbl.set_text(&format!("Ln {}, Col {}", iter.line() + 1, iter.line_offset() + 1));
+}
+
+impl React<PapersEditor> for StatusBar {
+
+    fn react(&self, editor : &PapersEditor) {
+        let buffer = editor.view.buffer();
+        update_words(&self.words, &editor.view);
+        update_position(&self.position, &editor.view);
+        let pending : Rc<RefCell<Option<glib::SourceId>>> = Rc::new(RefCell::new(None));
+        buffer.connect_changed({
+            let words = self.words.clone();
+            let view = editor.view.clone();
+            move |_| {
+                if let Some(id) = pending.borrow_mut().take() {
+                    id.remove();
+                }
+                let id = glib::timeout_add_local_once(Duration::from_millis(COUNT_DELAY), {
+                    let pending = pending.clone();
+                    let words = words.clone();
+                    let view = view.clone();
+                    move || {
+                        pending.borrow_mut().take();
+                        update_words(&words, &view);
+                    }
+                });
+                *pending.borrow_mut() = Some(id);
+            }
+        });
+        buffer.connect_cursor_position_notify({
+            let position = self.position.clone();
+            let view = editor.view.clone();
+            move |_| {
+                update_position(&position, &view);
+            }
+        });
+    }
+
+}
+
+impl React<Typesetter> for StatusBar {
+
+    fn react(&self, typesetter : &Typesetter) {
+        let elapsed = self.elapsed.clone();
+        typesetter.connect_elapsed(move |time| {
+            elapsed.set_text(&format!("Typeset in {} ms", time.as_millis()));
+        });
+    }
+
+}
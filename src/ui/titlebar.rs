@@ -7,6 +7,7 @@ use super::*;
 use crate::analyzer::Analyzer;
 use crate::tex::{Difference, BibEntry};
 use crate::tex::Token;
+use crate::typst_tools::crossref::Citation;
 use std::borrow::Cow;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use filecase::FileActions;
@@ -19,6 +20,33 @@ pub struct MainMenu {
     pub export_action : gio::SimpleAction,
     pub open_dialog : OpenDialog,
     pub save_dialog : SaveDialog,
+
+    // Boolean state: whether the document is typeset automatically after edits.
+    pub live_preview_action : gio::SimpleAction,
+
+    // Integer state: idle time (in milliseconds) after the last edit before a live preview update.
+    pub preview_delay_action : gio::SimpleAction,
+
+    // Boolean state: whether the document is typeset again when files it depends on change on disk.
+    pub watch_action : gio::SimpleAction,
+
+    // Boolean state: whether the preview keeps showing where the text at the cursor was laid out.
+    pub follow_cursor_action : gio::SimpleAction,
+
+    // String state: project root chosen for the current document (empty when it is found
+    // from the document path).
+    pub project_root_action : gio::SimpleAction,
+
+    pub choose_root_action : gio::SimpleAction,
+
+    pub root_dialog : FileChooserDialog,
+
+    // String array state: directories searched for fonts besides the system and project directories.
+    pub font_dirs_action : gio::SimpleAction,
+
+    pub show_font_dirs_action : gio::SimpleAction,
+
+    pub font_dirs_dialog : FontDirsDialog
 }
 
 impl MainMenu {
@@ -35,6 +63,28 @@ impl MainMenu {
         menu.append(Some("Save"), Some("win.save_file"));
         menu.append(Some("Save as"), Some("win.save_as_file"));
         menu.append(Some("Export"), Some("win.export"));
+
+        let preview_section = gio::Menu::new();
+        preview_section.append(Some("Live preview"), Some("win.live_preview"));
+        let delay_menu = gio::Menu::new();
+        for (label, delay) in [("Short (0.5 s)", 500i32), ("Medium (1 s)", 1000i32), ("Long (2 s)", 2000i32)] {
+            let item = gio::MenuItem::new(Some(label), None);
+            item.set_action_and_target_value(Some("win.live_preview_delay"), Some(&delay.to_variant()));
+            delay_menu.append_item(&item);
+        }
+        preview_section.append_submenu(Some("Live preview delay"), &delay_menu);
+        preview_section.append(Some("Typeset when files change"), Some("win.watch_files"));
+        preview_section.append(Some("Follow cursor in preview"), Some("win.follow_cursor"));
+        menu.append_section(None, &preview_section);
+
+        let root_section = gio::Menu::new();
+        root_section.append(Some("Choose project root"), Some("win.choose_project_root"));
+        let auto_root_item = gio::MenuItem::new(Some("Find project root automatically"), None);
+        auto_root_item.set_action_and_target_value(Some("win.project_root"), Some(&"".to_variant()));
+        root_section.append_item(&auto_root_item);
+        root_section.append(Some("Font folders"), Some("win.font_folders"));
+        menu.append_section(None, &root_section);
+
         let popover = PopoverMenu::from_model(Some(&menu));
         let actions = FileActions::new();
         let open_dialog = OpenDialog::build(&["*.typ"]);
@@ -43,7 +93,97 @@ impl MainMenu {
         let export_action = gio::SimpleAction::new("export", None);
         // let action_close = gio::SimpleAction::new("close_file", None);
         export_action.set_enabled(false);
-        Self { popover, actions, open_dialog, save_dialog, /*export_dialog,*/ export_action, /*action_close*/ }
+
+        // Stateful actions without handlers toggle (or take the activation parameter as)
+        // their state when activated from the menu.
+        let live_preview_action = gio::SimpleAction::new_stateful("live_preview", None, &false.to_variant());
+        let watch_action = gio::SimpleAction::new_stateful("watch_files", None, &false.to_variant());
+        let follow_cursor_action = gio::SimpleAction::new_stateful("follow_cursor", None, &false.to_variant());
+        let preview_delay_action = gio::SimpleAction::new_stateful(
+            "live_preview_delay",
+            Some(&i32::static_variant_type()),
+            &DEFAULT_PREVIEW_DELAY.to_variant()
+        );
+        let project_root_action = gio::SimpleAction::new_stateful(
+            "project_root",
+            Some(&String::static_variant_type()),
+            &"".to_variant()
+        );
+        let choose_root_action = gio::SimpleAction::new("choose_project_root", None);
+        let root_dialog = FileChooserDialog::new(
+            Some("Project root"),
+            None::<&Window>,
+            FileChooserAction::SelectFolder,
+            &[("Cancel", ResponseType::Cancel), ("Select", ResponseType::Accept)]
+        );
+        root_dialog.set_modal(true);
+        root_dialog.connect_response({
+            let project_root_action = project_root_action.clone();
+            move |dialog, resp| {
+                if resp == ResponseType::Accept {
+                    if let Some(path) = dialog.file().and_then(|f| f.path() ) {
+                        project_root_action.set_state(&path.display().to_string().to_variant());
+                    }
+                }
+                dialog.hide();
+            }
+        });
+        choose_root_action.connect_activate({
+            let root_dialog = root_dialog.clone();
+            move |_, _| {
+                root_dialog.show();
+            }
+        });
+        let font_dirs_action = gio::SimpleAction::new_stateful(
+            "font_dirs",
+            Some(&Vec::<String>::static_variant_type()),
+            &Vec::<String>::new().to_variant()
+        );
+        let show_font_dirs_action = gio::SimpleAction::new("font_folders", None);
+        let font_dirs_dialog = FontDirsDialog::build(&font_dirs_action);
+        show_font_dirs_action.connect_activate({
+            let dialog = font_dirs_dialog.dialog.clone();
+            move |_, _| {
+                dialog.show();
+            }
+        });
+        Self {
+            popover,
+            actions,
+            open_dialog,
+            save_dialog,
+            /*export_dialog,*/
+            export_action,
+            /*action_close*/
+            live_preview_action,
+            preview_delay_action,
+            watch_action,
+            follow_cursor_action,
+            project_root_action,
+            choose_root_action,
+            root_dialog,
+            font_dirs_action,
+            show_font_dirs_action,
+            font_dirs_dialog
+        }
+    }
+
+    /// Font directories configured by the user.
+    pub fn font_dirs(&self) -> Vec<PathBuf> {
+        self.font_dirs_action.state()
+            .and_then(|s| s.get::<Vec<String>>() )
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// Project root chosen by the user for the current document, if any.
+    pub fn configured_root(&self) -> Option<PathBuf> {
+        self.project_root_action.state()
+            .and_then(|s| s.get::<String>() )
+            .filter(|s| !s.is_empty() )
+            .map(PathBuf::from)
     }
 
 }
@@ -56,6 +196,8 @@ pub struct Titlebar {
     pub pdf_btn : Button,
     pub view_pdf_btn : ToggleButton,
     pub typeset_action : gio::SimpleAction,
+    pub cancel_typesetting_action : gio::SimpleAction,
+    pub show_in_preview_action : gio::SimpleAction,
     // pub editor_btn : ToggleButton,
     // pub explore_toggle : ToggleButton,
 
@@ -284,7 +426,7 @@ pub struct FormatPopover {
     pub right_btn : Button,
     pub center_btn : Button,
     pub justify_btn : Button,
-    pub font_btn : FontButton
+    pub font_chooser : FontChooser
 }
 
 fn build_fmt_btn(label : &str) -> Button {
@@ -416,8 +558,8 @@ impl FormatPopover {
 
         let font_bx = Box::new(Orientation::Vertical, 0);
         font_bx.append(&Label::builder().label("Font").halign(Align::Start).justify(Justification::Left).margin_bottom(6).build());
-        let font_btn = FontButton::new();
-        font_bx.append(&font_btn);
+        let font_chooser = FontChooser::build();
+        font_bx.append(&font_chooser.btn);
 
         let cols_bx = Box::new(Orientation::Vertical, 0);
         cols_bx.append(&Label::builder().label("Columns").halign(Align::Start).justify(Justification::Left).margin_bottom(6).build());
@@ -469,7 +611,7 @@ impl FormatPopover {
             left_btn,
             right_btn,
             justify_btn,
-            font_btn
+            font_chooser
         }
     }
 
@@ -479,7 +621,12 @@ impl FormatPopover {
 pub struct BibPopover {
     pub list : ListBox,
     pub popover : Popover,
-    pub search_entry : Entry
+    pub search_entry : Entry,
+
+    // Latest citations found at the project, shown by the rows of their entries.
+    citations : Rc<RefCell<Vec<Citation>>>,
+
+    on_citation_selected : Callbacks<Citation>
 }
 
 impl BibPopover {
@@ -522,7 +669,15 @@ impl BibPopover {
             }
         });
         create_init_row(&list);
-        BibPopover { list, popover, search_entry }
+        BibPopover { list, popover, search_entry, citations : Default::default(), on_citation_selected : Default::default() }
+    }
+
+    /// Called when the user clicks a place where an entry is cited.
+    pub fn connect_citation_selected<F>(&self, f : F)
+    where
+        F : Fn(Citation) + 'static
+    {
+        self.on_citation_selected.bind(f);
     }
 
 }
@@ -819,6 +974,9 @@ impl PaperPopover {
 
 pub const DEFAULT_ZOOM_SCALE : f64 = 1.5;
 
+// Idle time (in milliseconds) after the last edit before the live preview is updated.
+pub const DEFAULT_PREVIEW_DELAY : i32 = 1000;
+
 pub const ZOOM_SCALE_INCREMENT : f64 = 0.5;
 
 impl React<Analyzer> for Titlebar {
@@ -852,9 +1010,34 @@ impl Titlebar {
         self.set_typeset_mode(false);
     }
 
+    pub fn is_live_preview(&self) -> bool {
+        self.main_menu.live_preview_action.state()
+            .and_then(|s| s.get::<bool>() )
+            .unwrap_or(false)
+    }
+
     pub fn clear_pages(&self) {
         self.page_button.set_label("of 0");
         self.page_entry.set_text("0");
+        self.mark_changed_pages(&[], 0);
+    }
+
+    /// Highlights the page indicator when pages changed at the last typesetting, listing
+    /// them at its tooltip. The page number is highlighted when the current page changed.
+    pub fn mark_changed_pages(&self, changed : &[usize], curr_page : usize) {
+        if changed.is_empty() {
+            self.page_button.remove_css_class("accent");
+            self.page_button.set_tooltip_text(None);
+        } else {
+            self.page_button.add_css_class("accent");
+            let noun = if changed.len() == 1 { "Page" } else { "Pages" };
+            self.page_button.set_tooltip_text(Some(&format!("{} {} changed at the last typesetting", noun, page_ranges(changed))));
+        }
+        if changed.contains(&curr_page) {
+            self.page_entry.add_css_class("accent");
+        } else {
+            self.page_entry.remove_css_class("accent");
+        }
     }
 
     pub fn set_typeset_mode(&self, active : bool) {
@@ -1167,8 +1350,12 @@ impl Titlebar {
         });
 
         let typeset_action = gio::SimpleAction::new("typeset", None);
+        let cancel_typesetting_action = gio::SimpleAction::new("cancel_typesetting", None);
+        let show_in_preview_action = gio::SimpleAction::new("show_in_preview", None);
         Self {
             typeset_action,
+            cancel_typesetting_action,
+            show_in_preview_action,
             symbol_btn,
             fmt_btn,
             bib_btn,
@@ -1219,6 +1406,7 @@ impl React<Typesetter> for Titlebar {
             let page_entry = self.page_entry.clone();
             move |_| {
                 btn.set_icon_name("ink-tool-symbolic");
+                btn.set_tooltip_text(None);
                 btn.set_sensitive(true);
                 // refresh_btn.set_sensitive(true);
                 // page_entry.set_sensitive(true);
@@ -1235,9 +1423,22 @@ impl React<Typesetter> for Titlebar {
             let page_entry = self.page_entry.clone();
             move |_| {
                 btn.set_icon_name("ink-tool-symbolic");
+                btn.set_tooltip_text(None);
                 btn.set_sensitive(true);
                 // btn.set_active(false);
-                titlebar.set_typeset_mode(false);
+
+                // Errors are expected while the user is typing in live preview mode,
+                // so the last valid version is kept on screen.
+                if !titlebar.is_live_preview() {
+                    titlebar.set_typeset_mode(false);
+                }
+            }
+        });
+        typesetter.connect_cancelled({
+            let btn = self.pdf_btn.clone();
+            move |_| {
+                btn.set_icon_name("ink-tool-symbolic");
+                btn.set_tooltip_text(None);
             }
         });
     }
@@ -1253,16 +1454,42 @@ impl React<PdfViewer> for Titlebar {
                 page_entry.set_text(&format!("{page}"));
             }
         });
+        viewer.turn_action.connect_notify_local(Some("state"), {
+            let titlebar = self.clone();
+            let viewer = viewer.clone();
+            move |action, _| {
+                let page = action.state().and_then(|s| s.get::<i32>() ).unwrap_or(0).max(0) as usize;
+                titlebar.mark_changed_pages(&viewer.changed_pages(), page);
+            }
+        });
     }
 
 }
 
+// Lists (one-based) page numbers, joining consecutive pages into ranges (e.g. "2, 4-6").
+fn page_ranges(pages : &[usize]) -> String {
+    let mut ranges : Vec<(usize, usize)> = Vec::new();
+    for page in pages {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == *page => *last = *page,
+            _ => ranges.push((*page, *page))
+        }
+    }
+    ranges.iter()
+        .map(|(first, last)| if first == last { format!("{}", first + 1) } else { format!("{}-{}", first + 1, last + 1) } )
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[derive(Debug, Clone)]
 pub struct ReferenceRow {
     pub row : ListBoxRow,
     pub key_label : Label,
     pub authors_label : Label,
     pub title_label : Label,
+
+    // Backlinks to the places where the entry is cited.
+    pub citations_box : FlowBox
 }
 
 fn trim_braces(s : &str) -> &str {
@@ -1309,7 +1536,41 @@ impl ReferenceRow {
         let key_label = super::try_get_child_by_index::<Label>(&header_bx, 1)?;
         let authors_label = super::try_get_child_by_index::<Label>(&header_bx, 2)?;
         let title_label = super::try_get_child_by_index::<Label>(&bx, 1)?;
-        Some(Self { row : row.clone(), key_label, authors_label, title_label })
+        let citations_box = super::try_get_child_by_index::<FlowBox>(&bx, 2)?;
+        Some(Self { row : row.clone(), key_label, authors_label, title_label, citations_box })
+    }
+
+    /// Shows the places where the entry is cited (among all citations of the project),
+    /// each as a button that selects the citation.
+    pub fn show_citations(&self, citations : &[Citation], on_selected : &Callbacks<Citation>) {
+        while let Some(child) = self.citations_box.first_child() {
+            self.citations_box.remove(&child);
+        }
+        let key = self.key();
+        let cited : Vec<_> = citations.iter().filter(|c| c.key == key ).collect();
+        if cited.is_empty() {
+            let lbl = Label::new(Some("Not cited"));
+            lbl.add_css_class("dim-label");
+            lbl.add_css_class("caption");
+            self.citations_box.insert(&lbl, -1);
+            return;
+        }
+        for citation in cited {
+            let btn = Button::with_label(&citation.place());
+            btn.add_css_class("flat");
+            btn.add_css_class("caption");
+            if let Some(path) = &citation.path {
+                btn.set_tooltip_text(Some(&format!("{}:{}", path.display(), citation.line + 1)));
+            }
+            btn.connect_clicked({
+                let citation = citation.clone();
+                let on_selected = on_selected.clone();
+                move |_| {
+                    on_selected.call(citation.clone());
+                }
+            });
+            self.citations_box.insert(&btn, -1);
+        }
     }
 
     pub fn update(&self, entry : &BibEntry) {
@@ -1384,8 +1645,15 @@ impl ReferenceRow {
         // key_label.set_margin_bottom(6);
         bx_header.append(&authors_label);
 
+        let citations_box = FlowBox::new();
+        citations_box.set_selection_mode(SelectionMode::None);
+        citations_box.set_max_children_per_line(6);
+        citations_box.set_margin_start(6);
+        citations_box.set_margin_bottom(6);
+
         bx.append(&bx_header);
         bx.append(&title_label);
+        bx.append(&citations_box);
         title_label.set_margin_bottom(6);
         title_label.set_margin_start(6);
 
@@ -1394,7 +1662,7 @@ impl ReferenceRow {
         row.set_activatable(true);
 
         row.set_child(Some(&bx));
-        let ref_row = Self { row, key_label, authors_label, title_label };
+        let ref_row = Self { row, key_label, authors_label, title_label, citations_box };
         ref_row.update(entry);
         ref_row
     }
@@ -1422,12 +1690,6 @@ impl React<FileManager> for Titlebar {
                 pdf_btn.set_sensitive(true);
             }
         });
-        manager.connect_file_changed({
-            let pdf_btn = self.pdf_btn.clone();
-            move |_| {
-                pdf_btn.set_sensitive(false);
-            }
-        });
     }
 
 }
@@ -1444,12 +1706,15 @@ impl React<Analyzer> for BibPopover {
 
     fn react(&self, analyzer : &Analyzer) {
         let bib_list = self.list.clone();
+        let citations = self.citations.clone();
+        let on_citation_selected = self.on_citation_selected.clone();
         analyzer.connect_reference_changed(move |diff| {
             match diff {
                 Difference::Added(pos, txt) => {
                     match Token::from_str(&txt) {
                         Ok(Token::Reference(bib_entry, _)) => {
                             let row = ReferenceRow::build(&bib_entry);
+                            row.show_citations(&citations.borrow()[..], &on_citation_selected);
                             bib_list.insert(&row.row, pos as i32);
                         },
                         _other => {
@@ -1476,6 +1741,21 @@ impl React<Analyzer> for BibPopover {
                 }
             }
         });
+        analyzer.connect_citations({
+            let list = self.list.clone();
+            let citations = self.citations.clone();
+            let on_citation_selected = self.on_citation_selected.clone();
+            move |new_citations| {
+                *citations.borrow_mut() = new_citations;
+                let mut ix = 0;
+                while let Some(row) = list.row_at_index(ix) {
+                    if let Some(ref_row) = ReferenceRow::recover(&row) {
+                        ref_row.show_citations(&citations.borrow()[..], &on_citation_selected);
+                    }
+                    ix += 1;
+                }
+            }
+        });
         analyzer.connect_references_cleared({
             let list = self.list.clone();
             move |_| {
@@ -1493,9 +1773,11 @@ impl React<Analyzer> for BibPopover {
         analyzer.connect_doc_error({
             let list = self.list.clone();
             let last_is_err = last_is_err.clone();
-            move |err| {
+            move |diagnostics| {
                 clear_list(&list);
-                create_unique_row(&list, &format!("Parsing error: {}", err), "dialog-error-symbolic");
+                let msg = crate::diagnostic::summarize(&diagnostics[..])
+                    .unwrap_or(String::from("Unknown error"));
+                create_unique_row(&list, &format!("Parsing error: {}", msg), "dialog-error-symbolic");
                 last_is_err.store(true, Ordering::Relaxed);
             }
         });
@@ -0,0 +1,662 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the GPL v3.0 License.
+For a copy, see http://www.gnu.org/licenses.*/
+
+/* Typesetting runs in a separate process (the hidden worker subcommand of the drafts binary),
+so that a panic in typst or a document that never finishes compiling cannot take the editor
+down with it. The editor writes requests to the standard input of the worker and reads its
+responses from the standard output, one JSON object per line. */
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{HashSet, VecDeque};
+use std::thread;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use crate::typst_tools::{Fonts, SystemWorld, WorldWatcher};
+use crate::typst_tools::preview::{jump_from_click, locate, page_hash, render_page, ClickTarget, Location, Preview, DEFAULT_PREVIEW_SCALE, MAX_PREVIEW_SCALE};
+use typst::World;
+use crate::typst_tools::export::ExportRequest;
+use crate::typst_tools::outline::Outline;
+use crate::typst_tools::fonts::{font_families, FontFamily};
+use crate::diagnostic::Diagnostic;
+
+/// Name of the subcommand that starts a worker.
+pub const WORKER_CMD : &str = "worker";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypesettingRequest {
+
+    pub content : String,
+
+    // Project root of the file.
+    pub root : Option<PathBuf>,
+
+    pub file :  Option<PathBuf>,
+
+    // Document that includes the file, typeset in its place while the file is edited.
+    #[serde(default)]
+    pub main : Option<PathBuf>
+
+}
+
+/// Requests written by the editor to the worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WorkerRequest {
+
+    Typeset(TypesettingRequest),
+
+    Export(ExportRequest),
+
+    // Whether the document should be typeset again when files it read change.
+    Watch(bool),
+
+    // Font directories configured by the user.
+    FontDirs(Vec<PathBuf>),
+
+    // Rasterizes the given pages of the last document at the given number of pixels per point.
+    // The pages and the scale are also used for the preview of documents typeset later.
+    Render(Vec<usize>, f32),
+
+    // Finds where the text at the given byte offset of the main source was laid out.
+    Locate(usize),
+
+    // Finds what is at the given point of the last document (inverse search).
+    Click(Location)
+
+}
+
+/// Responses written by the worker to the editor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WorkerResponse {
+
+    // The font families available to the current document.
+    FontsChanged(Vec<FontFamily>),
+
+    // A compilation started (either requested by the editor or due to a changed dependency).
+    Started,
+
+    // Carries the pages of the document just typeset.
+    Done(Preview),
+
+    // Carries pages rasterized after a render request.
+    Rendered(Preview),
+
+    // Carries where the text at the offset of a locate request was laid out.
+    Located(Location),
+
+    // Carries what is at the point of a click request.
+    Clicked(ClickTarget),
+
+    // Carries where the headings and other content of the document just typeset were laid out.
+    Outline(Outline),
+
+    Error(Vec<Diagnostic>),
+
+    Exported(Vec<PathBuf>),
+
+    ExportError(String)
+
+}
+
+// Writes a response to the editor. A worker that cannot reach the editor has no purpose, so it exits.
+fn respond(resp : &WorkerResponse) {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let res = serde_json::to_writer(&mut out, resp)
+        .map_err(|e| e.to_string() )
+        .and_then(|_| writeln!(out).and_then(|_| out.flush() ).map_err(|e| e.to_string() ) );
+    if let Err(e) = res {
+        eprintln!("Unable to write response: {}", e);
+        std::process::exit(1);
+    }
+}
+
+pub struct Workspace {
+
+    // World of the document currently being edited (without a path if it was not
+    // saved yet). It lives across typesetting requests, so that typst can re-use the
+    // sources it parsed and the layouts it memoized in previous compilations.
+    world : Option<(Option<PathBuf>, SystemWorld)>,
+
+    // Project root of the world above.
+    root : Option<PathBuf>,
+
+    // Font directories configured by the user.
+    font_dirs : Vec<PathBuf>,
+
+    // Fonts of the world above, with the font directories they were indexed from.
+    fonts : Option<(Vec<PathBuf>, Fonts)>,
+
+    // Last document typeset successfully, from which pages are exported and rasterized.
+    doc : Option<typst::doc::Document>,
+
+    // Whether the latest typesetting failed, so that the world holds text the document
+    // above was not typeset from.
+    failed : bool,
+
+    // Number of documents typeset successfully.
+    revision : u64,
+
+    // Hashes of the pages of the document above.
+    hashes : Vec<u64>,
+
+    // Pages (by hash) already rasterized and sent to the editor with the current scale.
+    // The editor keeps their images, so they are not rasterized again when they are
+    // shown after another compilation (unless the editor asks for them).
+    sent : HashSet<u64>,
+
+    // Pages shown by the editor, and the number of pixels per point they are shown with.
+    visible : Vec<usize>,
+
+    scale : f32
+
+}
+
+impl Workspace {
+
+    pub fn new() -> Self {
+        Self {
+            world : None,
+            root : None,
+            font_dirs : Vec::new(),
+            fonts : None,
+            doc : None,
+            failed : false,
+            revision : 0,
+            hashes : Vec::new(),
+            sent : HashSet::new(),
+            visible : vec![0],
+            scale : DEFAULT_PREVIEW_SCALE
+        }
+    }
+
+    // Rasterizes the given pages of the last document (ignoring pages it does not have). Unless
+    // forced, pages the editor already has an image of are skipped.
+    fn preview(&mut self, pages : &[usize], force : bool) -> Option<Preview> {
+        let doc = self.doc.as_ref()?;
+        let sizes = doc.pages.iter().map(|p| (p.width().to_pt(), p.height().to_pt()) ).collect();
+        let mut images = Vec::new();
+        for ix in pages {
+            if let (Some(frame), Some(hash)) = (doc.pages.get(*ix), self.hashes.get(*ix)) {
+                // Identical pages are rasterized once, and the editor sets the image to all of them.
+                if force || !self.sent.contains(hash) {
+                    images.push((*ix, render_page(frame, self.scale)));
+                    self.sent.insert(*hash);
+                }
+            }
+        }
+        Some(Preview { revision : self.revision, sizes, hashes : self.hashes.clone(), images })
+    }
+
+    fn set_doc(&mut self, doc : typst::doc::Document) {
+        self.hashes = doc.pages.iter().map(page_hash).collect();
+
+        // The editor only keeps images of pages of the last document.
+        let hashes = &self.hashes;
+        self.sent.retain(|h| hashes.contains(h) );
+        self.doc = Some(doc);
+        self.failed = false;
+        self.revision += 1;
+    }
+
+    /// Returns the world for the given document, creating a new one only when the
+    /// document, its project root or its font directories changed since the last request.
+    pub fn world(&mut self, file : Option<&Path>, root : Option<&Path>, fonts : &Fonts) -> Result<&mut SystemWorld, String> {
+        let file_root = match file {
+            Some(file) => Some(
+                root.map(|r| r.to_owned() )
+                    .or_else(|| crate::typst_tools::project_root(file, None) )
+                    .ok_or_else(|| format!("File {} has no parent directory", file.display()) )?
+            ),
+            None => None
+        };
+
+        // Fonts are only indexed again when the set of font directories changes.
+        let dirs = crate::typst_tools::font_dirs(&self.font_dirs, file_root.as_deref());
+        let fonts_changed = self.fonts.as_ref().map(|(curr, _)| curr != &dirs ).unwrap_or(true);
+        if fonts_changed {
+            let fonts = if dirs.is_empty() { fonts.clone() } else { fonts.with_dirs(&dirs) };
+            self.fonts = Some((dirs, fonts));
+        }
+        let fonts = &self.fonts.as_ref().unwrap().1;
+
+        let is_current = self.world.as_ref().map(|(path, _)| path.as_deref() == file ).unwrap_or(false) &&
+            self.root.as_deref() == root && !fonts_changed;
+        if !is_current {
+            let world = match (file, file_root) {
+                (Some(file), Some(file_root)) => {
+                    let mut world = SystemWorld::new(file_root, fonts.clone());
+                    world.set_main(file).map_err(|e| e.to_string() )?;
+                    world
+                },
+                _ => {
+                    // Paths in untitled documents are resolved relative to the home directory.
+                    let root = dirs::home_dir().unwrap_or_else(std::env::temp_dir);
+                    let mut world = SystemWorld::new(root, fonts.clone());
+                    world.set_untitled_main();
+                    world
+                }
+            };
+            self.world = Some((file.map(|f| f.to_owned() ), world));
+            self.root = root.map(|r| r.to_owned() );
+            self.doc = None;
+        }
+        Ok(&mut self.world.as_mut().unwrap().1)
+    }
+
+}
+
+fn typeset_document_with_typst(
+    ws : &mut Workspace,
+    file : Option<&Path>,
+    main : Option<&Path>,
+    root : Option<&Path>,
+    content : Option<String>,
+    fonts : &Fonts
+) {
+    respond(&WorkerResponse::Started);
+
+    // Cleared once the new document is set.
+    ws.failed = true;
+
+    let world = match ws.world(main.or(file), root, fonts) {
+        Ok(world) => world,
+        Err(e) => {
+            respond(&WorkerResponse::Error(vec![Diagnostic::error(e)]));
+            return;
+        }
+    };
+
+    // The buffer might have unsaved changes, so it takes precedence over the file content. Without
+    // content, the document is typeset again with the last buffer (e.g. after a dependency changed).
+    // An included file being edited is overlaid instead, and typeset through the document including it.
+    if let Some(content) = content {
+        match (file, main) {
+            (Some(file), Some(main)) if file != main => {
+                if let Err(e) = world.overlay_file(file, content) {
+                    respond(&WorkerResponse::Error(vec![Diagnostic::error(e.to_string())]));
+                    return;
+                }
+            },
+            _ => world.overlay_main(content)
+        }
+    }
+
+    match crate::typst_tools::compile(world) {
+        Ok(doc) => {
+            let outline = crate::typst_tools::outline::outline(&doc, world.main());
+            ws.set_doc(doc);
+            let visible = ws.visible.clone();
+            if let Some(preview) = ws.preview(&visible, false) {
+                respond(&WorkerResponse::Done(preview));
+            }
+            respond(&WorkerResponse::Outline(outline));
+        },
+        Err(diagnostics) => {
+            respond(&WorkerResponse::Error(diagnostics));
+        }
+    }
+}
+
+// Pages are exported from the last document typeset successfully (and HTML from the
+// sources of the world, which only match it while the latest typesetting succeeded),
+// so nothing is exported while the latest changes have errors, or it would be outdated.
+fn export_document(ws : &Workspace, req : &ExportRequest) {
+    if ws.failed {
+        respond(&WorkerResponse::ExportError(String::from("The document has errors, so it can not be exported until they are fixed")));
+        return;
+    }
+    let res = match (&ws.doc, &ws.world, &ws.fonts) {
+        (Some(doc), Some((_, world)), Some((_, fonts))) => crate::typst_tools::export::export(doc, world, fonts, req),
+        _ => Err(String::from("The document must be typeset before it is exported"))
+    };
+    match res {
+        Ok(paths) => respond(&WorkerResponse::Exported(paths)),
+        Err(e) => respond(&WorkerResponse::ExportError(e))
+    }
+}
+
+// Requests handled by the worker loop.
+enum WorkspaceRequest {
+    Editor(WorkerRequest),
+    FileEvent(notify::Event)
+}
+
+// Time to wait after a relevant file change before typesetting, since editors
+// often write files in many steps (and users often save many files at once).
+const WATCH_DELAY : u64 = 100;
+
+// Requests accumulated since the last typesetting.
+#[derive(Default)]
+struct PendingRequests {
+    typeset : Option<TypesettingRequest>,
+    exports : Vec<ExportRequest>,
+    deps_changed : bool,
+    font_dirs : Option<Vec<PathBuf>>,
+    render : Option<(Vec<usize>, f32)>,
+    locate : Option<usize>,
+    click : Option<Location>
+}
+
+impl PendingRequests {
+
+    fn push(&mut self, req : WorkspaceRequest, ws : &Workspace, watching : &mut bool) {
+        match req {
+            WorkspaceRequest::Editor(WorkerRequest::Typeset(req)) => self.typeset = Some(req),
+            WorkspaceRequest::Editor(WorkerRequest::Export(req)) => self.exports.push(req),
+            WorkspaceRequest::Editor(WorkerRequest::Watch(watch)) => *watching = watch,
+            WorkspaceRequest::Editor(WorkerRequest::FontDirs(dirs)) => self.font_dirs = Some(dirs),
+            WorkspaceRequest::Editor(WorkerRequest::Render(pages, scale)) => self.render = Some((pages, scale)),
+            WorkspaceRequest::Editor(WorkerRequest::Locate(offset)) => self.locate = Some(offset),
+            WorkspaceRequest::Editor(WorkerRequest::Click(loc)) => self.click = Some(loc),
+            WorkspaceRequest::FileEvent(event) => {
+                if *watching && ws.world.as_ref().map(|(_, world)| world.relevant(&event) ).unwrap_or(false) {
+                    self.deps_changed = true;
+                }
+            }
+        }
+    }
+
+}
+
+// Keeps watching the project root of the current document while watching is enabled.
+// Untitled documents are not watched, since their root is the home directory.
+fn update_watcher(
+    watcher : &mut Option<WorldWatcher>,
+    watching : bool,
+    ws : &Workspace,
+    send : &mpsc::Sender<WorkspaceRequest>
+) {
+    let root = match &ws.world {
+        Some((Some(_), world)) if watching => world.root().to_owned(),
+        _ => {
+            *watcher = None;
+            return;
+        }
+    };
+    if watcher.as_ref().map(|w| w.root() != root.as_path() ).unwrap_or(true) {
+        let send = send.clone();
+        match WorldWatcher::new(&root, move |event| { send.send(WorkspaceRequest::FileEvent(event)); }) {
+            Ok(w) => *watcher = Some(w),
+            Err(e) => {
+                log::warn!("{}", e);
+                *watcher = None;
+            }
+        }
+    }
+}
+
+/// Serves the requests of the editor until it closes the standard input of the
+/// worker. Returns the exit code of the worker.
+pub fn run(fonts : &Fonts) -> i32 {
+    let (send, recv) = mpsc::channel::<WorkspaceRequest>();
+    thread::spawn({
+        let send = send.clone();
+        move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let Ok(line) = line else { break };
+                match serde_json::from_str::<WorkerRequest>(&line) {
+                    Ok(req) => {
+                        if send.send(WorkspaceRequest::Editor(req)).is_err() {
+                            break;
+                        }
+                    },
+                    Err(e) => eprintln!("Invalid request: {}", e)
+                }
+            }
+
+            // The editor is gone (or it wants this worker to stop).
+            std::process::exit(0);
+        }
+    });
+
+    // Indexing system fonts might take a while without a cache, so it is done here
+    // instead of at startup. Typesetting requests sent meanwhile wait in the channel.
+    let fonts = fonts.discover(crate::typst_tools::font_cache_path().as_deref());
+    respond(&WorkerResponse::FontsChanged(font_families(&fonts.book)));
+    let mut last_book = fonts.book.clone();
+    let mut ws = Workspace::new();
+    let mut watching = false;
+    let mut watcher : Option<WorldWatcher> = None;
+    loop {
+        let mut pending = PendingRequests::default();
+        match recv.recv() {
+            Ok(req) => pending.push(req, &ws, &mut watching),
+            Err(_) => break
+        }
+
+        // Typesetting requests queued while the previous one was being typeset are
+        // outdated by the most recent one, so only the last is typeset. Exports are
+        // served after it, so they reflect the latest content.
+        while let Ok(newer) = recv.try_recv() {
+            pending.push(newer, &ws, &mut watching);
+        }
+        if pending.deps_changed && pending.typeset.is_none() {
+            thread::sleep(Duration::from_millis(WATCH_DELAY));
+            while let Ok(newer) = recv.try_recv() {
+                pending.push(newer, &ws, &mut watching);
+            }
+        }
+        if let Some(dirs) = pending.font_dirs.take() {
+            ws.font_dirs = dirs;
+        }
+        let render = pending.render.take().map(|(pages, scale)| {
+            let scale = scale.min(MAX_PREVIEW_SCALE);
+            if scale != ws.scale {
+                ws.sent.clear();
+                ws.scale = scale;
+            }
+            ws.visible = pages.clone();
+            pages
+        });
+        let revision = ws.revision;
+        if let Some(TypesettingRequest { content, root, file, main }) = pending.typeset {
+            typeset_document_with_typst(&mut ws, file.as_deref(), main.as_deref(), root.as_deref(), Some(content), &fonts);
+        } else if pending.deps_changed && watching {
+            let file = ws.world.as_ref().and_then(|(file, _)| file.clone() );
+            let root = ws.root.clone();
+            typeset_document_with_typst(&mut ws, file.as_deref(), None, root.as_deref(), None, &fonts);
+        }
+
+        // Pages of a document typeset just now were already rasterized with the requested scale.
+        if let Some(pages) = render.filter(|_| ws.revision == revision ) {
+            if let Some(preview) = ws.preview(&pages, true) {
+                respond(&WorkerResponse::Rendered(preview));
+            }
+        }
+
+        // Positions are searched at the last document, so a pending typesetting is served first.
+        if let (Some(offset), Some(doc), Some((_, world))) = (pending.locate, &ws.doc, &ws.world) {
+            if let Some(loc) = locate(doc, world.edited(), offset) {
+                respond(&WorkerResponse::Located(loc));
+            }
+        }
+        if let (Some(loc), Some(doc), Some((_, world))) = (pending.click, &ws.doc, &ws.world) {
+            if let Some(target) = jump_from_click(world, doc, &loc) {
+                respond(&WorkerResponse::Clicked(target));
+            }
+        }
+
+        // Font folders of the project or the user might have changed the available fonts.
+        if let Some((_, curr)) = &ws.fonts {
+            if !Arc::ptr_eq(&curr.book, &last_book) {
+                last_book = curr.book.clone();
+                respond(&WorkerResponse::FontsChanged(font_families(&last_book)));
+            }
+        }
+        for req in pending.exports.iter() {
+            export_document(&ws, req);
+        }
+        update_watcher(&mut watcher, watching, &ws, &send);
+    }
+    0
+}
+
+// Number of lines written by the worker to its standard error that are kept, to
+// explain why it stopped (e.g. the message of a panic).
+const STDERR_TAIL : usize = 12;
+
+/// Events received from a worker process, tagged with the generation of the worker that
+/// sent them, so that events of workers already replaced can be told apart.
+pub enum WorkerEvent {
+    Response(u64, WorkerResponse),
+
+    // The worker closed its standard output (usually because it exited).
+    Exited(u64)
+}
+
+/// A worker process, seen from the editor.
+pub struct WorkerProcess {
+
+    pub generation : u64,
+
+    child : Child,
+
+    stdin : ChildStdin,
+
+    // Last lines written by the worker to its standard error.
+    stderr : Arc<Mutex<VecDeque<String>>>
+
+}
+
+impl WorkerProcess {
+
+    /// Starts a worker from the current executable, calling on_event with each of its responses.
+    pub fn spawn<F>(generation : u64, on_event : F) -> Result<Self, String>
+    where
+        F : Fn(WorkerEvent) + Send + 'static
+    {
+        let exe = std::env::current_exe().map_err(|e| format!("Unable to find the drafts executable: {}", e) )?;
+        let mut child = Command::new(exe)
+            .arg(WORKER_CMD)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Unable to start the typesetter: {}", e) )?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr_pipe = child.stderr.take().unwrap();
+        let stderr : Arc<Mutex<VecDeque<String>>> = Default::default();
+        thread::spawn({
+            let stderr = stderr.clone();
+            move || {
+                for line in BufReader::new(stderr_pipe).lines() {
+                    let Ok(line) = line else { break };
+                    let mut tail = stderr.lock().unwrap();
+                    if tail.len() == STDERR_TAIL {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }
+        });
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                match serde_json::from_str::<WorkerResponse>(&line) {
+                    Ok(resp) => on_event(WorkerEvent::Response(generation, resp)),
+                    Err(e) => log::warn!("Invalid response from the typesetter: {}", e)
+                }
+            }
+            on_event(WorkerEvent::Exited(generation));
+        });
+        Ok(Self { generation, child, stdin, stderr })
+    }
+
+    pub fn send(&mut self, req : &WorkerRequest) -> Result<(), String> {
+        let mut line = serde_json::to_string(req).map_err(|e| e.to_string() )?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes())
+            .and_then(|_| self.stdin.flush() )
+            .map_err(|e| format!("Unable to reach the typesetter: {}", e) )
+    }
+
+    /// Stops the worker immediately, even in the middle of a compilation.
+    pub fn kill(&mut self) {
+        if let Err(e) = self.child.kill() {
+            log::warn!("Unable to stop the typesetter: {}", e);
+        }
+        let _ = self.child.wait();
+    }
+
+    /// Waits for the worker to exit, returning a description of how it stopped
+    /// followed by the last lines it wrote to its standard error.
+    pub fn wait(&mut self) -> Vec<String> {
+        let status = match self.child.wait() {
+            Ok(status) => format!("The typesetter stopped unexpectedly ({})", status),
+            Err(e) => format!("The typesetter stopped unexpectedly ({})", e)
+        };
+
+        // The standard error is read by another thread, which might lag a bit behind the exit.
+        thread::sleep(Duration::from_millis(50));
+        let mut lines = vec![status];
+        lines.extend(self.stderr.lock().unwrap().iter().cloned());
+        lines
+    }
+
+}
+
+impl Drop for WorkerProcess {
+
+    fn drop(&mut self) {
+        // Workers exit by themselves once their standard input is closed, but
+        // one might be in the middle of a long compilation.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+}
+
+#[test]
+fn messages_round_trip_as_json_lines() {
+    use crate::typst_tools::preview::PageImage;
+
+    let req = WorkerRequest::Typeset(TypesettingRequest {
+        content : String::from("= Intro\n\nText"),
+        root : Some(PathBuf::from("/thesis")),
+        file : Some(PathBuf::from("/thesis/main.typ")),
+        main : None
+    });
+    let line = serde_json::to_string(&req).unwrap();
+    assert!(!line.contains('\n'));
+    match serde_json::from_str::<WorkerRequest>(&line).unwrap() {
+        WorkerRequest::Typeset(back) => {
+            assert_eq!(back.content, "= Intro\n\nText");
+            assert_eq!(back.file, Some(PathBuf::from("/thesis/main.typ")));
+        },
+        other => panic!("Unexpected request {:?}", other)
+    }
+    let click = WorkerRequest::Click(Location { page : 1, x : 10.5, y : 20.0 });
+    match serde_json::from_str::<WorkerRequest>(&serde_json::to_string(&click).unwrap()).unwrap() {
+        WorkerRequest::Click(loc) => assert_eq!(loc, Location { page : 1, x : 10.5, y : 20.0 }),
+        other => panic!("Unexpected request {:?}", other)
+    }
+
+    let img = PageImage { width : 1.0, height : 1.0, scale : 1.0, pixel_width : 1, pixel_height : 1, data : vec![0, 1, 2, 255] };
+    let resp = WorkerResponse::Done(Preview {
+        revision : 3,
+        sizes : vec![(595.0, 842.0), (595.0, 842.0)],
+        hashes : vec![7, 8],
+        images : vec![(1, img)]
+    });
+    let line = serde_json::to_string(&resp).unwrap();
+
+    // Pixels travel as base64 text rather than as an array of numbers.
+    assert!(line.contains("\"AAEC/w==\""));
+    match serde_json::from_str::<WorkerResponse>(&line).unwrap() {
+        WorkerResponse::Done(preview) => {
+            assert_eq!(preview.revision, 3);
+            assert_eq!(preview.hashes, vec![7, 8]);
+            assert_eq!(preview.images.len(), 1);
+            assert_eq!(preview.images[0].0, 1);
+            assert_eq!(preview.images[0].1.data, vec![0, 1, 2, 255]);
+        },
+        other => panic!("Unexpected response {:?}", other)
+    }
+}